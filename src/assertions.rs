@@ -0,0 +1,103 @@
+use regex::Regex;
+
+// Expected values for recieve-mode fields. An expectation is written in the
+// field's Expect box as one of
+//   5            exact value (integers compare numerically)
+//   1..10        inclusive integer range, either end can be left out
+//   /^ok\d+$/    regex matched against the decoded value
+
+pub enum Expectation{
+    Value(String),
+    Range(Option<i128>, Option<i128>),
+    Regex(Regex)
+}
+
+/// Integers may be entered in decimal or 0x hex
+pub fn parse_int(s : &str) -> Option<i128>{
+    let s = s.trim();
+    let (neg, s) = match s.strip_prefix('-'){
+        Some(x) => (true, x),
+        None => (false, s)
+    };
+    let val = match s.strip_prefix("0x").or(s.strip_prefix("0X")){
+        Some(hex) => i128::from_str_radix(hex, 16).ok()?,
+        None => s.parse::<i128>().ok()?
+    };
+    Some(if neg { -val } else { val })
+}
+
+impl Expectation{
+    pub fn parse(s : &str) -> Result<Self, String>{
+        if let Some(re) = s.strip_prefix('/').and_then(|x| x.strip_suffix('/')){
+            return Regex::new(re).map(Expectation::Regex).map_err(|e| e.to_string());
+        }
+        if let Some((lo, hi)) = s.split_once(".."){
+            let hi = hi.strip_prefix('=').unwrap_or(hi);
+            let bound = |x : &str| if x.trim().is_empty() { Ok(None) } else { parse_int(x).map(Some).ok_or(format!("{x} is not an integer")) };
+            return Ok(Expectation::Range(bound(lo)?, bound(hi)?));
+        }
+        Ok(Expectation::Value(s.to_string()))
+    }
+
+    pub fn matches(&self, actual : &str) -> bool{
+        match self{
+            Expectation::Value(x) => match (parse_int(x), parse_int(actual)){
+                (Some(a), Some(b)) => a == b,
+                _ => x == actual
+            },
+            Expectation::Range(lo, hi) => parse_int(actual).is_some_and(|v| lo.is_none_or(|lo| v >= lo) && hi.is_none_or(|hi| v <= hi)),
+            Expectation::Regex(re) => re.is_match(actual),
+        }
+    }
+}
+
+/// One row of the expected vs actual diff shown under a recieved packet
+#[derive(Debug, Clone)]
+pub struct FieldCheck{
+    pub field : usize,
+    pub expected : String,
+    pub actual : String,
+    pub passed : bool,
+    pub note : Option<String>
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn matches(exp : &str, actual : &str) -> bool{
+        Expectation::parse(exp).unwrap().matches(actual)
+    }
+
+    #[test]
+    fn integers(){
+        assert_eq!(parse_int(" 0x1F "), Some(31));
+        assert_eq!(parse_int("-0x10"), Some(-16));
+        assert_eq!(parse_int("12a"), None);
+        assert!(matches("0x10", "16"));
+        assert!(matches("-1", " -1"));
+        assert!(!matches("5", "6"));
+        // anything that isnt a number compares as text
+        assert!(matches("ready", "ready"));
+        assert!(!matches("ready", "Ready"));
+    }
+
+    #[test]
+    fn ranges(){
+        assert!(matches("1..10", "1") && matches("1..10", "10"));
+        assert!(!matches("1..10", "11") && !matches("1..10", "0"));
+        assert!(matches("..=0x10", "-500") && !matches("..=0x10", "17"));
+        assert!(matches("-5..", "1000000"));
+        assert!(!matches("0..", "abc"));
+        assert!(Expectation::parse("x..5").is_err());
+    }
+
+    #[test]
+    fn regexes(){
+        assert!(matches("/^ok\\d+$/", "ok42"));
+        assert!(!matches("/^ok\\d+$/", "ok"));
+        // the slashes have to enclose the whole expectation
+        assert!(matches("/a", "/a"));
+        assert!(Expectation::parse("/(/").is_err());
+    }
+}
@@ -0,0 +1,456 @@
+use std::collections::HashMap;
+
+use crate::packet::{PacketDataType, PacketField, PacketView, SizingMethod};
+
+// Imports packet layouts from C headers. Only what firmware message headers
+// actually use is understood: fixed width integers, char arrays, nested structs
+// declared earlier in the file, #define'd array sizes and packing via
+// __attribute__((packed)) or #pragma pack(N). Offsets follow the usual C rules,
+// the gaps they leave become Padding fields. Byte arrays import as fixed size
+// Bytes fields that send zeros until a file is picked for them.
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token{
+    Ident(String),
+    Number(usize),
+    Punct(char),
+    Pack(Option<usize>), // #pragma pack alignment limit in effect from here on
+}
+
+#[derive(Clone)]
+enum Kind{
+    Scalar(PacketDataType),
+    Struct(Layout),
+}
+
+#[derive(Clone)]
+struct Member{
+    name : String,
+    kind : Kind,
+    count : usize,
+}
+
+#[derive(Clone)]
+enum Leaf{
+    Int(PacketDataType),
+    Bytes(usize),
+}
+
+/// A struct laid out in memory, leaves are sorted by offset
+#[derive(Clone)]
+struct Layout{
+    leaves : Vec<(usize, String, Leaf)>,
+    size : usize,
+    align : usize,
+}
+
+impl Layout{
+    fn new(members : &[Member], pack : Option<usize>) -> Self{
+        let mut leaves = Vec::new();
+        let mut offset = 0usize;
+        let mut align = 1;
+        for m in members{
+            let (size, natural) = match &m.kind{
+                Kind::Scalar(d) => (d.data_size(), d.data_size()),
+                Kind::Struct(l) => (l.size, l.align),
+            };
+            let a = pack.map_or(natural, |p| natural.min(p));
+            offset = offset.next_multiple_of(a);
+            align = align.max(a);
+            match &m.kind{
+                Kind::Scalar(_) if m.count > 1 && size == 1 => leaves.push((offset, m.name.clone(), Leaf::Bytes(m.count))),
+                Kind::Scalar(d) => for i in 0..m.count{
+                    let name = if m.count > 1 { format!("{}[{i}]", m.name) } else { m.name.clone() };
+                    leaves.push((offset + i * size, name, Leaf::Int(*d)));
+                },
+                Kind::Struct(l) => for i in 0..m.count{
+                    let prefix = if m.count > 1 { format!("{}[{i}]", m.name) } else { m.name.clone() };
+                    leaves.extend(l.leaves.iter().map(|(o, n, x)| (offset + i * size + o, format!("{prefix}.{n}"), x.clone())));
+                },
+            }
+            offset += size * m.count;
+        }
+        Self { leaves, size: offset.next_multiple_of(align), align }
+    }
+
+    fn fields(&self) -> Vec<PacketField>{
+        let mut fields = Vec::new();
+        let mut at = 0;
+        let pad = |fields : &mut Vec<PacketField>, name : String, n : usize|{
+            let mut f = PacketField::typed(fields.len(), name, PacketDataType::Padding, Some(SizingMethod::FixedSize(n)));
+            f.data_string = "0".to_string();
+            fields.push(f);
+        };
+        for (offset, name, leaf) in &self.leaves{
+            if *offset > at{
+                pad(&mut fields, Default::default(), offset - at);
+            }
+            at = *offset;
+            match leaf{
+                Leaf::Int(d) => {
+                    let mut f = PacketField::typed(fields.len(), name.clone(), *d, None);
+                    f.data_string = "0".to_string();
+                    fields.push(f);
+                    at += d.data_size();
+                },
+                Leaf::Bytes(n) => {
+                    let meth = SizingMethod::FixedSize(*n);
+                    fields.push(PacketField::typed(fields.len(), name.clone(), PacketDataType::Bytes(meth), Some(meth)));
+                    at += n;
+                }
+            }
+        }
+        if self.size > at{
+            pad(&mut fields, Default::default(), self.size - at);
+        }
+        fields
+    }
+}
+
+struct Parser{
+    tokens : Vec<Token>,
+    pos : usize,
+    defines : HashMap<String, usize>,
+    structs : HashMap<String, Layout>,
+}
+
+pub fn parse_header(src : &str, first_index : usize) -> Result<Vec<PacketView>, String>{
+    let mut defines = HashMap::new();
+    let mut pack_stack : Vec<Option<usize>> = Vec::new();
+    let mut pack = None;
+    let mut tokens = Vec::new();
+    for line in strip_comments(src).lines(){
+        let line = line.trim();
+        if let Some(directive) = line.strip_prefix('#'){
+            let words : Vec<&str> = directive.split_whitespace().collect();
+            match words.first(){
+                Some(&"define") if words.len() >= 3 => {
+                    if let Some(n) = parse_number(words[2]){
+                        defines.insert(words[1].to_string(), n);
+                    }
+                },
+                Some(&"pragma") => {
+                    let args : String = words[1..].concat();
+                    if let Some(args) = args.strip_prefix("pack(").and_then(|x| x.strip_suffix(')')){
+                        let args : Vec<&str> = args.split(',').collect();
+                        match args.as_slice(){
+                            [""] => pack = None,
+                            ["pop", ..] => pack = pack_stack.pop().flatten(),
+                            ["push", rest @ ..] => {
+                                pack_stack.push(pack);
+                                if let Some(n) = rest.first(){
+                                    pack = Some(parse_number(n).filter(|n| n.is_power_of_two()).ok_or(format!("Bad #pragma pack value {n}"))?);
+                                }
+                            },
+                            [n] => pack = Some(parse_number(n).filter(|n| n.is_power_of_two()).ok_or(format!("Bad #pragma pack value {n}"))?),
+                            _ => return Err(format!("Unsupported #pragma pack({})", args.join(",")))
+                        }
+                    }
+                },
+                _ => ()
+            }
+            continue;
+        }
+        tokens.push(Token::Pack(pack));
+        tokenize(line, &mut tokens)?;
+    }
+    let mut parser = Parser { tokens, pos: 0, defines, structs: HashMap::new() };
+    parser.parse(first_index)
+}
+
+fn strip_comments(src : &str) -> String{
+    let mut ret = String::with_capacity(src.len());
+    let mut rest = src;
+    while !rest.is_empty(){
+        if let Some(r) = rest.strip_prefix("//"){
+            rest = r.find('\n').map(|i| &r[i..]).unwrap_or_default();
+        }
+        else if let Some(r) = rest.strip_prefix("/*"){
+            let end = r.find("*/").map(|i| i + 2).unwrap_or(r.len());
+            // keep line structure intact for the preprocessor pass
+            ret.extend(r[..end].chars().filter(|c| *c == '\n'));
+            rest = &r[end..];
+        }
+        else{
+            let c = rest.chars().next().unwrap();
+            ret.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    ret
+}
+
+fn parse_number(s : &str) -> Option<usize>{
+    let s = s.trim_start_matches('(').trim_end_matches(')').trim_end_matches(['u', 'U', 'l', 'L']);
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")){
+        usize::from_str_radix(hex, 16).ok()
+    }
+    else{
+        s.parse().ok()
+    }
+}
+
+fn tokenize(line : &str, tokens : &mut Vec<Token>) -> Result<(), String>{
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek(){
+        if c.is_whitespace(){
+            chars.next();
+        }
+        else if c.is_alphabetic() || c == '_'{
+            let mut ident = String::new();
+            while let Some(&c) = chars.peek().filter(|c| c.is_alphanumeric() || **c == '_'){
+                ident.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Ident(ident));
+        }
+        else if c.is_ascii_digit(){
+            let mut num = String::new();
+            while let Some(&c) = chars.peek().filter(|c| c.is_ascii_alphanumeric()){
+                num.push(c);
+                chars.next();
+            }
+            tokens.push(Token::Number(parse_number(&num).ok_or(format!("Bad number {num}"))?));
+        }
+        else{
+            tokens.push(Token::Punct(c));
+            chars.next();
+        }
+    }
+    Ok(())
+}
+
+fn builtin_type(words : &[String]) -> Option<PacketDataType>{
+    let words : Vec<&str> = words.iter().map(|x| x.as_str()).filter(|x| !matches!(*x, "const" | "volatile" | "int" | "signed")).collect();
+    let signed = !words.contains(&"unsigned");
+    let words : Vec<&str> = words.into_iter().filter(|x| *x != "unsigned").collect();
+    Some(match words.as_slice(){
+        ["uint8_t"] => PacketDataType::U8,
+        ["uint16_t"] => PacketDataType::U16,
+        ["uint32_t"] => PacketDataType::U32,
+        ["uint64_t"] => PacketDataType::U64,
+        ["int8_t"] => PacketDataType::I8,
+        ["int16_t"] => PacketDataType::I16,
+        ["int32_t"] => PacketDataType::I32,
+        ["int64_t"] => PacketDataType::I64,
+        ["char"] | ["bool"] | ["_Bool"] => if signed { PacketDataType::I8 } else { PacketDataType::U8 },
+        ["short"] => if signed { PacketDataType::I16 } else { PacketDataType::U16 },
+        [] => if signed { PacketDataType::I32 } else { PacketDataType::U32 },
+        ["long", "long"] => if signed { PacketDataType::I64 } else { PacketDataType::U64 },
+        _ => return None
+    })
+}
+
+impl Parser{
+    fn peek(&self) -> Option<&Token>{
+        self.tokens[self.pos..].iter().find(|t| !matches!(t, Token::Pack(_)))
+    }
+    fn next(&mut self) -> Option<Token>{
+        while let Some(t) = self.tokens.get(self.pos).cloned(){
+            self.pos += 1;
+            if !matches!(t, Token::Pack(_)){
+                return Some(t);
+            }
+        }
+        None
+    }
+    fn pragma_pack(&self) -> Option<usize>{
+        self.tokens[..self.pos].iter().rev().find_map(|t| match t{
+            Token::Pack(x) => Some(*x),
+            _ => None
+        }).flatten()
+    }
+    fn expect(&mut self, c : char) -> Result<(), String>{
+        match self.next(){
+            Some(Token::Punct(x)) if x == c => Ok(()),
+            x => Err(format!("Expected '{c}' but found {x:?}"))
+        }
+    }
+    fn ident(&mut self) -> Result<String, String>{
+        match self.next(){
+            Some(Token::Ident(x)) => Ok(x),
+            x => Err(format!("Expected a name but found {x:?}"))
+        }
+    }
+
+    /// Skips `__attribute__((...))` and reports whether it contained `packed`
+    fn attributes(&mut self) -> bool{
+        let mut packed = false;
+        while let Some(Token::Ident(x)) = self.peek().cloned(){
+            if x != "__attribute__" && x != "__packed"{
+                break;
+            }
+            packed |= x == "__packed";
+            self.next();
+            if x == "__packed"{
+                continue;
+            }
+            let mut depth = 0;
+            while let Some(t) = self.next(){
+                match t{
+                    Token::Punct('(') => depth += 1,
+                    Token::Punct(')') => {
+                        depth -= 1;
+                        if depth == 0{
+                            break;
+                        }
+                    },
+                    Token::Ident(x) if x == "packed" || x == "__packed__" => packed = true,
+                    _ => ()
+                }
+            }
+        }
+        packed
+    }
+
+    fn parse(&mut self, first_index : usize) -> Result<Vec<PacketView>, String>{
+        let mut ret = Vec::new();
+        while let Some(t) = self.next(){
+            if t != Token::Ident("struct".into()){
+                continue;
+            }
+            let pack = self.pragma_pack();
+            let mut packed = self.attributes();
+            let tag = if let Some(Token::Ident(_)) = self.peek() { Some(self.ident()?) } else { None };
+            if self.peek() != Some(&Token::Punct('{')){
+                // forward declaration or a struct typed member outside of a definition
+                continue;
+            }
+            self.next();
+            let members = self.members()?;
+            packed |= self.attributes();
+            let alias = if let Some(Token::Ident(_)) = self.peek() { Some(self.ident()?) } else { None };
+            packed |= self.attributes();
+            let name = alias.clone().or(tag.clone()).ok_or("Anonymous struct without a typedef name")?;
+
+            let layout = Layout::new(&members, if packed { Some(1) } else { pack });
+            let fields = layout.fields();
+            for key in [tag, alias].into_iter().flatten(){
+                self.structs.insert(key, layout.clone());
+            }
+            ret.push(PacketView::with_fields(first_index + ret.len(), name, fields));
+        }
+        Ok(ret)
+    }
+
+    fn members(&mut self) -> Result<Vec<Member>, String>{
+        let mut ret = Vec::new();
+        loop{
+            let mut words = Vec::new();
+            loop{
+                match self.next(){
+                    Some(Token::Punct('}')) if words.is_empty() => return Ok(ret),
+                    Some(Token::Ident(x)) => words.push(x),
+                    Some(Token::Punct('*')) => return Err("Pointer members can't be sent over the wire".to_string()),
+                    Some(Token::Punct('[' | ';' | ':')) => {
+                        self.pos -= 1;
+                        break;
+                    },
+                    Some(Token::Punct(',')) => return Err("Declare one member per line".to_string()),
+                    x => return Err(format!("Unexpected {x:?} in struct body"))
+                }
+            }
+            let name = words.pop().ok_or("Member without a name")?;
+            let mut count = 1;
+            while self.peek() == Some(&Token::Punct('[')){
+                self.next();
+                count *= match self.next(){
+                    Some(Token::Number(n)) => n,
+                    Some(Token::Ident(x)) => *self.defines.get(&x).ok_or(format!("Unknown array size {x}"))?,
+                    x => return Err(format!("Bad array size {x:?}"))
+                };
+                self.expect(']')?;
+            }
+            if self.peek() == Some(&Token::Punct(':')){
+                return Err(format!("Bitfield {name} is not supported"));
+            }
+            self.expect(';')?;
+
+            // Nested structs keep their own layout, only their alignment is subject to the outer packing
+            let type_name = if words.first().is_some_and(|x| x == "struct") { words[1..].join(" ") } else { words.join(" ") };
+            let kind = match self.structs.get(&type_name){
+                Some(inner) => Kind::Struct(inner.clone()),
+                None => Kind::Scalar(builtin_type(&words).ok_or(format!("Unsupported type {} for {name}", words.join(" ")))?),
+            };
+            ret.push(Member { name, kind, count });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    /// (name, offset, size) of every named field
+    fn offsets(view : &PacketView) -> Vec<(String, usize, usize)>{
+        let mut at = 0;
+        let mut ret = Vec::new();
+        for f in &view.fields{
+            let n = match (f.datatype.unwrap(), f.sizing_method){
+                (PacketDataType::Padding | PacketDataType::Bytes(_), Some(SizingMethod::FixedSize(n))) => n,
+                (d, _) => d.data_size(),
+            };
+            if !f.name.is_empty(){
+                ret.push((f.name.clone(), at, n));
+            }
+            at += n;
+        }
+        ret
+    }
+
+    fn one(src : &str) -> PacketView{
+        parse_header(src, 0).unwrap().pop().unwrap()
+    }
+
+    #[test]
+    fn natural_alignment_and_tail_padding(){
+        let view = one("struct msg { uint8_t a; uint32_t b; uint16_t c; };");
+        assert_eq!(offsets(&view), [("a".into(), 0, 1), ("b".into(), 4, 4), ("c".into(), 8, 2)]);
+        assert_eq!(view.to_bytes(&Default::default()).unwrap().len(), 12);
+    }
+
+    #[test]
+    fn nested_struct_keeps_its_size_and_alignment(){
+        let view = parse_header("
+            struct inner { uint32_t x; uint8_t y; };
+            struct outer { uint8_t a; struct inner in[2]; uint8_t b; };
+        ", 0).unwrap().pop().unwrap();
+        assert_eq!(offsets(&view), [
+            ("a".into(), 0, 1),
+            ("in[0].x".into(), 4, 4), ("in[0].y".into(), 8, 1),
+            ("in[1].x".into(), 12, 4), ("in[1].y".into(), 16, 1),
+            ("b".into(), 20, 1),
+        ]);
+        assert_eq!(view.to_bytes(&Default::default()).unwrap().len(), 24);
+    }
+
+    #[test]
+    fn pack_caps_alignment(){
+        let src = "
+            #pragma pack(push, 2)
+            typedef struct { uint8_t a; uint32_t b; uint64_t c; } two;
+            #pragma pack(pop)
+            typedef struct __attribute__((packed)) { uint8_t a; uint32_t b; } one;
+            typedef struct { uint8_t a; two t; } natural;
+        ";
+        let views = parse_header(src, 0).unwrap();
+        assert_eq!(offsets(&views[0]), [("a".into(), 0, 1), ("b".into(), 2, 4), ("c".into(), 6, 8)]);
+        assert_eq!(offsets(&views[1]), [("a".into(), 0, 1), ("b".into(), 1, 4)]);
+        // the nested struct only asks for the alignment it was packed to
+        assert_eq!(offsets(&views[2])[1], ("t.a".into(), 2, 1));
+        assert_eq!(views[2].to_bytes(&Default::default()).unwrap().len(), 16);
+    }
+
+    #[test]
+    fn byte_arrays_are_sized_data(){
+        let mut view = one("#define LEN 5\nstruct msg { char name[LEN]; uint16_t id; };");
+        assert_eq!(offsets(&view), [("name".into(), 0, 5), ("id".into(), 6, 2)]);
+        assert!(matches!(view.fields[0].datatype, Some(PacketDataType::Bytes(SizingMethod::FixedSize(5)))));
+        assert_eq!(view.to_bytes(&Default::default()).unwrap(), [0; 8]);
+        // what arrives in the array is kept rather than skipped
+        let dat = [&b"hello\0"[..], &7u16.to_ne_bytes()].concat();
+        let payloads = view.decode(&mut std::io::Cursor::new(&dat), &Default::default()).unwrap();
+        assert_eq!(payloads, [(0, b"hello".to_vec())]);
+        assert_eq!(view.fields[2].data_string, "7");
+    }
+}
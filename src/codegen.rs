@@ -0,0 +1,428 @@
+use std::fmt::Write;
+
+use crate::packet::{align_padding, unescape_bytes, PacketDataType, PacketField, PacketView, SizingMethod};
+
+// Code generation from a packet layout. The generated encoders write integers in
+// native byte order, same as PacketView::to_bytes, so a packet built from the same
+// values comes out byte for byte identical.
+
+const KEYWORDS : &[&str] = &[
+    "as", "break", "const", "continue", "crate", "else", "enum", "extern", "false", "fn", "for", "if", "impl", "in",
+    "let", "loop", "match", "mod", "move", "mut", "pub", "ref", "return", "self", "static", "struct", "super",
+    "trait", "true", "type", "unsafe", "use", "where", "while", "async", "await", "dyn", "char", "int", "short",
+    "long", "float", "double", "signed", "unsigned", "void", "union", "switch", "case", "default", "do", "goto",
+    "register", "sizeof", "typedef", "volatile", "auto",
+];
+
+pub fn ident(name : &str, fallback : String) -> String{
+    let mut ret : String = name.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' }).collect();
+    ret = ret.split('_').filter(|x| !x.is_empty()).collect::<Vec<_>>().join("_");
+    if ret.is_empty(){
+        return fallback;
+    }
+    if ret.starts_with(|c : char| c.is_ascii_digit()) || KEYWORDS.contains(&ret.as_str()){
+        ret.insert(0, '_');
+    }
+    ret
+}
+
+fn field_ident(field : &PacketField) -> String{
+    ident(&field.name, format!("field{}", field.index))
+}
+
+/// Identifiers for every field of the view, indexed like `view.fields`. Fillers are
+/// `pad<index>` and names that sanitize to the same thing get a `_2`, `_3`.. suffix.
+pub fn field_idents(view : &PacketView) -> Vec<String>{
+    let mut ret : Vec<String> = Vec::with_capacity(view.fields.len());
+    for f in &view.fields{
+        let base = if f.datatype.is_some_and(|d| d.is_filler()) { format!("pad{}", f.index) } else { field_ident(f) };
+        let mut id = base.clone();
+        let mut n = 1;
+        while ret.contains(&id){
+            n += 1;
+            id = format!("{base}_{n}");
+        }
+        ret.push(id);
+    }
+    ret
+}
+
+fn type_name(view : &PacketView) -> String{
+    let snake = ident(&view.lable, format!("packet{}", view.index));
+    snake.split('_').filter(|x| !x.is_empty()).map(|x| {
+        let mut c = x.chars();
+        c.next().map(|f| f.to_ascii_uppercase().to_string() + c.as_str()).unwrap_or_default()
+    }).collect()
+}
+
+/// The Bytes field, if any, that takes its length from the field at `index`
+fn header_of(view : &PacketView, index : usize) -> Option<&PacketField>{
+    view.fields.iter().find(|f| matches!(f.datatype, Some(PacketDataType::Bytes(_))) && f.sizing_method.is_some_and(|m| matches!(m, SizingMethod::SizeHeader(x) if x == index)))
+}
+
+fn c_int(dtype : PacketDataType) -> Option<&'static str>{
+    Some(match dtype{
+        PacketDataType::U64 => "uint64_t",
+        PacketDataType::U32 => "uint32_t",
+        PacketDataType::U16 => "uint16_t",
+        PacketDataType::U8  => "uint8_t",
+        PacketDataType::I64 => "int64_t",
+        PacketDataType::I32 => "int32_t",
+        PacketDataType::I16 => "int16_t",
+        PacketDataType::I8  => "int8_t",
+        _ => return None
+    })
+}
+
+fn rust_int(dtype : PacketDataType) -> Option<&'static str>{
+    Some(match dtype{
+        PacketDataType::U64 | PacketDataType::ULeb128 => "u64",
+        PacketDataType::U32 => "u32",
+        PacketDataType::U16 => "u16",
+        PacketDataType::U8  => "u8",
+        PacketDataType::I64 | PacketDataType::SLeb128 | PacketDataType::ZigZag => "i64",
+        PacketDataType::I32 => "i32",
+        PacketDataType::I16 => "i16",
+        PacketDataType::I8  => "i8",
+        _ => return None
+    })
+}
+
+/// Packed C struct for the fixed part of the layout. C can't describe anything after
+/// the first variable length field, it becomes a flexible array member and the rest
+/// is listed in a comment.
+pub fn c_header(view : &PacketView) -> String{
+    let name = ident(&view.lable, format!("packet{}", view.index));
+    let guard = format!("{}_H", name.to_ascii_uppercase());
+    let mut ret = String::new();
+    writeln!(ret, "/* Generated by packetmancer. Integers are in host byte order. */").unwrap();
+    writeln!(ret, "#ifndef {guard}\n#define {guard}\n\n#include <stdint.h>\n").unwrap();
+    writeln!(ret, "struct __attribute__((packed)) {name} {{").unwrap();
+    let ids = field_idents(view);
+    let mut offset = 0;
+    let mut fields = view.fields.iter();
+    for f in fields.by_ref(){
+        let Some(dtype) = f.datatype else { continue };
+        let id = &ids[f.index];
+        match (dtype, f.sizing_method){
+            (PacketDataType::Padding, Some(SizingMethod::FixedSize(n))) => {
+                writeln!(ret, "    uint8_t _pad{}[{n}]; /* fill 0x{:02x} */", f.index, f.fill_byte()).unwrap();
+                offset += n;
+            },
+            (PacketDataType::Align, Some(SizingMethod::FixedSize(n))) => {
+                let n = align_padding(offset, n);
+                if n > 0{
+                    writeln!(ret, "    uint8_t _pad{}[{n}]; /* fill 0x{:02x} */", f.index, f.fill_byte()).unwrap();
+                }
+                offset += n;
+            },
+            (PacketDataType::Padding | PacketDataType::Align, _) => (),
+            (PacketDataType::Bytes(_), Some(SizingMethod::FixedSize(n))) => {
+                writeln!(ret, "    uint8_t {id}[{n}];").unwrap();
+                offset += n;
+            },
+            (PacketDataType::Script, _) => {
+                let n = view.script_len(f);
+                writeln!(ret, "    uint8_t {id}[{n}]; /* computed by a script */").unwrap();
+                offset += n;
+            },
+            _ if c_int(dtype).is_some() => {
+                let comment = header_of(view, f.index).map(|x| format!(" /* length of {} */", ids[x.index])).unwrap_or_default();
+                writeln!(ret, "    {} {id};{comment}", c_int(dtype).unwrap()).unwrap();
+                offset += dtype.data_size();
+            },
+            _ => {
+                let how = match (dtype, f.sizing_method){
+                    (PacketDataType::Bytes(_), Some(SizingMethod::SizeHeader(x))) => format!("length in {}", ids[x]),
+                    (PacketDataType::Bytes(_), Some(SizingMethod::Delimiter)) => format!("terminated by \"{}\"", f.sizing_meth_str),
+                    (PacketDataType::Bytes(_), _) => "runs until the connection closes".to_string(),
+                    (PacketDataType::CStr, _) => "nul terminated string".to_string(),
+                    _ => format!("{dtype} varint"),
+                };
+                writeln!(ret, "    uint8_t {id}[]; /* {how} */").unwrap();
+                break;
+            }
+        }
+    }
+    let rest : Vec<String> = fields.filter(|f| f.datatype.is_some()).map(|f| format!("{} ({})", ids[f.index], f.datatype.unwrap())).collect();
+    if !rest.is_empty(){
+        writeln!(ret, "    /* followed by: {} */", rest.join(", ")).unwrap();
+    }
+    writeln!(ret, "}};\n\n#endif").unwrap();
+    ret
+}
+
+const ULEB_HELPERS : &str = "
+fn encode_uleb128(mut val: u64) -> Vec<u8> {
+    let mut out = Vec::new();
+    loop {
+        let byte = (val & 0x7f) as u8;
+        val >>= 7;
+        if val == 0 {
+            out.push(byte);
+            return out;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn decode_uleb128(buf: &[u8]) -> Option<(u64, usize)> {
+    let mut val = 0u64;
+    for (i, b) in buf.iter().take(10).enumerate() {
+        val |= ((b & 0x7f) as u64) << (7 * i);
+        if b & 0x80 == 0 {
+            return Some((val, i + 1));
+        }
+    }
+    None
+}
+";
+
+const SLEB_HELPERS : &str = "
+fn encode_sleb128(mut val: i64) -> Vec<u8> {
+    let mut out = Vec::new();
+    loop {
+        let byte = (val & 0x7f) as u8;
+        val >>= 7;
+        if (val == 0 && byte & 0x40 == 0) || (val == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return out;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn decode_sleb128(buf: &[u8]) -> Option<(i64, usize)> {
+    let mut val = 0i64;
+    for (i, b) in buf.iter().take(10).enumerate() {
+        val |= ((b & 0x7f) as i64) << (7 * i);
+        if b & 0x80 == 0 {
+            if 7 * (i + 1) < 64 && b & 0x40 != 0 {
+                val |= -1 << (7 * (i + 1));
+            }
+            return Some((val, i + 1));
+        }
+    }
+    None
+}
+";
+
+const ZIGZAG_HELPERS : &str = "
+fn zigzag_encode(val: i64) -> u64 {
+    ((val << 1) ^ (val >> 63)) as u64
+}
+
+fn zigzag_decode(val: u64) -> i64 {
+    ((val >> 1) as i64) ^ -((val & 1) as i64)
+}
+";
+
+/// Makes the generated `encode` refuse a fixed size field given the wrong length
+fn check_len(enc : &mut String, id : &str, n : usize){
+    writeln!(enc, "        if self.{id}.len() != {n} {{\n            return Err(format!(\"{id} is {{}} bytes, expected {n}\", self.{id}.len()));\n        }}").unwrap();
+}
+
+/// Rust module with a struct holding the packet's values plus `encode`/`decode`.
+/// Length headers aren't stored, `encode` derives them from the data they size.
+pub fn rust_module(view : &PacketView) -> Result<String, String>{
+    let name = type_name(view);
+    let ids = field_idents(view);
+    let fields : Vec<&PacketField> = view.fields.iter().filter(|f| f.datatype.is_some()).collect();
+
+    let mut decl = String::new();
+    let mut enc = String::new();
+    let mut dec = String::new();
+    for f in &fields{
+        let dtype = f.datatype.unwrap();
+        let id = &ids[f.index];
+        match (dtype, f.sizing_method){
+            (PacketDataType::Padding, Some(SizingMethod::FixedSize(n))) => {
+                writeln!(enc, "        out.resize(out.len() + {n}, {});", f.fill_byte()).unwrap();
+                writeln!(dec, "        buf.get(pos..pos + {n})?;\n        pos += {n};").unwrap();
+            },
+            (PacketDataType::Align, Some(SizingMethod::FixedSize(n))) if n > 0 => {
+                writeln!(enc, "        out.resize(out.len() + ({n} - out.len() % {n}) % {n}, {});", f.fill_byte()).unwrap();
+                writeln!(dec, "        pos += ({n} - pos % {n}) % {n};\n        buf.get(..pos)?;").unwrap();
+            },
+            (PacketDataType::Padding | PacketDataType::Align, _) => (),
+            (PacketDataType::CStr, _) => {
+                writeln!(decl, "    pub {id}: String,").unwrap();
+                writeln!(enc, "        if self.{id}.contains('\\0') {{\n            return Err(\"{id} can't contain a nul byte\".to_string());\n        }}").unwrap();
+                writeln!(enc, "        out.extend_from_slice(self.{id}.as_bytes());\n        out.push(0);").unwrap();
+                writeln!(dec, "        let end = pos + buf[pos..].iter().position(|b| *b == 0)?;").unwrap();
+                writeln!(dec, "        ret.{id} = String::from_utf8_lossy(&buf[pos..end]).into_owned();\n        pos = end + 1;").unwrap();
+            },
+            (PacketDataType::Script, _) => {
+                // The script itself can't be carried over, its output is sized like it is now
+                let n = view.script_len(f);
+                writeln!(decl, "    /// computed by a script in packetmancer\n    pub {id}: Vec<u8>,").unwrap();
+                check_len(&mut enc, id, n);
+                writeln!(enc, "        out.extend_from_slice(&self.{id});").unwrap();
+                writeln!(dec, "        ret.{id} = buf.get(pos..pos + {n})?.to_vec();\n        pos += {n};").unwrap();
+            },
+            (PacketDataType::Bytes(_), meth) => {
+                writeln!(decl, "    pub {id}: Vec<u8>,").unwrap();
+                if let Some(SizingMethod::FixedSize(n)) = meth{
+                    check_len(&mut enc, id, n);
+                }
+                writeln!(enc, "        out.extend_from_slice(&self.{id});").unwrap();
+                match meth{
+                    Some(SizingMethod::SizeHeader(x)) => {
+                        writeln!(dec, "        let len = usize::try_from({}).ok()?;", ids[x]).unwrap();
+                        writeln!(dec, "        ret.{id} = buf.get(pos..pos + len)?.to_vec();\n        pos += len;").unwrap();
+                    },
+                    Some(SizingMethod::Delimiter) => {
+                        let delim = unescape_bytes(&f.sizing_meth_str).filter(|d| !d.is_empty())
+                            .ok_or(format!("Field {id} needs a delimiter to be exported"))?;
+                        writeln!(enc, "        out.extend_from_slice(&{delim:?});").unwrap();
+                        writeln!(dec, "        let delim: &[u8] = &{delim:?};").unwrap();
+                        writeln!(dec, "        let end = pos + buf[pos..].windows(delim.len()).position(|w| w == delim)?;").unwrap();
+                        writeln!(dec, "        ret.{id} = buf[pos..end].to_vec();\n        pos = end + delim.len();").unwrap();
+                    },
+                    Some(SizingMethod::UntilClose) => {
+                        writeln!(dec, "        ret.{id} = buf[pos..].to_vec();\n        pos = buf.len();").unwrap();
+                    },
+                    Some(SizingMethod::FixedSize(n)) => {
+                        writeln!(dec, "        ret.{id} = buf.get(pos..pos + {n})?.to_vec();\n        pos += {n};").unwrap();
+                    },
+                    None => ()
+                }
+            },
+            _ => {
+                let ty = rust_int(dtype).unwrap();
+                // Headers live in a local so the Bytes field after them can use it
+                let (target, value) = match header_of(view, f.index){
+                    Some(sized) => {
+                        let sized = &ids[sized.index];
+                        writeln!(enc, "        let {id} = {ty}::try_from(self.{sized}.len()).map_err(|_| \"{sized} is too long for {id}\".to_string())?;").unwrap();
+                        (format!("let {id}"), id.clone())
+                    },
+                    None => {
+                        writeln!(decl, "    pub {id}: {ty},").unwrap();
+                        (format!("ret.{id}"), format!("self.{id}"))
+                    }
+                };
+                match dtype{
+                    PacketDataType::ULeb128 => {
+                        writeln!(enc, "        out.extend(encode_uleb128({value}));").unwrap();
+                        writeln!(dec, "        let (val, n) = decode_uleb128(&buf[pos..])?;\n        {target} = val;\n        pos += n;").unwrap();
+                    },
+                    PacketDataType::SLeb128 => {
+                        writeln!(enc, "        out.extend(encode_sleb128({value}));").unwrap();
+                        writeln!(dec, "        let (val, n) = decode_sleb128(&buf[pos..])?;\n        {target} = val;\n        pos += n;").unwrap();
+                    },
+                    PacketDataType::ZigZag => {
+                        writeln!(enc, "        out.extend(encode_uleb128(zigzag_encode({value})));").unwrap();
+                        writeln!(dec, "        let (val, n) = decode_uleb128(&buf[pos..])?;\n        {target} = zigzag_decode(val);\n        pos += n;").unwrap();
+                    },
+                    _ => {
+                        let n = dtype.data_size();
+                        writeln!(enc, "        out.extend_from_slice(&{value}.to_ne_bytes());").unwrap();
+                        writeln!(dec, "        {target} = {ty}::from_ne_bytes(buf.get(pos..pos + {n})?.try_into().ok()?);\n        pos += {n};").unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    let mut ret = String::new();
+    writeln!(ret, "// Generated by packetmancer. Integers are in host byte order.\n").unwrap();
+    writeln!(ret, "#[derive(Debug, Clone, Default, PartialEq)]\npub struct {name} {{\n{decl}}}\n").unwrap();
+    writeln!(ret, "impl {name} {{").unwrap();
+    writeln!(ret, "    /// Fails where `PacketView::to_bytes` would, or where a value doesn't fit its field").unwrap();
+    writeln!(ret, "    pub fn encode(&self) -> Result<Vec<u8>, String> {{\n        let mut out = Vec::new();\n{enc}        Ok(out)\n    }}\n").unwrap();
+    writeln!(ret, "    /// Decodes one packet from the front of `buf`, returning it and the number of bytes used").unwrap();
+    let ret_mut = if decl.is_empty() { "" } else { "mut " };
+    writeln!(ret, "    pub fn decode(buf: &[u8]) -> Option<(Self, usize)> {{\n        let {ret_mut}ret = Self::default();\n        let mut pos = 0;\n{dec}        Some((ret, pos))\n    }}\n}}").unwrap();
+
+    if fields.iter().any(|f| matches!(f.datatype, Some(PacketDataType::ULeb128 | PacketDataType::ZigZag))){
+        ret.push_str(ULEB_HELPERS);
+    }
+    if fields.iter().any(|f| matches!(f.datatype, Some(PacketDataType::SLeb128))){
+        ret.push_str(SLEB_HELPERS);
+    }
+    if fields.iter().any(|f| matches!(f.datatype, Some(PacketDataType::ZigZag))){
+        ret.push_str(ZIGZAG_HELPERS);
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::process::Command;
+
+    fn field(view : &mut Vec<PacketField>, name : &str, dtype : PacketDataType, meth : Option<SizingMethod>, data : &str){
+        let mut f = PacketField::typed(view.len(), name.to_string(), dtype, meth);
+        f.data_string = data.to_string();
+        view.push(f);
+    }
+
+    #[test]
+    fn identifiers_are_unique(){
+        let mut fields = Vec::new();
+        for name in ["Len", "len", "len_2", "pad0", ""]{
+            field(&mut fields, name, PacketDataType::U8, None, "0");
+        }
+        field(&mut fields, "", PacketDataType::Padding, Some(SizingMethod::FixedSize(1)), "0");
+        let view = PacketView::with_fields(0, "x".into(), fields);
+        assert_eq!(field_idents(&view), ["len", "len_2", "len_2_2", "pad0", "field4", "pad5"]);
+    }
+
+    #[test]
+    fn empty_delimiter_is_refused(){
+        let mut fields = Vec::new();
+        field(&mut fields, "body", PacketDataType::Bytes(SizingMethod::Delimiter), Some(SizingMethod::Delimiter), "");
+        assert!(rust_module(&PacketView::with_fields(0, "x".into(), fields)).is_err());
+    }
+
+    #[test]
+    fn rust_encoder_matches_to_bytes(){
+        let dir = std::env::temp_dir().join(format!("packetmancer-codegen-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = |name : &str, data : &[u8]|{
+            let path = dir.join(name);
+            std::fs::write(&path, data).unwrap();
+            path.to_str().unwrap().to_string()
+        };
+
+        let mut fields = Vec::new();
+        field(&mut fields, "id", PacketDataType::U16, None, "513");
+        field(&mut fields, "len", PacketDataType::U8, None, "5");
+        field(&mut fields, "payload", PacketDataType::Bytes(SizingMethod::SizeHeader(1)), Some(SizingMethod::SizeHeader(1)), &file("payload", b"hello"));
+        field(&mut fields, "", PacketDataType::Padding, Some(SizingMethod::FixedSize(2)), "170");
+        field(&mut fields, "", PacketDataType::Align, Some(SizingMethod::FixedSize(8)), "0");
+        field(&mut fields, "name", PacketDataType::CStr, None, "hi");
+        field(&mut fields, "count", PacketDataType::ULeb128, None, "300");
+        field(&mut fields, "delta", PacketDataType::ZigZag, None, "-5");
+        field(&mut fields, "offset", PacketDataType::SLeb128, None, "-129");
+        field(&mut fields, "line", PacketDataType::Bytes(SizingMethod::Delimiter), Some(SizingMethod::Delimiter), &file("line", b"ab"));
+        fields[9].sizing_meth_str = "\\r\\n".to_string();
+        field(&mut fields, "Line", PacketDataType::I32, None, "-2");
+        field(&mut fields, "tag", PacketDataType::Bytes(SizingMethod::FixedSize(3)), Some(SizingMethod::FixedSize(3)), &file("tag", b"xyz"));
+        let view = PacketView::with_fields(0, "msg".into(), fields);
+        let expected = view.to_bytes(&Default::default()).unwrap();
+
+        let main = r#"
+fn main() {
+    let p = Msg { id: 513, payload: b"hello".to_vec(), name: "hi".into(), count: 300, delta: -5, offset: -129, line: b"ab".to_vec(), line_2: -2, tag: b"xyz".to_vec() };
+    let enc = p.encode().unwrap();
+    assert_eq!(Msg::decode(&enc), Some((p.clone(), enc.len())));
+    // values to_bytes couldnt send either are refused
+    assert!(Msg { tag: b"xy".to_vec(), ..p.clone() }.encode().is_err());
+    assert!(Msg { payload: vec![0; 256], ..p.clone() }.encode().is_err());
+    assert!(Msg { name: "a\0b".into(), ..p.clone() }.encode().is_err());
+    print!("{enc:?}");
+}
+"#;
+        let src = file("gen.rs", (rust_module(&view).unwrap() + main).as_bytes());
+        let bin = dir.join("gen");
+        let rustc = std::env::var("RUSTC").unwrap_or("rustc".to_string());
+        let out = Command::new(rustc).args(["--edition", "2021", "-o"]).arg(&bin).arg(&src).output().unwrap();
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        let out = Command::new(&bin).output().unwrap();
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(String::from_utf8(out.stdout).unwrap(), format!("{expected:?}"));
+    }
+}
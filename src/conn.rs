@@ -0,0 +1,279 @@
+use std::fmt::Display;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
+
+use iced::{widget::{button, pick_list, row, text, text_input, Column}, Element, Length};
+
+use crate::framing::Framing;
+use crate::passive::Passive;
+use crate::serial::SerialSettings;
+use crate::state::Message;
+use crate::timeouts::{self, TimeoutSettings};
+use crate::tls::TlsSettings;
+use crate::transport::{self, Connection, Datagram, Transport};
+use crate::websocket::WsSettings;
+
+// One named connection: where it goes, how, and the socket once it is up.
+// Packets and scenario steps pick one by name, so several clients of the same
+// server can be driven side by side.
+
+/// Entry in the connection pickers, empty falls back to the default connection
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnChoice(pub(crate) String);
+
+impl Display for ConnChoice{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0.is_empty() { write!(f, "Default connection") } else { write!(f, "{}", self.0) }
+    }
+}
+
+/// The default entry followed by every connection's name
+pub fn choices(conns : &[Conn]) -> Vec<ConnChoice>{
+    std::iter::once(ConnChoice(String::new())).chain(conns.iter().map(|c| ConnChoice(c.name.clone()))).collect()
+}
+
+#[derive(Default)]
+pub struct Conn{
+    pub(crate) name : String,
+    pub(crate) current_ip : String,
+    pub(crate) current_port : String,
+    pub(crate) transport : Transport,
+    pub(crate) unix_path : String,
+    pub(crate) serial : SerialSettings,
+    pub(crate) websocket : WsSettings,
+    pub(crate) tls : TlsSettings,
+    pub(crate) sock : Option<Connection>,
+    /// Waiting for a single peer to connect to us, replaced by `sock` once it does
+    pub(crate) listener : Option<TcpListener>,
+    pub(crate) accept_run : usize,
+    /// Splits what arrives on `sock` into messages, holds bytes read ahead of the current one
+    pub(crate) framing : Framing,
+    pub(crate) timeouts : TimeoutSettings,
+    /// Made with Connect rather than accepted, so it can be made again
+    pub(crate) outbound : bool,
+    /// (run id, attempt) while auto reconnect is dialing
+    pub(crate) reconnect : Option<(usize, usize)>,
+    /// Set when an operation finds the connection gone, picked up after the message is handled
+    pub(crate) lost : bool,
+    /// Outcome of the last failed operation or reconnect
+    pub(crate) status : String,
+    pub(crate) passive : Passive
+}
+
+impl Conn{
+    pub fn new(name : String) -> Self{
+        Self { name, ..Default::default() }
+    }
+
+    pub fn disconnect(&mut self){
+        if let Some(mut s) = self.sock.take(){
+            // The peer may already be gone, nothing left to do about it
+            let _ = s.shutdown();
+        }
+        // Nothing read ahead on the old connection belongs to a new one
+        self.framing.clear();
+    }
+
+    pub fn connect(&mut self) -> io::Result<()>{
+        self.disconnect();
+        let s = self.dialer()()?;
+        self.attach(s, true)
+    }
+
+    /// Makes the connection the way `connect` does without touching this one,
+    /// so the dial can run off the UI thread. The result goes to `attach`
+    pub fn dialer(&self) -> impl FnOnce() -> io::Result<Connection> + Send + 'static{
+        let (transport, ip, port, unix_path) = (self.transport, self.current_ip.clone(), self.current_port.clone(), self.unix_path.clone());
+        let (serial, websocket, tls, timeout) = (self.serial.clone(), self.websocket.clone(), self.tls.clone(), self.timeouts.connect());
+        move || Ok(match transport{
+            Transport::Tcp => {
+                let s = transport::tcp_connect(format!("{ip}:{port}"), timeout)?;
+                if tls.enabled { tls.connect(s, &ip)? } else { Connection::Tcp(s) }
+            },
+            Transport::UnixStream => Connection::Unix(UnixStream::connect(&unix_path)?),
+            Transport::UnixDatagram => Connection::UnixDatagram(Datagram::connect(&unix_path)?),
+            Transport::Serial => Connection::Serial(serial.open()?),
+            Transport::WebSocket => websocket.connect(&tls, timeout)?,
+        })
+    }
+
+    /// Makes `s` the current connection with the configured read and write timeouts
+    pub fn attach(&mut self, mut s : Connection, outbound : bool) -> io::Result<()>{
+        s.set_read_timeout(self.timeouts.read())?;
+        s.set_write_timeout(self.timeouts.write())?;
+        self.sock = Some(s);
+        self.outbound = outbound;
+        self.status.clear();
+        Ok(())
+    }
+
+    pub fn listen(&mut self) -> io::Result<()>{
+        self.disconnect();
+        let ip = if self.current_ip.is_empty() { "0.0.0.0" } else { self.current_ip.as_str() };
+        let listener = TcpListener::bind(format!("{ip}:{}", self.current_port))?;
+        listener.set_nonblocking(true)?;
+        self.listener = Some(listener);
+        Ok(())
+    }
+
+    /// Takes a waiting peer if there is one, None while nobody has connected yet.
+    /// It still has to go through `handshaker` before it can be attached
+    pub fn accept(&mut self) -> io::Result<Option<TcpStream>>{
+        let Some(listener) = &self.listener else { return Ok(None) };
+        let s = match listener.accept(){
+            Ok((s, _)) => s,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+            Err(e) => {
+                self.listener = None;
+                return Err(e);
+            }
+        };
+        self.listener = None;
+        s.set_nonblocking(false)?;
+        Ok(Some(s))
+    }
+
+    /// Runs the server side of the TLS handshake on an accepted peer when enabled.
+    /// Like `dialer` this is meant for the executor's threads, the result goes to `attach`
+    pub fn handshaker(&self, s : TcpStream) -> impl FnOnce() -> io::Result<Connection> + Send + 'static{
+        let tls = self.tls.clone();
+        move || if tls.enabled { tls.accept(s) } else { Ok(Connection::Tcp(s)) }
+    }
+
+    /// Drops the connection after a failed operation, the state then starts reconnecting if enabled
+    pub fn lose(&mut self, status : String){
+        self.status = status;
+        self.disconnect();
+        self.lost = true;
+    }
+
+    /// Remembers why an operation failed and whether the connection went with it
+    pub fn io_failed(&mut self, op : &str, e : &io::Error){
+        if timeouts::is_disconnect(e){
+            self.lose(timeouts::describe(op, e));
+        }
+        else{
+            self.status = timeouts::describe(op, e);
+        }
+    }
+
+    /// Writes all of `dat`, false when not connected
+    pub fn write(&mut self, dat : &[u8]) -> io::Result<bool>{
+        let Some(s) = &mut self.sock else { return Ok(false) };
+        if let Err(e) = s.write_all(dat){
+            self.io_failed("Send", &e);
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    /// (local, peer) addresses while connected over IP
+    pub fn endpoints(&self) -> Option<(SocketAddr, SocketAddr)>{
+        self.sock.as_ref()?.endpoints()
+    }
+
+    /// Puts the read timeout back after an operation used its own
+    pub fn restore_read_timeout(&mut self) -> io::Result<()>{
+        match &mut self.sock{
+            Some(s) => s.set_read_timeout(self.timeouts.read()),
+            None => Ok(())
+        }
+    }
+
+    /// Takes the outcome of an auto reconnect dial, true while there are attempts left
+    pub fn redialed(&mut self, res : Result<Connection, String>) -> bool{
+        let Some((run, attempt)) = self.reconnect else { return false };
+        self.disconnect();
+        match res.and_then(|s| self.attach(s, true).map_err(|e| e.to_string())){
+            Ok(_) => {
+                self.reconnect = None;
+                self.status = format!("Reconnected on attempt {}", attempt + 1);
+                false
+            },
+            Err(e) if attempt + 1 >= self.timeouts.retries() => {
+                self.reconnect = None;
+                self.status = format!("Gave up reconnecting after {} attempts: {e}", attempt + 1);
+                false
+            },
+            Err(e) => {
+                self.reconnect = Some((run, attempt + 1));
+                self.status = format!("Last attempt failed: {e}");
+                true
+            }
+        }
+    }
+
+    fn target(&self) -> String{
+        match self.transport{
+            Transport::Tcp => format!("{}:{}", self.current_ip, self.current_port),
+            Transport::UnixStream | Transport::UnixDatagram => self.unix_path.clone(),
+            Transport::Serial => format!("{} at {} baud", self.serial.path, self.serial.baud()),
+            Transport::WebSocket => self.websocket.url.clone(),
+        }
+    }
+
+    /// The connection's line in the connection list
+    pub fn draw(&self, idx : usize, selected : bool, default : bool, removable : bool) -> Element<'_, Message>{
+        let mut r = row![
+            if selected { button("Editing") } else { button("Edit").on_press(Message::SelectConnection(idx)) },
+            text_input("Connection name", &self.name).on_input(move |x| Message::ConnNameEntry(idx, x)).width(Length::Fixed(150.0)),
+        ].spacing(5);
+        if default{
+            r = r.push(text("Default"));
+        }
+        else if removable{
+            r = r.push(button("Make default").on_press(Message::DefaultConnection(idx)));
+        }
+        if let Some(l) = self.listener.as_ref().filter(|_| self.sock.is_none()){
+            r = r.push(button("Stop listening").on_press(Message::StopListening(idx)));
+            r = r.push(text!("Listening on: {}", l.local_addr().map(|x| x.to_string()).unwrap_or_default()));
+        }
+        else if self.sock.is_none(){
+            r = r.push(button("Connect").on_press(Message::Connect(idx)));
+            if self.transport == Transport::Tcp{
+                r = r.push(button("Listen").on_press(Message::Listen(idx)));
+            }
+        }
+        else{
+            r = r.push(button("Disconnect").on_press(Message::Disconnect(idx)));
+            r = r.push(self.passive.draw(idx, self.framing.buf.len()));
+            r = r.push(text!("Connected to: {}", self.target()));
+        }
+        if let Some((_, attempt)) = self.reconnect{
+            r = r.push(text!("Reconnecting, attempt {} of {}  {}", attempt + 1, self.timeouts.retries(), self.status));
+        }
+        else if !self.status.is_empty(){
+            r = r.push(text(&self.status));
+        }
+        if removable{
+            r = r.push(button("Remove").on_press(Message::RemoveConnection(idx)));
+        }
+        r.into()
+    }
+
+    /// Where and how to connect, edited for the selected connection
+    pub fn draw_settings(&self) -> Element<'_, Message>{
+        let mut col = Column::new();
+        let mut addr_row = row![pick_list(Transport::ALL, Some(self.transport), Message::TransportSelect)].spacing(5);
+        match self.transport{
+            Transport::Tcp => {
+                addr_row = addr_row.push(text_input("Input IP", &self.current_ip).on_input(Message::IpEntry));
+                addr_row = addr_row.push(text_input("Input Port", &self.current_port).on_input(Message::PortEntry));
+            },
+            Transport::UnixStream | Transport::UnixDatagram => addr_row = addr_row.push(text_input("Socket path", &self.unix_path).on_input(Message::UnixPathEntry)),
+            Transport::Serial => addr_row = addr_row.push(self.serial.draw()),
+            Transport::WebSocket => addr_row = addr_row.push(self.websocket.draw()),
+        }
+        col = col.push(addr_row);
+        if self.transport == Transport::Tcp{
+            col = col.push(self.tls.draw());
+        }
+        else if self.transport == Transport::WebSocket && self.websocket.url.starts_with("wss://"){
+            col = col.push(self.tls.draw_certs());
+        }
+        col = col.push(self.timeouts.draw(self.transport == Transport::Serial));
+        col = col.push(self.framing.draw(Message::FramingMessage));
+        col.spacing(10).into()
+    }
+}
@@ -0,0 +1,221 @@
+use std::fmt::Write;
+
+use crate::codegen::{field_idents, ident};
+use crate::packet::{unescape_bytes, PacketDataType, PacketView, SizingMethod};
+
+// Wireshark Lua dissector generation. Every PacketView gets a decode function,
+// a protocol preference picks which one is applied to the payload. Values were
+// encoded in host byte order so that is what the dissector reads them in.
+
+const LEB_HELPERS : &str = r#"
+local function read_uleb128(buf, off)
+    local val = UInt64(0)
+    local mult = UInt64(1)
+    local n = 0
+    repeat
+        if off + n >= buf:len() then return nil end
+        local b = buf(off + n, 1):uint()
+        val = val + mult * (b % 128)
+        mult = mult * 128
+        n = n + 1
+    until b < 128
+    return val, n
+end
+
+local function read_sleb128(buf, off)
+    local u, n = read_uleb128(buf, off)
+    if u == nil then return nil end
+    local s = Int64.fromhex(u:tohex())
+    if buf(off + n - 1, 1):uint() % 128 >= 64 and 7 * n < 64 then
+        s = s - Int64(1):lshift(7 * n)
+    end
+    return s, n
+end
+
+local function read_zigzag(buf, off)
+    local u, n = read_uleb128(buf, off)
+    if u == nil then return nil end
+    local s = Int64.fromhex(u:rshift(1):tohex())
+    if u:band(1) == UInt64(1) then s = -s - 1 end
+    return s, n
+end
+
+local function find_delim(buf, off, delim)
+    for i = off, buf:len() - delim:len() do
+        if buf(i, delim:len()):bytes() == delim then return i end
+    end
+    return nil
+end
+"#;
+
+fn proto_field(dtype : PacketDataType) -> &'static str{
+    match dtype{
+        PacketDataType::U8 => "uint8",
+        PacketDataType::U16 => "uint16",
+        PacketDataType::U32 => "uint32",
+        PacketDataType::U64 | PacketDataType::ULeb128 => "uint64",
+        PacketDataType::I8 => "int8",
+        PacketDataType::I16 => "int16",
+        PacketDataType::I32 => "int32",
+        PacketDataType::I64 | PacketDataType::SLeb128 | PacketDataType::ZigZag => "int64",
+        PacketDataType::CStr => "stringz",
+        PacketDataType::Bytes(_) | PacketDataType::Padding | PacketDataType::Align | PacketDataType::Script => "bytes",
+    }
+}
+
+/// Quoted Lua string literal. Anything outside printable ASCII is written as `\ddd`
+/// byte escapes, which every Lua version Wireshark ships with understands
+fn lua_string(s : &str) -> String{
+    let mut ret = String::from("\"");
+    for b in s.bytes(){
+        match b{
+            b'"' | b'\\' => {
+                ret.push('\\');
+                ret.push(b as char);
+            },
+            0x20..0x7f => ret.push(b as char),
+            _ => write!(ret, "\\{b:03}").unwrap(),
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+pub fn lua_dissector(views : &[PacketView], port : u16) -> String{
+    let le = cfg!(target_endian = "little");
+    let (add, prefix) = if le { ("add_le", "le_") } else { ("add", "") };
+    let mut fields = String::new();
+    let mut funcs = String::new();
+    let mut layouts = Vec::new();
+    let mut vnames : Vec<String> = Vec::new();
+    // Functions and field keys go by position, indices of packets loaded from different files can repeat
+    for (n, v) in views.iter().enumerate(){
+        // Abbrevs have to be unique across the whole protocol, so layouts with the same name get a suffix
+        let base = ident(&v.lable, format!("packet{}", v.index));
+        let vname = (1..).map(|n| if n == 1 { base.clone() } else { format!("{base}_{n}") }).find(|x| !vnames.contains(x)).unwrap();
+        vnames.push(vname.clone());
+        let ids = field_idents(v);
+        let title = if v.lable.is_empty() { vname.clone() } else { v.lable.clone() };
+        writeln!(funcs, "local function dissect_{n}(buf, tree, off)\n    local start = off\n    local vals = {{}}\n    local len, val, d").unwrap();
+        writeln!(funcs, "    local sub = tree:add(proto, buf(off), {})", lua_string(&title)).unwrap();
+        layouts.push(title);
+        for f in v.fields.iter(){
+            let Some(dtype) = f.datatype else { continue };
+            let key = format!("l{n}_f{}", f.index);
+            let fname = &ids[f.index];
+            let label = if f.name.is_empty() { fname.clone() } else { f.name.clone() };
+            let base = if matches!(dtype, PacketDataType::CStr | PacketDataType::Bytes(_) | PacketDataType::Padding | PacketDataType::Align | PacketDataType::Script) { "" } else { ", base.DEC" };
+            writeln!(fields, "pf[\"{key}\"] = ProtoField.{}(\"packetmancer.{vname}.{fname}\", {}{base})", proto_field(dtype), lua_string(&label)).unwrap();
+
+            // `len` is how many bytes the field covers, `val` what later SizeHeaders read
+            match (dtype, f.sizing_method){
+                (PacketDataType::Padding, Some(SizingMethod::FixedSize(n))) => writeln!(funcs, "    len = {n}").unwrap(),
+                (PacketDataType::Align, Some(SizingMethod::FixedSize(n))) if n > 0 => writeln!(funcs, "    len = ({n} - (off - start) % {n}) % {n}").unwrap(),
+                (PacketDataType::Padding | PacketDataType::Align, _) => writeln!(funcs, "    len = 0").unwrap(),
+                (PacketDataType::Script, _) => writeln!(funcs, "    len = {}", v.script_len(f)).unwrap(),
+                (PacketDataType::CStr, _) => {
+                    writeln!(funcs, "    d = find_delim(buf, off, ByteArray.new(\"00\"))\n    if d == nil then return nil end\n    len = d - off + 1").unwrap();
+                },
+                (PacketDataType::Bytes(_), meth) => match meth{
+                    Some(SizingMethod::FixedSize(n)) => writeln!(funcs, "    len = {n}").unwrap(),
+                    Some(SizingMethod::SizeHeader(x)) => writeln!(funcs, "    len = vals[{x}] or 0").unwrap(),
+                    Some(SizingMethod::Delimiter) => {
+                        let hex : String = unescape_bytes(&f.sizing_meth_str).unwrap_or_default().iter().map(|b| format!("{b:02x}")).collect();
+                        writeln!(funcs, "    d = find_delim(buf, off, ByteArray.new(\"{hex}\"))\n    if d == nil then return nil end\n    len = d - off + {}", hex.len() / 2).unwrap();
+                    },
+                    Some(SizingMethod::UntilClose) | None => writeln!(funcs, "    len = buf:len() - off").unwrap(),
+                },
+                (PacketDataType::ULeb128 | PacketDataType::SLeb128 | PacketDataType::ZigZag, _) => {
+                    let reader = match dtype{
+                        PacketDataType::ULeb128 => "read_uleb128",
+                        PacketDataType::SLeb128 => "read_sleb128",
+                        _ => "read_zigzag",
+                    };
+                    writeln!(funcs, "    val, len = {reader}(buf, off)\n    if val == nil then return nil end").unwrap();
+                    writeln!(funcs, "    vals[{}] = val:tonumber()\n    sub:add(pf[\"{key}\"], buf(off, len), val)\n    off = off + len", f.index).unwrap();
+                    continue;
+                },
+                _ => {
+                    let n = dtype.data_size();
+                    let getter = match dtype{
+                        PacketDataType::U64 => format!("{prefix}uint64():tonumber()"),
+                        PacketDataType::I64 => format!("{prefix}int64():tonumber()"),
+                        PacketDataType::I8 | PacketDataType::I16 | PacketDataType::I32 => format!("{prefix}int()"),
+                        _ => format!("{prefix}uint()"),
+                    };
+                    writeln!(funcs, "    if off + {n} > buf:len() then return nil end").unwrap();
+                    writeln!(funcs, "    vals[{}] = buf(off, {n}):{getter}\n    sub:{add}(pf[\"{key}\"], buf(off, {n}))\n    off = off + {n}", f.index).unwrap();
+                    continue;
+                }
+            }
+            writeln!(funcs, "    if off + len > buf:len() then return nil end").unwrap();
+            writeln!(funcs, "    if len > 0 then sub:add(pf[\"{key}\"], buf(off, len)) end\n    off = off + len").unwrap();
+        }
+        writeln!(funcs, "    sub:set_len(off - start)\n    return off\nend\n").unwrap();
+    }
+
+    let mut ret = String::new();
+    writeln!(ret, "-- Generated by packetmancer, load with wireshark -X lua_script:<this file>").unwrap();
+    writeln!(ret, "local proto = Proto(\"packetmancer\", \"Packetmancer\")\nlocal pf = {{}}").unwrap();
+    ret.push_str(&fields);
+    writeln!(ret, "proto.fields = pf").unwrap();
+    ret.push_str(LEB_HELPERS);
+    writeln!(ret).unwrap();
+    ret.push_str(&funcs);
+    let table : Vec<String> = layouts.iter().enumerate().map(|(i, t)| format!("{{{}, {}, {}}}", i + 1, lua_string(t), i + 1)).collect();
+    let dispatch : Vec<String> = (0..layouts.len()).map(|n| format!("dissect_{n}")).collect();
+    writeln!(ret, "local layouts = {{ {} }}", dispatch.join(", ")).unwrap();
+    writeln!(ret, "proto.prefs.layout = Pref.enum(\"Layout\", 1, \"Packet layout used to decode payloads\", {{ {} }}, false)\n", table.join(", ")).unwrap();
+    writeln!(ret, r#"function proto.dissector(buf, pinfo, tree)
+    if #layouts == 0 then return 0 end
+    pinfo.cols.protocol = "PACKETMANCER"
+    local off = 0
+    while off < buf:len() do
+        local next_off = layouts[proto.prefs.layout](buf, tree, off)
+        if next_off == nil then
+            -- the rest of the packet is in a later segment
+            pinfo.desegment_offset = off
+            pinfo.desegment_len = DESEGMENT_ONE_MORE_SEGMENT
+            return buf:len()
+        end
+        if next_off == off then break end
+        off = next_off
+    end
+    return off
+end
+
+DissectorTable.get("tcp.port"):add({port}, proto)
+DissectorTable.get("udp.port"):add({port}, proto)"#).unwrap();
+    ret
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::packet::PacketField;
+
+    #[test]
+    fn names_are_unique(){
+        let view = |index : usize, lable : &str| PacketView::with_fields(index, lable.to_string(), vec![
+            PacketField::typed(0, "Seq".to_string(), PacketDataType::U8, None),
+            PacketField::typed(1, "seq".to_string(), PacketDataType::U16, None),
+        ]);
+        let lua = lua_dissector(&[view(0, "Hello"), view(1, "hello"), view(1, "other")], 9000);
+        let abbrevs : Vec<&str> = lua.lines().filter_map(|l| l.split("(\"packetmancer.").nth(1)?.split('"').next()).collect();
+        assert_eq!(abbrevs[..4], ["hello.seq", "hello.seq_2", "hello_2.seq", "hello_2.seq_2"]);
+        for prefix in ["local function ", "pf[\""]{
+            let mut names : Vec<&str> = lua.lines().filter_map(|l| l.strip_prefix(prefix)?.split(['(', '"']).next()).collect();
+            let n = names.len();
+            names.sort();
+            names.dedup();
+            assert_eq!(names.len(), n, "{prefix}");
+        }
+        assert!(lua.contains("local layouts = { dissect_0, dissect_1, dissect_2 }"));
+        assert!(lua.contains("DissectorTable.get(\"tcp.port\"):add(9000, proto)"));
+    }
+
+    #[test]
+    fn strings_use_byte_escapes(){
+        assert_eq!(lua_string("caf\u{e9} \"x\"\\\n"), "\"caf\\195\\169 \\\"x\\\"\\\\\\010\"");
+    }
+}
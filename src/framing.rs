@@ -0,0 +1,397 @@
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::io::{self, Cursor, ErrorKind, Read};
+use std::net::TcpStream;
+
+use iced::{widget::{pick_list, row, text, text_input, toggler}, Element, Length};
+
+use crate::packet::{unescape_bytes, PacketView};
+use crate::state::Message;
+use crate::variables::Variables;
+
+// Splits the bytes coming off the connection into messages. Reads land in a
+// buffer first and a recieve only takes the frame at its front, so whatever
+// arrived with it stays put for the next recieve instead of being lost to an
+// over-eager read. Transports that keep message boundaries, like datagrams,
+// hand over whole messages and every one of them is a frame of its own.
+
+/// Largest message a length prefix or delimiter may announce. Past it the
+/// stream is taken to be out of step rather than buffered without end
+pub const MAX_FRAME : usize = 16 << 20;
+
+/// Where frames are read from
+pub trait Source : Read{
+    /// Every message arrives whole and is one frame, whatever the rule says
+    fn keeps_boundaries(&self) -> bool{
+        false
+    }
+
+    /// Whatever is ready, one whole message where boundaries are kept. None
+    /// once the peer closed, so an empty message is not taken for a close
+    fn message(&mut self) -> io::Result<Option<Vec<u8>>>{
+        let mut dat = vec![0; 65536];
+        let n = self.read(&mut dat)?;
+        dat.truncate(n);
+        Ok((n > 0).then_some(dat))
+    }
+}
+
+impl<T : AsRef<[u8]>> Source for Cursor<T>{}
+
+impl Source for TcpStream{}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FrameRule{
+    /// The message is as long as the template being recieved says
+    #[default]
+    Template,
+    /// A length field at a fixed offset covers the rest of the message
+    LengthPrefix,
+    /// Messages end with a delimiter, e.g. \r\n
+    Delimiter
+}
+
+impl FrameRule{
+    pub const ALL : [FrameRule; 3] = [FrameRule::Template, FrameRule::LengthPrefix, FrameRule::Delimiter];
+}
+
+impl Display for FrameRule{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self{
+            FrameRule::Template => write!(f, "Framed by template"),
+            FrameRule::LengthPrefix => write!(f, "Length prefix"),
+            FrameRule::Delimiter => write!(f, "Delimiter"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum FramingMessage{
+    Rule(FrameRule),
+    OffsetEntry(String),
+    Width(usize),
+    BigEndian(bool),
+    CountsHeader(bool),
+    DelimiterEntry(String)
+}
+
+#[derive(Clone)]
+pub struct Framing{
+    pub(crate) rule : FrameRule,
+    pub(crate) offset_string : String,
+    /// Size of the length field in bytes
+    pub(crate) width : usize,
+    pub(crate) big_endian : bool,
+    /// The length covers the whole message, not just what follows the length field
+    pub(crate) counts_header : bool,
+    pub(crate) delimiter_string : String,
+    /// Bytes read off the connection that no message has claimed yet
+    pub(crate) buf : Vec<u8>,
+    /// Where the messages in the buffer end, for transports that keep boundaries
+    ends : VecDeque<usize>
+}
+
+impl Default for Framing{
+    fn default() -> Self {
+        Self {
+            rule: Default::default(),
+            offset_string: Default::default(),
+            width: 2,
+            big_endian: true,
+            counts_header: false,
+            delimiter_string: "\\r\\n".to_string(),
+            buf: Vec::new(),
+            ends: VecDeque::new()
+        }
+    }
+}
+
+impl Framing{
+    pub fn offset(&self) -> usize{
+        self.offset_string.parse().unwrap_or_default()
+    }
+
+    /// Whether the buffer holds whole messages off a transport that keeps boundaries
+    pub fn by_message(&self) -> bool{
+        !self.ends.is_empty()
+    }
+
+    /// Length of the complete frame at the front of the buffer by message
+    /// boundaries, or by the length prefix or delimiter rule. None while it
+    /// is still incomplete or when framing by template
+    pub fn split(&self) -> io::Result<Option<usize>>{
+        if let Some(end) = self.ends.front(){
+            return Ok(Some(*end));
+        }
+        match self.rule{
+            FrameRule::Template => Ok(None),
+            FrameRule::LengthPrefix => {
+                let header = self.offset() + self.width;
+                let Some(field) = self.buf.get(self.offset()..header) else { return Ok(None) };
+                let len = if self.big_endian{
+                    field.iter().fold(0u64, |acc, b| acc << 8 | *b as u64)
+                }
+                else{
+                    field.iter().rev().fold(0u64, |acc, b| acc << 8 | *b as u64)
+                } as usize;
+                let total = if self.counts_header { len } else { header.saturating_add(len) };
+                if total < header{
+                    return Err(io::Error::new(ErrorKind::InvalidData, format!("Length prefix says {len} bytes, the header alone is {header}")));
+                }
+                if total > MAX_FRAME{
+                    return Err(io::Error::new(ErrorKind::InvalidData, format!("Length prefix says {len} bytes, more than the {MAX_FRAME} a message may have")));
+                }
+                Ok((self.buf.len() >= total).then_some(total))
+            },
+            FrameRule::Delimiter => {
+                let delim = unescape_bytes(&self.delimiter_string).filter(|d| !d.is_empty())
+                    .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, format!("{} is not a valid delimiter", self.delimiter_string)))?;
+                match self.buf.windows(delim.len()).position(|w| w == delim){
+                    Some(i) => Ok(Some(i + delim.len())),
+                    None if self.buf.len() > MAX_FRAME => Err(io::Error::new(ErrorKind::InvalidData, format!("No delimiter in the last {} bytes", self.buf.len()))),
+                    None => Ok(None)
+                }
+            }
+        }
+    }
+
+    /// Length of the complete frame at the front of the buffer when recieving `view`
+    fn frame_len(&self, view : &PacketView, vars : &Variables) -> io::Result<Option<usize>>{
+        if self.rule != FrameRule::Template || self.by_message(){
+            return self.split();
+        }
+        let too_long = || io::Error::new(ErrorKind::InvalidData, format!("No message for the template in the last {} bytes", self.buf.len()));
+        // Only the peer closing ends it
+        if view.reads_until_close(){
+            return if self.buf.len() > MAX_FRAME { Err(too_long()) } else { Ok(None) };
+        }
+        let mut view = view.clone();
+        let mut cur = Cursor::new(&self.buf);
+        match view.decode(&mut cur, vars){
+            Ok(_) => Ok(Some(cur.position() as usize)),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof && self.buf.len() > MAX_FRAME => Err(too_long()),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Appends whatever the connection has ready, false when the peer closed
+    pub fn fill<S : Source>(&mut self, s : &mut S) -> io::Result<bool>{
+        let Some(dat) = s.message()? else { return Ok(false) };
+        self.buf.extend_from_slice(&dat);
+        if s.keeps_boundaries(){
+            self.ends.push_back(self.buf.len());
+        }
+        Ok(true)
+    }
+
+    /// Takes `n` bytes off the front of the buffer
+    pub fn take(&mut self, n : usize) -> Vec<u8>{
+        while self.ends.front().is_some_and(|e| *e <= n){
+            self.ends.pop_front();
+        }
+        for e in &mut self.ends{
+            *e -= n;
+        }
+        self.buf.drain(..n).collect()
+    }
+
+    /// Takes everything buffered
+    pub fn take_all(&mut self) -> Vec<u8>{
+        self.ends.clear();
+        std::mem::take(&mut self.buf)
+    }
+
+    pub fn clear(&mut self){
+        self.buf.clear();
+        self.ends.clear();
+    }
+
+    /// Takes over what a copy that read off another thread left buffered
+    pub fn rejoin(&mut self, copy : Framing){
+        self.buf = copy.buf;
+        self.ends = copy.ends;
+    }
+
+    /// Reads until a whole message for `view` is buffered and takes it. Errors
+    /// like timeouts leave what was read so far buffered, once the peer closed
+    /// the message is whatever is left
+    pub fn next_frame<S : Source>(&mut self, s : &mut S, view : &PacketView, vars : &Variables) -> io::Result<Vec<u8>>{
+        loop{
+            if let Some(n) = self.frame_len(view, vars)?{
+                return Ok(self.take(n));
+            }
+            if !self.fill(s)?{
+                if self.buf.is_empty(){
+                    return Err(ErrorKind::UnexpectedEof.into());
+                }
+                return Ok(self.take_all());
+            }
+        }
+    }
+
+    pub fn update(&mut self, msg : FramingMessage){
+        match msg{
+            FramingMessage::Rule(x) => self.rule = x,
+            FramingMessage::OffsetEntry(x) => if x.is_empty() || x.parse::<usize>().is_ok() { self.offset_string = x },
+            FramingMessage::Width(x) => self.width = x,
+            FramingMessage::BigEndian(x) => self.big_endian = x,
+            FramingMessage::CountsHeader(x) => self.counts_header = x,
+            FramingMessage::DelimiterEntry(x) => self.delimiter_string = x,
+        }
+    }
+
+    /// `wrap` says whose framing the edits go to
+    pub fn draw(&self, wrap : fn(FramingMessage) -> Message) -> Element<'_, Message>{
+        let mut r = row![pick_list(FrameRule::ALL, Some(self.rule), move |x| wrap(FramingMessage::Rule(x)))].spacing(5);
+        match self.rule{
+            FrameRule::Template => (),
+            FrameRule::LengthPrefix => {
+                r = r.push(text_input("Length offset", &self.offset_string).on_input(move |x| wrap(FramingMessage::OffsetEntry(x))).width(Length::Fixed(120.0)));
+                r = r.push(pick_list([1, 2, 4, 8], Some(self.width), move |x| wrap(FramingMessage::Width(x))));
+                r = r.push(text("byte length"));
+                r = r.push(toggler(self.big_endian).label("Big endian").on_toggle(move |x| wrap(FramingMessage::BigEndian(x))));
+                r = r.push(toggler(self.counts_header).label("Length counts header").on_toggle(move |x| wrap(FramingMessage::CountsHeader(x))));
+            },
+            FrameRule::Delimiter => {
+                r = r.push(text_input("Delimiter e.g. \\r\\n", &self.delimiter_string).on_input(move |x| wrap(FramingMessage::DelimiterEntry(x))).width(Length::Fixed(200.0)));
+            }
+        }
+        r.into()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::packet::{PacketDataType, PacketField, SizingMethod};
+
+    /// Hands out the chunks one read at a time, like segments off a socket
+    struct Chunks(VecDeque<Vec<u8>>);
+
+    impl Source for Chunks{}
+
+    impl Read for Chunks{
+        fn read(&mut self, buf : &mut [u8]) -> io::Result<usize>{
+            let Some(mut chunk) = self.0.pop_front() else { return Ok(0) };
+            let n = chunk.len().min(buf.len());
+            buf[..n].copy_from_slice(&chunk[..n]);
+            if n < chunk.len(){
+                self.0.push_front(chunk.split_off(n));
+            }
+            Ok(n)
+        }
+    }
+
+    fn chunks(parts : &[&[u8]]) -> Chunks{
+        Chunks(parts.iter().map(|p| p.to_vec()).collect())
+    }
+
+    fn any() -> PacketView{
+        PacketView::new(0)
+    }
+
+    fn length_prefix(width : usize, big_endian : bool, counts_header : bool) -> Framing{
+        Framing { rule: FrameRule::LengthPrefix, width, big_endian, counts_header, ..Default::default() }
+    }
+
+    #[test]
+    fn length_prefix_big_endian(){
+        let mut f = length_prefix(2, true, false);
+        let mut s = chunks(&[&[0, 3, 1, 2, 3, 0, 1, 9]]);
+        assert_eq!(f.next_frame(&mut s, &any(), &Variables::new()).unwrap(), [0, 3, 1, 2, 3]);
+        // The second frame came in the same read and stays buffered
+        assert_eq!(f.buf, [0, 1, 9]);
+        assert_eq!(f.next_frame(&mut s, &any(), &Variables::new()).unwrap(), [0, 1, 9]);
+    }
+
+    #[test]
+    fn length_prefix_little_endian_with_offset(){
+        let mut f = length_prefix(4, false, false);
+        f.offset_string = "1".to_string();
+        let mut s = chunks(&[&[0xaa, 2, 0, 0, 0, 7, 8, 0xbb]]);
+        assert_eq!(f.next_frame(&mut s, &any(), &Variables::new()).unwrap(), [0xaa, 2, 0, 0, 0, 7, 8]);
+        assert_eq!(f.buf, [0xbb]);
+    }
+
+    #[test]
+    fn length_prefix_counting_the_header(){
+        let mut f = length_prefix(1, true, true);
+        let mut s = chunks(&[&[3, 1, 2, 2, 5]]);
+        assert_eq!(f.next_frame(&mut s, &any(), &Variables::new()).unwrap(), [3, 1, 2]);
+        assert_eq!(f.next_frame(&mut s, &any(), &Variables::new()).unwrap(), [2, 5]);
+    }
+
+    #[test]
+    fn frame_split_across_reads(){
+        let mut f = length_prefix(2, true, false);
+        let mut s = chunks(&[&[0], &[4, 1], &[2, 3], &[4, 0xff]]);
+        assert_eq!(f.next_frame(&mut s, &any(), &Variables::new()).unwrap(), [0, 4, 1, 2, 3, 4]);
+        assert_eq!(f.buf, [0xff]);
+    }
+
+    #[test]
+    fn short_length_prefix_is_invalid(){
+        let mut f = length_prefix(1, true, true);
+        let mut s = chunks(&[&[0, 1, 2]]);
+        let e = f.next_frame(&mut s, &any(), &Variables::new()).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn oversized_length_prefix_is_invalid(){
+        let mut f = length_prefix(8, true, false);
+        let mut s = chunks(&[&[0xff; 8], &[1, 2, 3]]);
+        let e = f.next_frame(&mut s, &any(), &Variables::new()).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn delimiter(){
+        let mut f = Framing { rule: FrameRule::Delimiter, ..Default::default() };
+        let mut s = chunks(&[b"PING\r", b"\nPONG\r\nQU", b"IT\r\n"]);
+        let vars = Variables::new();
+        assert_eq!(f.next_frame(&mut s, &any(), &vars).unwrap(), b"PING\r\n");
+        assert_eq!(f.buf, b"PONG\r\nQU");
+        assert_eq!(f.next_frame(&mut s, &any(), &vars).unwrap(), b"PONG\r\n");
+        assert_eq!(f.next_frame(&mut s, &any(), &vars).unwrap(), b"QUIT\r\n");
+    }
+
+    #[test]
+    fn bad_delimiter_is_invalid(){
+        let mut f = Framing { rule: FrameRule::Delimiter, delimiter_string: "\\q".to_string(), ..Default::default() };
+        let e = f.next_frame(&mut chunks(&[b"x"]), &any(), &Variables::new()).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn template(){
+        let view = PacketView::with_fields(0, "t".to_string(), vec![
+            PacketField::typed(0, String::new(), PacketDataType::U8, None),
+            PacketField::typed(1, String::new(), PacketDataType::Bytes(SizingMethod::SizeHeader(0)), Some(SizingMethod::SizeHeader(0))),
+        ]);
+        let mut f = Framing::default();
+        let mut s = chunks(&[&[3, 1], &[2], &[3, 1, 9, 2]]);
+        let vars = Variables::new();
+        assert_eq!(f.next_frame(&mut s, &view, &vars).unwrap(), [3, 1, 2, 3]);
+        assert_eq!(f.buf, [1, 9, 2]);
+        assert_eq!(f.next_frame(&mut s, &view, &vars).unwrap(), [1, 9]);
+        // Cut off by the peer closing, whatever is left is the last message
+        assert_eq!(f.next_frame(&mut s, &view, &vars).unwrap(), [2]);
+        assert_eq!(f.next_frame(&mut s, &view, &vars).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn oversized_template_message_is_invalid(){
+        let view = PacketView::with_fields(0, "t".to_string(), vec![
+            PacketField::typed(0, String::new(), PacketDataType::U32, None),
+            PacketField::typed(1, String::new(), PacketDataType::Bytes(SizingMethod::SizeHeader(0)), Some(SizingMethod::SizeHeader(0))),
+        ]);
+        let mut f = Framing { buf: vec![0xff; MAX_FRAME], ..Default::default() };
+        assert!(f.frame_len(&view, &Variables::new()).unwrap().is_none());
+        f.buf.push(0);
+        assert_eq!(f.frame_len(&view, &Variables::new()).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,380 @@
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use iced::{widget::{button, pick_list, row, scrollable, text, text_input, toggler, Column}, Element, Length::{self, Fill}};
+
+use crate::packet::{PacketDataType, PacketView, SizingMethod};
+use crate::scenario::PacketChoice;
+use crate::state::Message;
+use crate::transport::{Connection, Handoff};
+use crate::variables::Variables;
+use crate::varint;
+
+// Mutation fuzzing of a single packet template. Every case is derived from the
+// seed and the case number alone, so a finding can be reproduced by running the
+// same seed again or by replaying the recorded bytes. Cases are sent from the
+// executor's threads with the connection taken along and handed back after.
+
+/// splitmix64, small and stable across builds which is all reproducibility needs
+pub struct Rng(u64);
+
+impl Rng{
+    pub fn new(seed : u64, case : u64) -> Self{
+        let mut rng = Self(seed ^ case.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+        rng.next_u64();
+        rng
+    }
+
+    pub fn next_u64(&mut self) -> u64{
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform-ish in 0..n, n has to be above 0
+    pub fn below(&mut self, n : usize) -> usize{
+        (self.next_u64() % n as u64) as usize
+    }
+
+    pub fn pick<'a, T>(&mut self, items : &'a [T]) -> &'a T{
+        &items[self.below(items.len())]
+    }
+}
+
+/// Low `n` bytes of `v` in host byte order
+fn int_bytes(v : u128, n : usize) -> Vec<u8>{
+    let mut ret = v.to_le_bytes()[..n].to_vec();
+    if cfg!(target_endian = "big"){
+        ret.reverse();
+    }
+    ret
+}
+
+fn boundary(dtype : PacketDataType, rng : &mut Rng) -> (Vec<u8>, String){
+    if dtype.is_varint(){
+        if rng.below(8) == 0{
+            // Longer than any 64 bit value can need
+            return (vec![0x80; 10].into_iter().chain([0x01]).collect(), "overlong varint".to_string());
+        }
+        let v = *rng.pick(&[0, 1, 0x7f, 0x80, 0x3fff, 0x4000, u32::MAX as u64, i64::MAX as u64, u64::MAX]);
+        return (varint::encode_uleb128(v), format!("{v}"));
+    }
+    let n = dtype.data_size();
+    let bits = 8 * n as u32;
+    let ones = u128::MAX >> (128 - bits);
+    let smax = ones >> 1;
+    let v = *rng.pick(&[0, 1, ones, ones - 1, smax, smax + 1, smax - 1]);
+    (int_bytes(v, n), format!("0x{v:x}"))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Mutation{
+    Boundary,
+    BitFlip,
+    OversizedLength,
+    WrongSizeHeader,
+    Truncate,
+    Inflate
+}
+
+/// Builds the bytes for one fuzz case and a short description of what was done
+/// to them, Err when the template itself doesnt encode
+pub fn mutate(view : &PacketView, vars : &Variables, seed : u64, case : u64) -> Result<(Vec<u8>, String), String>{
+    let mut rng = Rng::new(seed, case);
+    let (mut buf, spans) = view.encode_spans(vars)?;
+    let ints : Vec<usize> = view.fields.iter().filter(|f| f.datatype.is_some_and(|d| d.is_integer())).map(|f| f.index).collect();
+    let headers : Vec<usize> = view.fields.iter().filter_map(|f| match f.sizing_method{
+        Some(SizingMethod::SizeHeader(x)) if matches!(f.datatype, Some(PacketDataType::Bytes(_))) => Some(x),
+        _ => None
+    }).collect();
+    let payloads : Vec<usize> = view.fields.iter().filter(|f| matches!(f.datatype, Some(PacketDataType::Bytes(_)))).map(|f| f.index).collect();
+
+    let mut choices = vec![Mutation::BitFlip, Mutation::Truncate, Mutation::Inflate];
+    if !ints.is_empty(){
+        choices.extend([Mutation::Boundary, Mutation::Boundary]);
+    }
+    if !headers.is_empty(){
+        choices.extend([Mutation::OversizedLength, Mutation::WrongSizeHeader]);
+    }
+    let desc = match *rng.pick(&choices){
+        Mutation::Boundary => {
+            let i = *rng.pick(&ints);
+            let (dat, val) = boundary(view[i].datatype.unwrap(), &mut rng);
+            buf.splice(spans[i].clone(), dat);
+            format!("field {i} set to {val}")
+        },
+        Mutation::OversizedLength => {
+            let i = *rng.pick(&headers);
+            let dtype = view[i].datatype.unwrap();
+            let dat = if dtype.is_varint() { varint::encode_uleb128(u64::MAX >> 1) } else { int_bytes(u128::MAX, dtype.data_size()) };
+            buf.splice(spans[i].clone(), dat);
+            format!("size header {i} set to its maximum")
+        },
+        Mutation::WrongSizeHeader => {
+            let i = *rng.pick(&headers);
+            let dtype = view[i].datatype.unwrap();
+            let cur = view[i].data_string.parse::<i128>().unwrap_or_default();
+            let delta = rng.below(16) as i128 + 1;
+            let val = if rng.below(2) == 0 { cur + delta } else { cur - delta };
+            let dat = if dtype.is_varint() { varint::encode_uleb128(val as u64) } else { int_bytes(val as u128, dtype.data_size()) };
+            buf.splice(spans[i].clone(), dat);
+            format!("size header {i} off by {}", val - cur)
+        },
+        Mutation::BitFlip if !buf.is_empty() => {
+            let flips = rng.below(8) + 1;
+            let mut at = Vec::new();
+            for _ in 0..flips{
+                let bit = rng.below(buf.len() * 8);
+                buf[bit / 8] ^= 1 << (bit % 8);
+                at.push(bit.to_string());
+            }
+            format!("flipped bits {}", at.join(", "))
+        },
+        Mutation::Truncate if !buf.is_empty() => {
+            let n = rng.below(buf.len());
+            buf.truncate(n);
+            format!("truncated to {n} bytes")
+        },
+        _ => {
+            // Grow a payload past what its header or the peer expects
+            let at = if payloads.is_empty() { buf.len() } else { spans[*rng.pick(&payloads)].end };
+            let n = rng.below(4096) + 1;
+            let junk : Vec<u8> = (0..n).map(|_| rng.next_u64() as u8).collect();
+            buf.splice(at..at, junk);
+            format!("inserted {n} bytes at {at}")
+        }
+    };
+    Ok((buf, desc))
+}
+
+#[derive(Debug, Clone)]
+pub struct Finding{
+    pub case : u64,
+    pub mutation : String,
+    pub data : Vec<u8>,
+    pub outcome : String
+}
+
+/// What one case did to the peer
+#[derive(Debug, Clone)]
+pub struct Probe{
+    pub(crate) conn : usize,
+    /// The connection to carry on with, empty once the case took it down
+    pub(crate) sock : Handoff,
+    /// Dialed for this case because the last one took the connection down
+    pub(crate) reconnected : bool,
+    pub(crate) sent : bool,
+    pub(crate) reply : Option<Vec<u8>>,
+    /// Set when the case is a finding
+    pub(crate) outcome : Option<String>,
+    /// The peer cant be reached any more, nothing after this case can run
+    pub(crate) fatal : bool
+}
+
+/// Sends one case and reads for the reply, dialing first when `sock` is
+/// None. Blocks for up to `timeout`
+pub fn probe(conn : usize, sock : Option<Connection>, dial : impl FnOnce() -> io::Result<Connection>, data : &[u8], timeout : Duration, expect_reply : bool) -> Probe{
+    let mut ret = Probe { conn, sock: Handoff::default(), reconnected: sock.is_none(), sent: false, reply: None, outcome: None, fatal: false };
+    let mut s = match sock.map_or_else(dial, Ok){
+        Ok(s) => s,
+        Err(e) => {
+            // Most likely the last case took the peer down for good
+            ret.outcome = Some(format!("Couldnt reconnect: {e}"));
+            ret.fatal = true;
+            return ret;
+        }
+    };
+    let keep = match s.write_all(data){
+        Err(e) => {
+            ret.outcome = Some(format!("Send failed: {e}"));
+            false
+        },
+        Ok(_) => {
+            ret.sent = true;
+            let mut buf = [0; 4096];
+            match s.set_read_timeout(Some(timeout)).and_then(|_| s.read(&mut buf)){
+                Ok(0) => {
+                    ret.outcome = Some("Peer closed the connection".to_string());
+                    false
+                },
+                Ok(n) => {
+                    ret.reply = Some(buf[..n].to_vec());
+                    true
+                },
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    ret.outcome = expect_reply.then(|| "No reply before the timeout".to_string());
+                    true
+                },
+                Err(e) => {
+                    ret.outcome = Some(e.to_string());
+                    false
+                }
+            }
+        }
+    };
+    // Anything but a quiet peer starts the next case on a fresh connection
+    if keep{
+        ret.sock = Handoff::new(s);
+    }
+    else{
+        let _ = s.shutdown();
+    }
+    ret
+}
+
+#[derive(Debug, Clone)]
+pub enum FuzzMessage{
+    Packet(usize),
+    SeedEntry(String),
+    CasesEntry(String),
+    TimeoutEntry(String),
+    ExpectReply(bool),
+    ClearFindings
+}
+
+pub struct Fuzzer{
+    pub(crate) packet : Option<usize>,
+    pub(crate) seed_string : String,
+    pub(crate) cases_string : String,
+    pub(crate) timeout_string : String,
+    /// Count a case without any answer as a finding
+    pub(crate) expect_reply : bool,
+    /// Case that runs on the next FuzzCase message
+    pub(crate) running : Option<u64>,
+    pub(crate) run_id : usize,
+    pub(crate) findings : Vec<Finding>
+}
+
+impl Default for Fuzzer{
+    fn default() -> Self {
+        Self {
+            packet: None,
+            seed_string: "1".to_string(),
+            cases_string: "1000".to_string(),
+            timeout_string: "200".to_string(),
+            expect_reply: false,
+            running: None,
+            run_id: 0,
+            findings: Default::default()
+        }
+    }
+}
+
+impl Fuzzer{
+    pub fn seed(&self) -> u64{
+        self.seed_string.parse().unwrap_or_default()
+    }
+
+    pub fn cases(&self) -> u64{
+        self.cases_string.parse().unwrap_or_default()
+    }
+
+    pub fn timeout(&self) -> Duration{
+        Duration::from_millis(self.timeout_string.parse::<u64>().unwrap_or_default().max(1))
+    }
+
+    pub fn update(&mut self, msg : FuzzMessage){
+        let numeric = |s : &String| s.is_empty() || s.parse::<u64>().is_ok();
+        match msg{
+            FuzzMessage::Packet(x) => self.packet = Some(x),
+            FuzzMessage::SeedEntry(x) => if numeric(&x) { self.seed_string = x },
+            FuzzMessage::CasesEntry(x) => if numeric(&x) { self.cases_string = x },
+            FuzzMessage::TimeoutEntry(x) => if numeric(&x) { self.timeout_string = x },
+            FuzzMessage::ExpectReply(x) => self.expect_reply = x,
+            FuzzMessage::ClearFindings => self.findings.clear(),
+        }
+    }
+
+    pub fn packet_removed(&mut self, idx : usize){
+        self.packet = match self.packet{
+            Some(p) if p == idx => None,
+            Some(p) if p > idx => Some(p - 1),
+            x => x
+        };
+    }
+
+    pub fn draw<'a>(&'a self, packets : &[PacketView]) -> Element<'a, Message>{
+        let choices : Vec<PacketChoice> = packets.iter().map(|p| PacketChoice(p.index, p.lable.clone())).collect();
+        let selected = self.packet.and_then(|p| choices.iter().find(|c| c.0 == p).cloned());
+        let run = match self.running{
+            Some(_) => button("Stop").on_press(Message::StopFuzz),
+            None => button("Fuzz").on_press(Message::StartFuzz),
+        };
+        let progress = match self.running{
+            Some(case) => text!("case {case} of {}, {} findings", self.cases(), self.findings.len()),
+            None => text!("{} findings", self.findings.len()),
+        };
+        let mut col = Column::new();
+        col = col.push(
+            row![
+                text("Fuzzing"),
+                pick_list(choices, selected, |x : PacketChoice| Message::FuzzMessage(FuzzMessage::Packet(x.0))).placeholder("Packet"),
+                text_input("Seed", &self.seed_string).on_input(|x| Message::FuzzMessage(FuzzMessage::SeedEntry(x))).width(Length::Fixed(150.0)),
+                text_input("Cases", &self.cases_string).on_input(|x| Message::FuzzMessage(FuzzMessage::CasesEntry(x))).width(Length::Fixed(100.0)),
+                text_input("Timeout ms", &self.timeout_string).on_input(|x| Message::FuzzMessage(FuzzMessage::TimeoutEntry(x))).width(Length::Fixed(100.0)),
+                toggler(self.expect_reply).label("Expect reply").on_toggle(|x| Message::FuzzMessage(FuzzMessage::ExpectReply(x))),
+                run,
+                button("Clear").on_press(Message::FuzzMessage(FuzzMessage::ClearFindings)),
+                progress
+            ].spacing(5)
+        );
+        let mut entries = Column::new();
+        for (i, f) in self.findings.iter().enumerate(){
+            let preview : Vec<String> = f.data.iter().take(16).map(|b| format!("{b:02x}")).collect();
+            entries = entries.push(
+                row![
+                    text!("case {}: {} -> {} [{} bytes: {}]", f.case, f.mutation, f.outcome, f.data.len(), preview.join(" ")).width(Fill),
+                    button("Replay").on_press(Message::ReplayFinding(i))
+                ].spacing(5)
+            );
+        }
+        col = col.push(scrollable(entries).height(Length::Fixed(120.0)));
+        col.spacing(5).into()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::packet::PacketField;
+
+    fn view() -> PacketView{
+        let mut fields = vec![
+            PacketField::typed(0, String::new(), PacketDataType::U32, None),
+            PacketField::typed(1, String::new(), PacketDataType::Bytes(SizingMethod::SizeHeader(0)), Some(SizingMethod::SizeHeader(0))),
+        ];
+        fields[0].data_string = "4".to_string();
+        // Bytes fields send a file's contents
+        let path = std::env::temp_dir().join(format!("packetmancer-fuzz-{}", std::process::id()));
+        std::fs::write(&path, b"abcd").unwrap();
+        fields[1].data_string = path.to_string_lossy().to_string();
+        PacketView::with_fields(0, "t".to_string(), fields)
+    }
+
+    #[test]
+    fn mutate_is_deterministic(){
+        let (view, vars) = (view(), Variables::new());
+        let run = |seed| (0..50).map(|case| mutate(&view, &vars, seed, case).unwrap()).collect::<Vec<_>>();
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+    }
+
+    #[test]
+    fn probe_hands_the_connection_back(){
+        let data = [1, 2, 3];
+        let probe = probe(0, Some(crate::websocket::tests::connect()), || unreachable!(), &data, Duration::from_secs(5), true);
+        assert_eq!(probe.reply.as_deref(), Some(&data[..]));
+        assert!(probe.sent && probe.outcome.is_none() && !probe.reconnected);
+        assert!(probe.sock.take().is_some());
+    }
+
+    #[test]
+    fn unreachable_peer_ends_the_run(){
+        let probe = probe(0, None, || Err(io::ErrorKind::ConnectionRefused.into()), &[1], Duration::from_millis(10), false);
+        assert!(probe.fatal && !probe.sent);
+        assert!(probe.outcome.unwrap().starts_with("Couldnt reconnect"));
+        assert!(probe.sock.take().is_none());
+    }
+}
@@ -0,0 +1,171 @@
+use std::cell::Cell;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::fuzz::Rng;
+use crate::packet::PacketDataType;
+
+// Values computed fresh every time a field is encoded. The parameters live in
+// the field's generator string:
+//   Counter      start,step[,wrap]   wraps back to start past `wrap` or the type's max
+//   Random       min..max            empty means the type's whole range
+//   RandomBytes  n                   Bytes fields only
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GenKind{
+    Fixed,
+    Counter,
+    Random,
+    RandomBytes,
+    UnixSecs,
+    UnixMillis,
+    UnixMicros
+}
+
+impl GenKind{
+    pub const INTEGER : [GenKind; 6] = [GenKind::Fixed, GenKind::Counter, GenKind::Random, GenKind::UnixSecs, GenKind::UnixMillis, GenKind::UnixMicros];
+    pub const BYTES : [GenKind; 2] = [GenKind::Fixed, GenKind::RandomBytes];
+
+    pub fn placeholder(&self) -> Option<&'static str>{
+        match self{
+            GenKind::Counter => Some("start,step[,wrap]"),
+            GenKind::Random => Some("min..max"),
+            GenKind::RandomBytes => Some("Byte count"),
+            _ => None
+        }
+    }
+
+    pub fn from_name(name : &str) -> Option<Self>{
+        [GenKind::Fixed, GenKind::Counter, GenKind::Random, GenKind::RandomBytes, GenKind::UnixSecs, GenKind::UnixMillis, GenKind::UnixMicros]
+            .into_iter().find(|g| format!("{g:?}") == name)
+    }
+}
+
+impl Display for GenKind{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self{
+            GenKind::Fixed => write!(f, "Fixed value"),
+            GenKind::Counter => write!(f, "Counter"),
+            GenKind::Random => write!(f, "Random"),
+            GenKind::RandomBytes => write!(f, "Random bytes"),
+            GenKind::UnixSecs => write!(f, "Unix time s"),
+            GenKind::UnixMillis => write!(f, "Unix time ms"),
+            GenKind::UnixMicros => write!(f, "Unix time µs"),
+        }
+    }
+}
+
+pub enum Generated{
+    Value(String),
+    Bytes(Vec<u8>)
+}
+
+fn rng() -> Rng{
+    // Two calls within the same clock tick still get different streams
+    static CALLS : AtomicU64 = AtomicU64::new(0);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+    Rng::new(now, CALLS.fetch_add(1, Ordering::Relaxed))
+}
+
+fn int(s : &str) -> Result<i128, String>{
+    s.trim().parse::<i128>().map_err(|_| format!("{s} is not an integer"))
+}
+
+/// `state` carries a counter's next value between calls
+pub fn generate(kind : GenKind, params : &str, dtype : PacketDataType, state : &Cell<Option<i128>>) -> Result<Generated, String>{
+    let (min, max) = dtype.int_range().unwrap_or((i128::MIN, i128::MAX));
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let val = match kind{
+        GenKind::Fixed => return Err("Not a generator".to_string()),
+        GenKind::Counter => {
+            let parts : Vec<&str> = params.split(',').collect();
+            let start = parts.first().filter(|x| !x.trim().is_empty()).map(|x| int(x)).transpose()?.unwrap_or(0).clamp(min, max);
+            let step = parts.get(1).map(|x| int(x)).transpose()?.unwrap_or(1);
+            let wrap = parts.get(2).map(|x| int(x)).transpose()?.unwrap_or(max).min(max);
+            let val = state.get().unwrap_or(start);
+            let next = val + step;
+            state.set(Some(if next > wrap || next < min { start } else { next }));
+            val
+        },
+        GenKind::Random => {
+            let (lo, hi) = match params.split_once(".."){
+                Some((lo, hi)) => (
+                    if lo.trim().is_empty() { min } else { int(lo)? },
+                    if hi.trim().is_empty() { max } else { int(hi.strip_prefix('=').unwrap_or(hi))? }
+                ),
+                None if params.trim().is_empty() => (min, max),
+                None => return Err("Random needs a min..max range".to_string())
+            };
+            // Anything outside the type would not encode
+            let (lo, hi) = (lo.max(min), hi.min(max));
+            if lo > hi{
+                return Err(format!("{lo} is above {hi}"));
+            }
+            let span = (hi - lo) as u128 + 1;
+            let mut r = rng();
+            let wide = ((r.next_u64() as u128) << 64) | r.next_u64() as u128;
+            lo + if span == 0 { wide } else { wide % span } as i128
+        },
+        GenKind::RandomBytes => {
+            let n : usize = params.trim().parse().map_err(|_| "Random bytes needs a byte count".to_string())?;
+            let mut r = rng();
+            return Ok(Generated::Bytes((0..n).map(|_| r.next_u64() as u8).collect()));
+        },
+        GenKind::UnixSecs => now.as_secs() as i128,
+        GenKind::UnixMillis => now.as_millis() as i128,
+        GenKind::UnixMicros => now.as_micros() as i128,
+    };
+    // Timestamps keep their low bits when the field is too narrow for them
+    let val = if val > max { min + (val - min) % (max - min + 1) } else { val };
+    Ok(Generated::Value(val.to_string()))
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn values(kind : GenKind, params : &str, dtype : PacketDataType, n : usize) -> Vec<i128>{
+        let state = Cell::new(None);
+        (0..n).map(|_| match generate(kind, params, dtype, &state).unwrap(){
+            Generated::Value(x) => x.parse().unwrap(),
+            Generated::Bytes(_) => panic!("expected a value")
+        }).collect()
+    }
+
+    #[test]
+    fn counters_step_and_wrap(){
+        assert_eq!(values(GenKind::Counter, "", PacketDataType::U8, 3), [0, 1, 2]);
+        assert_eq!(values(GenKind::Counter, "0,1,2", PacketDataType::U16, 4), [0, 1, 2, 0]);
+        // past the type's max counts as a wrap too
+        assert_eq!(values(GenKind::Counter, "250,3", PacketDataType::U8, 3), [250, 253, 250]);
+        assert_eq!(values(GenKind::Counter, "5,-2", PacketDataType::U8, 4), [5, 3, 1, 5]);
+        assert!(generate(GenKind::Counter, "a,1", PacketDataType::U8, &Cell::new(None)).is_err());
+    }
+
+    #[test]
+    fn random_stays_in_range(){
+        assert!(values(GenKind::Random, "10..=12", PacketDataType::U32, 200).iter().all(|x| (10..=12).contains(x)));
+        assert!(values(GenKind::Random, "-3..", PacketDataType::I8, 200).iter().all(|x| (-3..=127).contains(x)));
+        // clamped to the type
+        assert!(values(GenKind::Random, "..1000", PacketDataType::U8, 200).iter().all(|x| (0..=255).contains(x)));
+        assert!(values(GenKind::Random, "", PacketDataType::U64, 200).iter().all(|x| (0..=u64::MAX as i128).contains(x)));
+        assert!(generate(GenKind::Random, "5..1", PacketDataType::U8, &Cell::new(None)).is_err());
+        assert!(generate(GenKind::Random, "7", PacketDataType::U8, &Cell::new(None)).is_err());
+    }
+
+    #[test]
+    fn random_bytes_and_timestamps(){
+        match generate(GenKind::RandomBytes, "16", PacketDataType::Bytes(crate::packet::SizingMethod::UntilClose), &Cell::new(None)).unwrap(){
+            Generated::Bytes(x) => assert_eq!(x.len(), 16),
+            Generated::Value(_) => panic!("expected bytes")
+        }
+        assert!(generate(GenKind::RandomBytes, "", PacketDataType::U8, &Cell::new(None)).is_err());
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i128;
+        assert!((values(GenKind::UnixSecs, "", PacketDataType::U64, 1)[0] - now).abs() <= 1);
+        assert!(values(GenKind::UnixMillis, "", PacketDataType::U64, 1)[0] >= now * 1000);
+        // too narrow fields keep the low bits
+        assert!(values(GenKind::UnixMicros, "", PacketDataType::U16, 20).iter().all(|x| (0..=65535).contains(x)));
+        assert!(generate(GenKind::Fixed, "", PacketDataType::U8, &Cell::new(None)).is_err());
+    }
+}
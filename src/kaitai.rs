@@ -0,0 +1,344 @@
+use std::fmt::Write;
+
+use yaml_rust2::{Yaml, YamlLoader};
+
+use crate::codegen::{field_idents, ident};
+use crate::packet::{unescape_bytes, PacketDataType, PacketField, PacketView, SizingMethod};
+
+// Kaitai Struct import and export. The top level seq and every entry under
+// `types` become their own PacketView, user types used inside a seq are inlined
+// with their field names prefixed. Packets are always built in host byte order,
+// so a ksy whose multi byte integers are in the other order is refused on import
+// rather than silently swapped. An enum field keeps the enum's value names and
+// starts on its first value, export writes them back out under `enums`.
+
+const VLQ_IMPORT : &str = "/common/vlq_base128_le";
+const HOST_ENDIAN : &str = if cfg!(target_endian = "little") { "le" } else { "be" };
+
+struct Importer<'a>{
+    types : &'a Yaml,
+    enums : &'a Yaml,
+}
+
+pub fn import_ksy(src : &str, first_index : usize) -> Result<Vec<PacketView>, String>{
+    let docs = YamlLoader::load_from_str(src).map_err(|e| e.to_string())?;
+    let doc = docs.first().ok_or("Empty ksy file")?;
+    let imp = Importer { types: &doc["types"], enums: &doc["enums"] };
+    let order = endian(&doc["meta"], None)?;
+    let mut ret = Vec::new();
+    if !doc["seq"].is_badvalue(){
+        let name = doc["meta"]["id"].as_str().unwrap_or_default().to_string();
+        let mut fields = Vec::new();
+        imp.seq(&doc["seq"], "", &mut fields, order, 0)?;
+        ret.push(PacketView::with_fields(first_index, name, fields));
+    }
+    if let Some(types) = doc["types"].as_hash(){
+        for (name, spec) in types{
+            let mut fields = Vec::new();
+            imp.seq(&spec["seq"], "", &mut fields, endian(&spec["meta"], order)?, 0)?;
+            ret.push(PacketView::with_fields(first_index + ret.len(), name.as_str().unwrap_or_default().to_string(), fields));
+        }
+    }
+    Ok(ret)
+}
+
+fn int_type(name : &str) -> Option<PacketDataType>{
+    if name == "vlq_base128_le"{
+        return Some(PacketDataType::ULeb128);
+    }
+    let name = name.strip_suffix("le").or(name.strip_suffix("be")).unwrap_or(name);
+    Some(match name{
+        "u1" => PacketDataType::U8,
+        "u2" => PacketDataType::U16,
+        "u4" => PacketDataType::U32,
+        "u8" => PacketDataType::U64,
+        "s1" => PacketDataType::I8,
+        "s2" => PacketDataType::I16,
+        "s4" => PacketDataType::I32,
+        "s8" => PacketDataType::I64,
+        _ => return None
+    })
+}
+
+/// Byte order set by a `meta` section, falling back to the enclosing one
+fn endian<'a>(meta : &'a Yaml, outer : Option<&'a str>) -> Result<Option<&'a str>, String>{
+    match &meta["endian"]{
+        Yaml::BadValue => Ok(outer),
+        Yaml::String(x) if x == "le" || x == "be" => Ok(Some(x.as_str())),
+        _ => Err("Only a fixed meta/endian of le or be can be imported".to_string())
+    }
+}
+
+/// Matches the `(n - _io.pos % n) % n` expression export writes for Align fields
+fn align_expr(expr : &str) -> Option<usize>{
+    let expr : String = expr.chars().filter(|c| !c.is_whitespace()).collect();
+    let n = expr.strip_prefix('(')?.split('-').next()?;
+    (expr == format!("({n}-_io.pos%{n})%{n}")).then(|| n.parse().ok()).flatten()
+}
+
+fn push(fields : &mut Vec<PacketField>, name : String, dtype : PacketDataType, meth : Option<SizingMethod>) -> usize{
+    fields.push(PacketField::typed(fields.len(), name, dtype, meth));
+    fields.len() - 1
+}
+
+impl Importer<'_>{
+    /// Value names of a top level enum, a value maps to its name or to a map with an `id`
+    fn enum_names(&self, id : &str) -> Option<Vec<(i64, String)>>{
+        self.enums[id].as_hash()?.iter().map(|(k, v)| Some((k.as_i64()?, v.as_str().or(v["id"].as_str())?.to_string()))).collect()
+    }
+
+    fn seq(&self, seq : &Yaml, prefix : &str, fields : &mut Vec<PacketField>, order : Option<&str>, depth : usize) -> Result<(), String>{
+        if depth > 16{
+            return Err("User types nest too deep".to_string());
+        }
+        for entry in seq.as_vec().map(|x| x.as_slice()).unwrap_or_default(){
+            let id = entry["id"].as_str().ok_or("seq entry without an id")?;
+            let count = match (entry["repeat"].as_str(), &entry["repeat-expr"]){
+                (None, _) => 1,
+                (Some("expr"), Yaml::Integer(n)) => usize::try_from(*n).map_err(|_| format!("{id}: repeat-expr {n} is negative"))?,
+                (Some(x), _) => return Err(format!("{id}: repeat: {x} needs a constant repeat-expr"))
+            };
+            for i in 0..count{
+                let name = match (prefix, count){
+                    ("", 1) => id.to_string(),
+                    ("", _) => format!("{id}[{i}]"),
+                    (_, 1) => format!("{prefix}.{id}"),
+                    (_, _) => format!("{prefix}.{id}[{i}]"),
+                };
+                self.entry(entry, name, fields, order, depth)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn entry(&self, entry : &Yaml, name : String, fields : &mut Vec<PacketField>, order : Option<&str>, depth : usize) -> Result<(), String>{
+        if let Some(contents) = entry["contents"].as_vec(){
+            for (i, b) in contents.iter().enumerate(){
+                let b = b.as_i64().ok_or(format!("{name}: contents must be bytes"))?;
+                let idx = push(fields, format!("{name}[{i}]"), PacketDataType::U8, None);
+                fields[idx].data_string = b.to_string();
+            }
+            return Ok(());
+        }
+        if let Some(contents) = entry["contents"].as_str(){
+            for (i, b) in contents.bytes().enumerate(){
+                let idx = push(fields, format!("{name}[{i}]"), PacketDataType::U8, None);
+                fields[idx].data_string = b.to_string();
+            }
+            return Ok(());
+        }
+
+        let ty = entry["type"].as_str();
+        if let Some(dtype) = ty.and_then(int_type){
+            let order = ty.and_then(|t| ["le", "be"].into_iter().find(|x| t.ends_with(x))).or(order);
+            if let Some(order) = order.filter(|o| *o != HOST_ENDIAN && !dtype.is_varint() && dtype.data_size() > 1){
+                return Err(format!("{name}: {} is {order} but packets are built in host byte order ({HOST_ENDIAN})", ty.unwrap()));
+            }
+            let names = entry["enum"].as_str().map(|e| self.enum_names(e).map(|x| (e.to_string(), x)).ok_or(format!("{name}: unknown enum {e}"))).transpose()?;
+            let idx = push(fields, name, dtype, None);
+            // Start enum fields on their first value so the packet is sendable as is
+            let first = names.as_ref().and_then(|(_, x)| x.first()).map(|(v, _)| *v);
+            fields[idx].data_string = first.unwrap_or_default().to_string();
+            fields[idx].enum_names = names;
+            return Ok(());
+        }
+        if ty == Some("strz"){
+            push(fields, name, PacketDataType::CStr, None);
+            return Ok(());
+        }
+        if let Some(user) = ty.filter(|t| *t != "str"){
+            let spec = &self.types[user];
+            if spec.is_badvalue(){
+                return Err(format!("{name}: unknown type {user}"));
+            }
+            return self.seq(&spec["seq"], &name, fields, endian(&spec["meta"], order)?, depth + 1);
+        }
+
+        // Everything else is raw bytes (or a sized str)
+        let meth = if let Some(n) = entry["size"].as_i64(){
+            SizingMethod::FixedSize(usize::try_from(n).map_err(|_| format!("{name}: size {n} is negative"))?)
+        }
+        else if let Some(expr) = entry["size"].as_str(){
+            if let Some(n) = align_expr(expr){
+                let idx = push(fields, Default::default(), PacketDataType::Align, Some(SizingMethod::FixedSize(n)));
+                fields[idx].data_string = "0".to_string();
+                return Ok(());
+            }
+            let header = expr.trim().trim_end_matches(".value");
+            // Inside an inlined user type the header carries the same prefix as this field
+            let scoped = name.rsplit_once('.').map(|(p, _)| format!("{p}.{header}")).unwrap_or_default();
+            let header = fields.iter().position(|f| (f.name == header || f.name == scoped) && f.datatype.is_some_and(|d| d.is_integer()))
+                .ok_or(format!("{name}: size expression \"{expr}\" has to name an earlier integer field"))?;
+            SizingMethod::SizeHeader(header)
+        }
+        else if entry["size-eos"].as_bool() == Some(true){
+            SizingMethod::UntilClose
+        }
+        else if let Some(t) = entry["terminator"].as_i64(){
+            let idx = push(fields, name, PacketDataType::Bytes(SizingMethod::Delimiter), Some(SizingMethod::Delimiter));
+            fields[idx].sizing_meth_str = format!("\\x{t:02x}");
+            return Ok(());
+        }
+        else{
+            return Err(format!("{name}: no type or size"));
+        };
+        push(fields, name, PacketDataType::Bytes(meth), Some(meth));
+        Ok(())
+    }
+}
+
+fn ksy_int(dtype : PacketDataType) -> Option<&'static str>{
+    Some(match dtype{
+        PacketDataType::U8 => "u1",
+        PacketDataType::U16 => "u2",
+        PacketDataType::U32 => "u4",
+        PacketDataType::U64 => "u8",
+        PacketDataType::I8 => "s1",
+        PacketDataType::I16 => "s2",
+        PacketDataType::I32 => "s4",
+        PacketDataType::I64 => "s8",
+        _ => return None
+    })
+}
+
+/// Fails for what Kaitai can't describe, like delimiters longer than a byte
+pub fn export_ksy(view : &PacketView) -> Result<String, String>{
+    let fields : Vec<&PacketField> = view.fields.iter().filter(|f| f.datatype.is_some()).collect();
+    let ids = field_idents(view);
+    let mut seq = String::new();
+    let mut enums : Vec<(String, &Vec<(i64, String)>)> = Vec::new();
+    for f in &fields{
+        let dtype = f.datatype.unwrap();
+        let id = &ids[f.index];
+        writeln!(seq, "  - id: {id}").unwrap();
+        match (dtype, f.sizing_method){
+            (PacketDataType::Padding, Some(SizingMethod::FixedSize(n))) => {
+                writeln!(seq, "    size: {n}\n    contents: {:?}", vec![f.fill_byte(); n]).unwrap();
+            },
+            (PacketDataType::Align, Some(SizingMethod::FixedSize(n))) => {
+                writeln!(seq, "    size: ({n} - _io.pos % {n}) % {n}").unwrap();
+            },
+            (PacketDataType::Padding | PacketDataType::Align, _) => {
+                writeln!(seq, "    size: 0").unwrap();
+            },
+            (PacketDataType::Script, _) => {
+                writeln!(seq, "    size: {}\n    doc: computed by a script", view.script_len(f)).unwrap();
+            },
+            (PacketDataType::CStr, _) => {
+                writeln!(seq, "    type: strz\n    encoding: UTF-8").unwrap();
+            },
+            (PacketDataType::Bytes(_), meth) => match meth{
+                Some(SizingMethod::FixedSize(n)) => writeln!(seq, "    size: {n}").unwrap(),
+                Some(SizingMethod::SizeHeader(x)) => {
+                    let suffix = if view[x].datatype.is_some_and(|d| d.is_varint()) { ".value" } else { "" };
+                    writeln!(seq, "    size: {}{suffix}", ids[x]).unwrap();
+                },
+                Some(SizingMethod::Delimiter) => match unescape_bytes(&f.sizing_meth_str).unwrap_or_default().as_slice(){
+                    [t] => writeln!(seq, "    terminator: {t}\n    consume: true").unwrap(),
+                    _ => return Err(format!("{id}: Kaitai terminators are a single byte, \"{}\" can't be exported", f.sizing_meth_str))
+                },
+                Some(SizingMethod::UntilClose) | None => writeln!(seq, "    size-eos: true").unwrap(),
+            },
+            (PacketDataType::ULeb128, _) => writeln!(seq, "    type: vlq_base128_le").unwrap(),
+            (PacketDataType::SLeb128, _) => writeln!(seq, "    type: vlq_base128_le\n    doc: signed LEB128, read value_signed").unwrap(),
+            (PacketDataType::ZigZag, _) => writeln!(seq, "    type: vlq_base128_le\n    doc: zigzag encoded signed value").unwrap(),
+            _ => {
+                writeln!(seq, "    type: {}", ksy_int(dtype).unwrap()).unwrap();
+                if let Some((eid, names)) = &f.enum_names{
+                    // Another table under the same id gets a name of its own
+                    let eid = (1..).map(|n| if n == 1 { eid.clone() } else { format!("{eid}_{n}") })
+                        .find(|x| enums.iter().all(|(e, v)| e != x || *v == names)).unwrap();
+                    if enums.iter().all(|(e, _)| *e != eid){
+                        enums.push((eid.clone(), names));
+                    }
+                    writeln!(seq, "    enum: {eid}").unwrap();
+                }
+            },
+        }
+    }
+
+    let mut ret = String::new();
+    writeln!(ret, "meta:\n  id: {}", ident(&view.lable, format!("packet{}", view.index))).unwrap();
+    writeln!(ret, "  endian: {HOST_ENDIAN}").unwrap();
+    if fields.iter().any(|f| f.datatype.is_some_and(|d| d.is_varint())){
+        writeln!(ret, "  imports:\n    - {VLQ_IMPORT}").unwrap();
+    }
+    writeln!(ret, "seq:").unwrap();
+    ret.push_str(&seq);
+    if !enums.is_empty(){
+        writeln!(ret, "enums:").unwrap();
+        for (eid, names) in enums{
+            writeln!(ret, "  {eid}:").unwrap();
+            for (v, name) in names{
+                writeln!(ret, "    {v}: {name}").unwrap();
+            }
+        }
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const OTHER_ENDIAN : &str = if cfg!(target_endian = "little") { "be" } else { "le" };
+
+    #[test]
+    fn foreign_byte_order_is_refused(){
+        let ksy = |meta : &str, ty : &str| format!("meta:\n  id: m\n{meta}seq:\n  - id: a\n    type: {ty}\n");
+        assert!(import_ksy(&ksy(&format!("  endian: {HOST_ENDIAN}\n"), "u4"), 0).is_ok());
+        assert!(import_ksy(&ksy(&format!("  endian: {OTHER_ENDIAN}\n"), "u1"), 0).is_ok());
+        assert!(import_ksy(&ksy("", &format!("u2{HOST_ENDIAN}")), 0).is_ok());
+        assert!(import_ksy(&ksy(&format!("  endian: {OTHER_ENDIAN}\n"), "u4"), 0).err().unwrap().contains("byte order"));
+        assert!(import_ksy(&ksy(&format!("  endian: {HOST_ENDIAN}\n"), &format!("s8{OTHER_ENDIAN}")), 0).is_err());
+        assert!(import_ksy(&ksy("  endian:\n    switch-on: x\n", "u4"), 0).err().unwrap().contains("meta/endian"));
+    }
+
+    #[test]
+    fn user_types_inherit_and_override_byte_order(){
+        let ksy = format!("meta:\n  id: m\n  endian: {HOST_ENDIAN}\nseq:\n  - id: h\n    type: hdr\ntypes:\n  hdr:\n    meta:\n      endian: {OTHER_ENDIAN}\n    seq:\n      - id: len\n        type: u2\n");
+        assert!(import_ksy(&ksy, 0).is_err());
+        let ksy = format!("meta:\n  id: m\n  endian: {HOST_ENDIAN}\nseq:\n  - id: h\n    type: hdr\ntypes:\n  hdr:\n    seq:\n      - id: len\n        type: u2\n");
+        let views = import_ksy(&ksy, 0).unwrap();
+        assert_eq!(views[0].fields[0].name, "h.len");
+    }
+
+    #[test]
+    fn enums_start_on_their_first_value(){
+        let ksy = "meta:\n  id: m\nseq:\n  - id: kind\n    type: u1\n    enum: kind\nenums:\n  kind:\n    7: ping\n    9: pong\n";
+        assert_eq!(import_ksy(ksy, 0).unwrap()[0].fields[0].data_string, "7");
+    }
+
+    #[test]
+    fn enums_survive_import_export_and_saving(){
+        let ksy = "meta:\n  id: m\nseq:\n  - id: kind\n    type: u1\n    enum: kind\n  - id: back\n    type: u1\n    enum: kind\nenums:\n  kind:\n    7: ping\n    9:\n      id: pong\n";
+        let view = import_ksy(ksy, 0).unwrap().remove(0);
+        let names = Some(("kind".to_string(), vec![(7, "ping".to_string()), (9, "pong".to_string())]));
+        assert_eq!(view.fields[0].enum_names, names);
+        let out = export_ksy(&view).unwrap();
+        assert!(out.contains("    enum: kind\n") && out.ends_with("enums:\n  kind:\n    7: ping\n    9: pong\n"), "{out}");
+        let again = import_ksy(&out, 0).unwrap().remove(0);
+        assert_eq!(again.fields[1].enum_names, names);
+        let saved = PacketField::from(jzon::JsonValue::from(view.fields[0].clone()));
+        assert_eq!(saved.enum_names, names);
+        assert!(import_ksy("seq:\n  - id: kind\n    type: u1\n    enum: nope\n", 0).err().unwrap().contains("unknown enum"));
+    }
+
+    #[test]
+    fn negative_sizes_are_refused(){
+        let err = import_ksy("seq:\n  - id: a\n    size: -1\n", 0).err().unwrap();
+        assert!(err.contains("a: size -1"), "{err}");
+        let err = import_ksy("seq:\n  - id: b\n    type: u1\n    repeat: expr\n    repeat-expr: -3\n", 0).err().unwrap();
+        assert!(err.contains("b: repeat-expr -3"), "{err}");
+    }
+
+    #[test]
+    fn only_single_byte_delimiters_export(){
+        let mut f = PacketField::typed(0, "line".to_string(), PacketDataType::Bytes(SizingMethod::Delimiter), Some(SizingMethod::Delimiter));
+        f.sizing_meth_str = "\\n".to_string();
+        let mut view = PacketView::with_fields(0, "m".to_string(), vec![f]);
+        assert!(export_ksy(&view).unwrap().contains("    terminator: 10\n    consume: true\n"));
+        view.fields[0].sizing_meth_str = "\\r\\n".to_string();
+        assert!(export_ksy(&view).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+use iced::{widget::{button, pick_list, row, text, text_input, toggler, Column}, Element, Length};
+
+use crate::packet::PacketView;
+use crate::scenario::PacketChoice;
+use crate::state::Message;
+
+// Repeated sending for load tests. Packets go out in ticks: every tick sends
+// whatever the configured rate says is due by now, then sleeps until the next
+// packet is due so the UI keeps redrawing the stats in between. Replies are
+// waited for in short reads across ticks, not in one read as long as the
+// reply timeout, and a packet the socket only partly takes is finished on
+// later ticks. Sequence numbers are the packet's own Counter generators,
+// which step on every send.
+
+/// Longest a tick keeps sending before handing the UI its turn
+pub const TICK_BUDGET : Duration = Duration::from_millis(10);
+
+/// Longest a single read for a reply blocks
+pub const REPLY_POLL : Duration = Duration::from_millis(5);
+
+/// Longest a single write blocks before the rest of the packet waits its turn
+pub const WRITE_POLL : Duration = Duration::from_millis(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateUnit{
+    Packets,
+    Bytes
+}
+
+impl RateUnit{
+    const ALL : [RateUnit; 2] = [RateUnit::Packets, RateUnit::Bytes];
+}
+
+impl Display for RateUnit{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self{
+            RateUnit::Packets => write!(f, "packets/s"),
+            RateUnit::Bytes => write!(f, "bytes/s"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum LoadMessage{
+    Packet(usize),
+    CountEntry(String),
+    RateEntry(String),
+    Unit(RateUnit),
+    WaitReply(bool),
+    TimeoutEntry(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct LoadStats{
+    pub start : Instant,
+    /// Set once the run stops so the rates stop decaying
+    pub end : Option<Instant>,
+    pub sent : u64,
+    pub bytes : u64,
+    pub replies : u64,
+    pub timeouts : u64,
+    pub rtt_total : Duration,
+    pub rtt_min : Option<Duration>,
+    pub rtt_max : Duration,
+    pub error : Option<String>
+}
+
+impl LoadStats{
+    pub fn new() -> Self{
+        Self { start: Instant::now(), end: None, sent: 0, bytes: 0, replies: 0, timeouts: 0, rtt_total: Duration::ZERO, rtt_min: None, rtt_max: Duration::ZERO, error: None }
+    }
+
+    pub fn elapsed(&self) -> Duration{
+        self.end.unwrap_or_else(Instant::now) - self.start
+    }
+
+    pub fn add_rtt(&mut self, rtt : Duration){
+        self.replies += 1;
+        self.rtt_total += rtt;
+        self.rtt_min = Some(self.rtt_min.map_or(rtt, |x| x.min(rtt)));
+        self.rtt_max = self.rtt_max.max(rtt);
+    }
+}
+
+pub struct LoadTest{
+    pub(crate) packet : Option<usize>,
+    /// 0 keeps sending until stopped
+    pub(crate) count_string : String,
+    /// Empty or 0 sends as fast as the socket takes it
+    pub(crate) rate_string : String,
+    pub(crate) unit : RateUnit,
+    pub(crate) wait_reply : bool,
+    pub(crate) timeout_string : String,
+    pub(crate) running : bool,
+    pub(crate) run_id : usize,
+    pub(crate) stats : Option<LoadStats>,
+    /// When the packet whose reply is still awaited went out
+    pub(crate) awaiting : Option<Instant>,
+    /// What the socket hasn't taken yet of the packet going out
+    pub(crate) unsent : Vec<u8>
+}
+
+impl Default for LoadTest{
+    fn default() -> Self {
+        Self {
+            packet: None,
+            count_string: "100".to_string(),
+            rate_string: Default::default(),
+            unit: RateUnit::Packets,
+            wait_reply: false,
+            timeout_string: "1000".to_string(),
+            running: false,
+            run_id: 0,
+            stats: None,
+            awaiting: None,
+            unsent: Vec::new()
+        }
+    }
+}
+
+impl LoadTest{
+    pub fn count(&self) -> u64{
+        self.count_string.parse().unwrap_or_default()
+    }
+
+    pub fn rate(&self) -> Option<f64>{
+        self.rate_string.parse::<f64>().ok().filter(|x| *x > 0.0)
+    }
+
+    pub fn timeout(&self) -> Duration{
+        Duration::from_millis(self.timeout_string.parse::<u64>().unwrap_or_default().max(1))
+    }
+
+    /// None when the next packet may go out now, otherwise how long until it may
+    pub fn next_due(&self, stats : &LoadStats) -> Option<Duration>{
+        let rate = self.rate()?;
+        let done = match self.unit{
+            RateUnit::Packets => stats.sent,
+            RateUnit::Bytes => stats.bytes,
+        };
+        // A rate this low never gets to the next packet
+        let Ok(due) = Duration::try_from_secs_f64(done as f64 / rate) else { return Some(Duration::MAX) };
+        due.checked_sub(stats.start.elapsed())
+    }
+
+    pub fn update(&mut self, msg : LoadMessage){
+        let numeric = |s : &String| s.is_empty() || s.parse::<u64>().is_ok();
+        match msg{
+            LoadMessage::Packet(x) => self.packet = Some(x),
+            LoadMessage::CountEntry(x) => if numeric(&x) { self.count_string = x },
+            LoadMessage::RateEntry(x) => if x.is_empty() || x.parse::<f64>().is_ok() { self.rate_string = x },
+            LoadMessage::Unit(x) => self.unit = x,
+            LoadMessage::WaitReply(x) => self.wait_reply = x,
+            LoadMessage::TimeoutEntry(x) => if numeric(&x) { self.timeout_string = x },
+        }
+    }
+
+    pub fn packet_removed(&mut self, idx : usize){
+        self.packet = match self.packet{
+            Some(p) if p == idx => None,
+            Some(p) if p > idx => Some(p - 1),
+            x => x
+        };
+    }
+
+    fn draw_stats(&self) -> Element<'_, Message>{
+        let Some(stats) = &self.stats else { return text("").into() };
+        let secs = stats.elapsed().as_secs_f64().max(1e-3);
+        let mut line = format!(
+            "{} packets, {} bytes in {secs:.1}s: {:.1} packets/s, {:.0} bytes/s",
+            stats.sent, stats.bytes, stats.sent as f64 / secs, stats.bytes as f64 / secs
+        );
+        if self.wait_reply{
+            let avg = if stats.replies > 0 { stats.rtt_total / stats.replies as u32 } else { Duration::ZERO };
+            line.push_str(&format!(
+                ", latency min {:.2?} avg {avg:.2?} max {:.2?}, {} timeouts",
+                stats.rtt_min.unwrap_or_default(), stats.rtt_max, stats.timeouts
+            ));
+        }
+        if let Some(e) = &stats.error{
+            line.push_str(&format!(", stopped: {e}"));
+        }
+        text(line).into()
+    }
+
+    pub fn draw<'a>(&'a self, packets : &[PacketView]) -> Element<'a, Message>{
+        let choices : Vec<PacketChoice> = packets.iter().map(|p| PacketChoice(p.index, p.lable.clone())).collect();
+        let selected = self.packet.and_then(|p| choices.iter().find(|c| c.0 == p).cloned());
+        let run = if self.running{
+            button("Stop").on_press(Message::StopLoad)
+        }
+        else{
+            button("Start").on_press(Message::StartLoad)
+        };
+        let mut r = row![
+            text("Repeat send"),
+            pick_list(choices, selected, |x : PacketChoice| Message::LoadMessage(LoadMessage::Packet(x.0))).placeholder("Packet"),
+            text_input("Count, 0 = until stopped", &self.count_string).on_input(|x| Message::LoadMessage(LoadMessage::CountEntry(x))).width(Length::Fixed(120.0)),
+            text_input("Rate, empty = max", &self.rate_string).on_input(|x| Message::LoadMessage(LoadMessage::RateEntry(x))).width(Length::Fixed(120.0)),
+            pick_list(RateUnit::ALL, Some(self.unit), |x| Message::LoadMessage(LoadMessage::Unit(x))),
+            toggler(self.wait_reply).label("Wait for reply").on_toggle(|x| Message::LoadMessage(LoadMessage::WaitReply(x))),
+        ].spacing(5);
+        if self.wait_reply{
+            r = r.push(text_input("Timeout ms", &self.timeout_string).on_input(|x| Message::LoadMessage(LoadMessage::TimeoutEntry(x))).width(Length::Fixed(100.0)));
+        }
+        r = r.push(run);
+        let mut col = Column::new();
+        col = col.push(r);
+        col = col.push(self.draw_stats());
+        col.spacing(5).into()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn tiny_rates_are_never_due(){
+        let mut load = LoadTest::default();
+        load.update(LoadMessage::RateEntry("1e-30".to_string()));
+        let mut stats = LoadStats::new();
+        assert_eq!(load.next_due(&stats), None);
+        stats.sent = 1;
+        assert_eq!(load.next_due(&stats), Some(Duration::MAX));
+    }
+}
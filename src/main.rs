@@ -4,12 +4,36 @@ use iced::Element;
 
 mod packet;
 mod state;
-
-fn update(state : &mut state::State, msg : state::Message){
-    state.update(msg);
+mod varint;
+mod cimport;
+mod codegen;
+mod dissector;
+mod kaitai;
+mod pcap;
+mod session;
+mod scenario;
+mod variables;
+mod assertions;
+mod scripting;
+mod fuzz;
+mod load;
+mod generators;
+mod transport;
+mod tls;
+mod serial;
+mod websocket;
+mod proxy;
+mod passive;
+mod framing;
+mod timeouts;
+mod conn;
+mod mock;
+
+fn update(state : &mut state::State, msg : state::Message) -> iced::Task<state::Message>{
+    state.update(msg)
 }
 
-fn view(state : &crate::state::State) -> Element<state::Message>{
+fn view(state : &crate::state::State) -> Element<'_, state::Message>{
     state.draw()
 }
 
@@ -0,0 +1,458 @@
+use std::fmt::Display;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use iced::{widget::{button, pick_list, row, text, text_input, Column}, Element, Length};
+
+use crate::packet::PacketView;
+use crate::passive::{self, Fit};
+use crate::proxy::MatchChoice;
+use crate::scenario::PacketChoice;
+use crate::session::{Direction, SessionEntry};
+use crate::state::Message;
+use crate::variables::Variables;
+
+// Stand-in peer for working on templates without the real device. Serves one
+// client at a time on a local port from a thread of its own, so a recieve on
+// the UI thread can wait for its answer. The thread works from a copy of the
+// templates, variables and rules taken at start and reports back through a
+// channel the UI drains on ticks. Its own traffic stays out of the session
+// log, the connection talking to it already logs both directions.
+
+/// How often the serving thread looks for a stop or a new client
+const POLL : Duration = Duration::from_millis(20);
+
+/// Longest a reply may take to go out before the client is given up on
+const WRITE_TIMEOUT : Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MockMode{
+    /// Sends back whatever arrives
+    #[default]
+    Echo,
+    /// Answers each request with the packet of the first rule whose template decodes it
+    Reply,
+    /// Plays the server's side of a recorded session
+    Replay
+}
+
+impl MockMode{
+    const ALL : [MockMode; 3] = [MockMode::Echo, MockMode::Reply, MockMode::Replay];
+}
+
+impl Display for MockMode{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self{
+            MockMode::Echo => write!(f, "Echo"),
+            MockMode::Reply => write!(f, "Reply by template"),
+            MockMode::Replay => write!(f, "Replay session"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplyRule{
+    /// Template the request has to decode as, None for any request
+    pub(crate) request : Option<usize>,
+    /// Packet sent back, None to swallow the request
+    pub(crate) reply : Option<usize>
+}
+
+#[derive(Debug, Clone)]
+pub enum MockMessage{
+    ListenEntry(String),
+    Mode(MockMode),
+    AddRule,
+    RuleRequest(usize, MatchChoice),
+    RuleReply(usize, usize),
+    RemoveRule(usize)
+}
+
+#[derive(Default)]
+pub struct MockServer{
+    /// A port, or address:port to listen on one interface only
+    pub(crate) listen_string : String,
+    pub(crate) mode : MockMode,
+    pub(crate) rules : Vec<ReplyRule>,
+    /// Session replayed in Replay mode. Sent entries are what the client is
+    /// expected to send, Recieved ones are answered with
+    pub(crate) script : Vec<SessionEntry>,
+    pub(crate) running : bool,
+    pub(crate) run_id : usize,
+    pub(crate) status : String,
+    /// Where it listens while running
+    pub(crate) local : Option<SocketAddr>,
+    stop : Arc<AtomicBool>,
+    updates : Option<Receiver<String>>,
+    thread : Option<JoinHandle<()>>
+}
+
+/// The serving thread's side
+struct Worker{
+    mode : MockMode,
+    rules : Vec<ReplyRule>,
+    script : Vec<SessionEntry>,
+    views : Vec<PacketView>,
+    vars : Variables,
+    client : Option<TcpStream>,
+    buf : Vec<u8>,
+    /// Next script entry in Replay mode
+    replay_pos : usize,
+    /// Messages from the current client that didnt match the recording
+    differed : usize,
+    status : Sender<String>
+}
+
+impl MockServer{
+    pub fn start(&mut self, run_id : usize, views : &[PacketView], vars : &Variables) -> io::Result<()>{
+        self.stop();
+        let addr = if self.listen_string.parse::<u16>().is_ok() { format!("127.0.0.1:{}", self.listen_string) } else { self.listen_string.clone() };
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let local = listener.local_addr()?;
+        let (tx, rx) = mpsc::channel();
+        let worker = Worker {
+            mode: self.mode,
+            rules: self.rules.clone(),
+            script: self.script.clone(),
+            views: views.to_vec(),
+            vars: vars.clone(),
+            client: None,
+            buf: Vec::new(),
+            replay_pos: 0,
+            differed: 0,
+            status: tx
+        };
+        self.stop = Arc::new(AtomicBool::new(false));
+        let stop = self.stop.clone();
+        self.thread = Some(std::thread::spawn(move || worker.run(listener, &stop)));
+        self.status = format!("Listening on {local}");
+        self.local = Some(local);
+        self.updates = Some(rx);
+        self.running = true;
+        self.run_id = run_id;
+        Ok(())
+    }
+
+    pub fn stop(&mut self){
+        self.running = false;
+        self.local = None;
+        self.updates = None;
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(t) = self.thread.take(){
+            // Gone within a poll, and the port is free again after
+            let _ = t.join();
+        }
+    }
+
+    /// Picks up what the serving thread reported since the last tick. False
+    /// once it has stopped on its own
+    pub fn poll(&mut self) -> bool{
+        if let Some(rx) = &self.updates{
+            while let Ok(status) = rx.try_recv(){
+                self.status = status;
+            }
+        }
+        if self.thread.as_ref().is_some_and(|t| t.is_finished()){
+            self.running = false;
+            self.thread = None;
+            self.local = None;
+        }
+        self.running
+    }
+
+    /// The recording to replay, taken from the session log
+    pub fn record(&mut self, session : &[SessionEntry]){
+        self.script = session.to_vec();
+    }
+
+    pub fn packet_removed(&mut self, idx : usize){
+        let shift = |p : &mut Option<usize>| *p = match *p{
+            Some(x) if x == idx => None,
+            Some(x) if x > idx => Some(x - 1),
+            x => x
+        };
+        for r in &mut self.rules{
+            shift(&mut r.request);
+            shift(&mut r.reply);
+        }
+    }
+
+    pub fn update(&mut self, msg : MockMessage){
+        match msg{
+            MockMessage::ListenEntry(x) => self.listen_string = x,
+            MockMessage::Mode(x) => self.mode = x,
+            MockMessage::AddRule => self.rules.push(ReplyRule { request: None, reply: None }),
+            MockMessage::RuleRequest(i, x) => self.rules[i].request = x.0,
+            MockMessage::RuleReply(i, x) => self.rules[i].reply = Some(x),
+            MockMessage::RemoveRule(i) => { self.rules.remove(i); },
+        }
+    }
+
+    pub fn draw<'a>(&'a self, packets : &[PacketView]) -> Element<'a, Message>{
+        let mut requests = vec![MatchChoice(None, String::new())];
+        requests.extend(packets.iter().map(|p| MatchChoice(Some(p.index), p.lable.clone())));
+        let replies : Vec<PacketChoice> = packets.iter().map(|p| PacketChoice(p.index, p.lable.clone())).collect();
+        let run = if self.running{
+            button("Stop").on_press(Message::StopMock)
+        }
+        else{
+            button("Start").on_press(Message::StartMock)
+        };
+        let mut r = row![
+            text("Mock server"),
+            text_input("Listen port", &self.listen_string).on_input(|x| Message::MockMessage(MockMessage::ListenEntry(x))).width(Length::Fixed(150.0)),
+            pick_list(MockMode::ALL, Some(self.mode), |x| Message::MockMessage(MockMessage::Mode(x))),
+            run
+        ].spacing(5);
+        match self.mode{
+            MockMode::Echo => (),
+            MockMode::Reply => r = r.push(button("Add reply rule").on_press(Message::MockMessage(MockMessage::AddRule))),
+            MockMode::Replay => {
+                r = r.push(button("Use session log").on_press(Message::RecordMock));
+                r = r.push(text!("{} messages recorded", self.script.len()));
+            }
+        }
+        if self.running{
+            r = r.push(text("Changes apply on the next start"));
+        }
+        r = r.push(text(&self.status));
+        let mut col = Column::new();
+        col = col.push(r);
+        if self.mode == MockMode::Reply{
+            for (i, rule) in self.rules.iter().enumerate(){
+                let request = requests.iter().find(|c| c.0 == rule.request).cloned();
+                let reply = rule.reply.and_then(|p| replies.iter().find(|c| c.0 == p).cloned());
+                col = col.push(
+                    row![
+                        text("When"),
+                        pick_list(requests.clone(), request, move |x| Message::MockMessage(MockMessage::RuleRequest(i, x))),
+                        text("arrives, reply with"),
+                        pick_list(replies.clone(), reply, move |x : PacketChoice| Message::MockMessage(MockMessage::RuleReply(i, x.0))).placeholder("Nothing"),
+                        button("Remove").on_press(Message::MockMessage(MockMessage::RemoveRule(i)))
+                    ].spacing(5)
+                );
+            }
+        }
+        col.spacing(5).into()
+    }
+}
+
+impl Worker{
+    fn run(mut self, listener : TcpListener, stop : &AtomicBool){
+        while !stop.load(Ordering::Relaxed){
+            let res = if self.client.is_some() { self.serve() } else { self.accept(&listener) };
+            if let Err(e) = res{
+                self.report(format!("Stopped: {e}"));
+                return;
+            }
+        }
+    }
+
+    fn report(&self, status : String){
+        // Nobody is listening once the server was stopped
+        let _ = self.status.send(status);
+    }
+
+    fn close(&mut self){
+        self.client = None;
+        self.buf.clear();
+        self.replay_pos = 0;
+        self.differed = 0;
+    }
+
+    fn accept(&mut self, listener : &TcpListener) -> io::Result<()>{
+        let (client, peer) = match listener.accept(){
+            Ok(x) => x,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                std::thread::sleep(POLL);
+                return Ok(());
+            },
+            Err(e) => return Err(e)
+        };
+        client.set_nonblocking(false)?;
+        // Reads come back every poll so a stop is noticed
+        client.set_read_timeout(Some(POLL))?;
+        client.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        self.report(format!("Serving {peer}"));
+        self.client = Some(client);
+        if self.mode == MockMode::Replay{
+            // The server may speak first
+            self.answer(Self::replay);
+        }
+        Ok(())
+    }
+
+    /// Serves whatever the client sent since the last read
+    fn serve(&mut self) -> io::Result<()>{
+        let Some(client) = &mut self.client else { return Ok(()) };
+        let mut dat = vec![0; 65536];
+        match client.read(&mut dat){
+            Ok(0) => {
+                self.report("Client closed the connection, waiting for the next one".to_string());
+                self.close();
+                return Ok(());
+            },
+            Ok(n) => self.buf.extend_from_slice(&dat[..n]),
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => return Ok(()),
+            Err(e) => {
+                self.report(format!("Client connection failed: {e}, waiting for the next one"));
+                self.close();
+                return Ok(());
+            }
+        }
+        match self.mode{
+            MockMode::Echo => self.answer(|w| {
+                let dat = std::mem::take(&mut w.buf);
+                w.send(&dat)
+            }),
+            MockMode::Reply => self.answer(Self::reply),
+            MockMode::Replay => self.answer(Self::replay)
+        }
+        Ok(())
+    }
+
+    /// Drops the client when answering it fails
+    fn answer(&mut self, f : impl FnOnce(&mut Self) -> io::Result<()>){
+        if let Err(e) = f(self){
+            self.report(format!("Client connection failed: {e}, waiting for the next one"));
+            self.close();
+        }
+    }
+
+    fn send(&mut self, dat : &[u8]) -> io::Result<()>{
+        match &mut self.client{
+            Some(c) => c.write_all(dat),
+            None => Ok(())
+        }
+    }
+
+    /// Answers every complete request at the front of the buffer
+    fn reply(&mut self) -> io::Result<()>{
+        while !self.buf.is_empty(){
+            let mut waiting = false;
+            let mut matched = None;
+            for r in &self.rules{
+                let len = match r.request.and_then(|p| self.views.get(p)){
+                    // Without a template whatever arrived together is one request
+                    None => self.buf.len(),
+                    Some(v) => match passive::fit(v, &self.buf, &self.vars){
+                        Fit::Yes(len, _) if len > 0 => len,
+                        Fit::NeedMore => {
+                            waiting = true;
+                            continue;
+                        },
+                        _ => continue
+                    }
+                };
+                matched = Some((len, r.request, r.reply));
+                break;
+            }
+            let Some((len, request, reply)) = matched else {
+                if !waiting{
+                    self.report(format!("No rule matches {} bytes, dropped them", self.buf.len()));
+                    self.buf.clear();
+                }
+                return Ok(());
+            };
+            self.buf.drain(..len);
+            let request = request.map_or("a request".to_string(), |p| format!("#{p}"));
+            match reply.and_then(|p| self.views.get(p)).map(|v| (v.index, v.lable.clone(), v.to_bytes(&self.vars))){
+                Some((idx, lable, Ok(dat))) => {
+                    self.send(&dat)?;
+                    self.report(format!("Answered {request} with #{idx} {lable}"));
+                },
+                Some((idx, _, Err(e))) => self.report(format!("Couldnt answer {request} with #{idx}: {e}")),
+                None => self.report(format!("Swallowed {request}"))
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the recorded replies up to the next message the client has to send,
+    /// taking that one off the buffer once enough has arrived
+    fn replay(&mut self) -> io::Result<()>{
+        while let Some(e) = self.script.get(self.replay_pos){
+            match e.direction{
+                Direction::Recieved => {
+                    let dat = e.data.clone();
+                    self.send(&dat)?;
+                },
+                Direction::Sent => {
+                    if self.buf.len() < e.data.len(){
+                        return Ok(());
+                    }
+                    let got : Vec<u8> = self.buf.drain(..e.data.len()).collect();
+                    if got != e.data{
+                        self.differed += 1;
+                    }
+                }
+            }
+            self.replay_pos += 1;
+        }
+        if !self.script.is_empty(){
+            self.report(format!("Replayed all {} messages, {} sent by the client differed from the recording", self.script.len(), self.differed));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::conn::Conn;
+    use crate::packet::{PacketDataType, PacketField};
+
+    /// A connection to the running mock
+    fn connect(mock : &MockServer) -> Conn{
+        let local = mock.local.unwrap();
+        let mut conn = Conn::new("mock".to_string());
+        conn.current_ip = local.ip().to_string();
+        conn.current_port = local.port().to_string();
+        conn.connect().unwrap();
+        conn
+    }
+
+    fn recieve(conn : &mut Conn, view : &PacketView) -> Vec<u8>{
+        let s = conn.sock.as_mut().unwrap();
+        conn.framing.next_frame(s, view, &Variables::new()).unwrap()
+    }
+
+    #[test]
+    fn echo(){
+        let mut mock = MockServer { listen_string: "0".to_string(), ..Default::default() };
+        mock.start(1, &[], &Variables::new()).unwrap();
+        let mut conn = connect(&mock);
+        assert!(conn.write(&[1, 2, 3]).unwrap());
+        let view = PacketView::with_fields(0, "t".to_string(), vec![PacketField::typed(0, String::new(), PacketDataType::U16, None), PacketField::typed(1, String::new(), PacketDataType::U8, None)]);
+        assert_eq!(recieve(&mut conn, &view), [1, 2, 3]);
+        mock.stop();
+    }
+
+    #[test]
+    fn reply_by_template(){
+        let mut answer = PacketField::typed(0, String::new(), PacketDataType::U16, None);
+        answer.data_string = "${seq}".to_string();
+        let views = [PacketView::with_fields(0, "answer".to_string(), vec![answer])];
+        let vars = Variables::from([("seq".to_string(), "258".to_string())]);
+        let mut mock = MockServer { listen_string: "0".to_string(), mode: MockMode::Reply, ..Default::default() };
+        mock.rules.push(ReplyRule { request: None, reply: Some(0) });
+        mock.start(1, &views, &vars).unwrap();
+        let mut conn = connect(&mock);
+        assert!(conn.write(b"hello").unwrap());
+        assert_eq!(recieve(&mut conn, &views[0]), 258u16.to_ne_bytes());
+        // The status comes back from the serving thread
+        let start = std::time::Instant::now();
+        while mock.poll() && !mock.status.starts_with("Answered") && start.elapsed() < Duration::from_secs(5){
+            std::thread::sleep(POLL);
+        }
+        assert_eq!(mock.status, "Answered a request with #0 answer");
+        mock.stop();
+        assert!(!mock.poll());
+    }
+}
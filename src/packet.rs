@@ -1,21 +1,39 @@
 
-use std::{fmt::{Debug, Display}, fs::{metadata, File}, io::{Read, Write}, ops::{Index, IndexMut}};
+use std::{cell::Cell, fmt::{Debug, Display}, fs::{metadata, File}, io::{Read, Write}, ops::{Index, IndexMut, Range}};
 
 use iced::{
-    widget::{button, combo_box, container, row, text, text_input, toggler, Column, ComboBox, Row},
-    Element, Length::{self, Fill}
+    widget::{button, combo_box, container, pick_list, row, text, text_input, toggler, Column, ComboBox, Row},
+    Color, Element, Length::{self, Fill}
 };
 use iced::widget::combo_box::State as ComboState;
-use jzon::{object, JsonValue};
+use jzon::{array, object, JsonValue};
 use rfd::FileDialog;
 use crate::state::Message;
+use crate::{codegen, kaitai, scripting, varint};
+use crate::session::Recorder;
+use crate::variables::{substitute, Variables};
+use crate::assertions::{Expectation, FieldCheck};
+use crate::generators::{self, GenKind, Generated};
+use crate::conn::ConnChoice;
 
 #[derive(Clone)]
 pub struct PacketField{
     pub(crate)index : usize,
+    pub(crate)name : String,
     pub(crate)dtype_combo_state : ComboState<PacketDataType>,
     pub(crate)datatype : Option<PacketDataType>,
     pub(crate) data_string : String,
+    /// Variable the decoded value is stored in when the packet is recieved
+    pub(crate) capture : String,
+    /// Value, range or regex the recieved value is checked against
+    pub(crate) expect_string : String,
+    /// Computes the value on every send instead of using `data_string`
+    pub(crate) generator : GenKind,
+    pub(crate) gen_string : String,
+    /// Next value of a counter generator
+    pub(crate) gen_state : Cell<Option<i128>>,
+    /// Kaitai enum the value was imported with, (enum id, value names), written back out on export
+    pub(crate) enum_names : Option<(String, Vec<(i64, String)>)>,
     pub(crate)smethod_combo_state : ComboState<SizingMethod>,
     pub(crate)sizing_method : Option<SizingMethod>,
     pub(crate)sizing_meth_str : String
@@ -32,7 +50,13 @@ pub enum PacketDataType{
     I64,
     I32,
     I16,
-    I8
+    I8,
+    ULeb128,
+    SLeb128,
+    ZigZag,
+    Padding, //FixedSize(n) bytes of the fill value in data_string
+    Align,   //fill until the packet length is a multiple of FixedSize(n)
+    Script   //Rhai script in data_string computes the bytes
 }
 
 impl PacketDataType{
@@ -44,12 +68,44 @@ impl PacketDataType{
             PacketDataType::U32 | PacketDataType::I32 => 4,
             PacketDataType::U16 | PacketDataType::I16 => 2,
             PacketDataType::U8  | PacketDataType::I8  => 1,
+            PacketDataType::ULeb128 | PacketDataType::SLeb128 | PacketDataType::ZigZag => panic!("Varints are arbitrarily sized"),
+            PacketDataType::Padding | PacketDataType::Align => panic!("Use the sizing method"),
+            PacketDataType::Script => panic!("Scripts are arbitrarily sized"),
         }
     }
+    pub const fn is_varint(&self) -> bool{
+        matches!(self, PacketDataType::ULeb128 | PacketDataType::SLeb128 | PacketDataType::ZigZag)
+    }
+    /// Whether the field holds a plain integer and can be used as a `SizeHeader` source
+    pub const fn is_integer(&self) -> bool{
+        !matches!(self, PacketDataType::Bytes(_) | PacketDataType::CStr | PacketDataType::Padding | PacketDataType::Align | PacketDataType::Script)
+    }
+    /// Smallest and largest value of an integer type
+    pub fn int_range(&self) -> Option<(i128, i128)>{
+        Some(match self{
+            PacketDataType::U8 => (0, u8::MAX.into()),
+            PacketDataType::U16 => (0, u16::MAX.into()),
+            PacketDataType::U32 => (0, u32::MAX.into()),
+            PacketDataType::U64 | PacketDataType::ULeb128 => (0, u64::MAX.into()),
+            PacketDataType::I8 => (i8::MIN.into(), i8::MAX.into()),
+            PacketDataType::I16 => (i16::MIN.into(), i16::MAX.into()),
+            PacketDataType::I32 => (i32::MIN.into(), i32::MAX.into()),
+            PacketDataType::I64 | PacketDataType::SLeb128 | PacketDataType::ZigZag => (i64::MIN.into(), i64::MAX.into()),
+            _ => return None
+        })
+    }
+    pub const fn is_filler(&self) -> bool{
+        matches!(self, PacketDataType::Padding | PacketDataType::Align)
+    }
     pub fn bytes_to_val(&self, dat : Vec<u8>) -> Box<dyn ToString>{
         match self{
             PacketDataType::Bytes(_) => panic!("Not for this"),
             PacketDataType::CStr => panic!("Not for this"),
+            PacketDataType::Padding | PacketDataType::Align => panic!("Not for this"),
+            PacketDataType::Script => panic!("Not for this"),
+            PacketDataType::ULeb128 => Box::new(varint::decode_uleb128(&dat)),
+            PacketDataType::SLeb128 => Box::new(varint::decode_sleb128(&dat)),
+            PacketDataType::ZigZag  => Box::new(varint::zigzag_decode(varint::decode_uleb128(&dat))),
             PacketDataType::U64 => Box::new(u64::from_ne_bytes(dat.try_into().unwrap())),
             PacketDataType::U32 => Box::new(u32::from_ne_bytes(dat.try_into().unwrap())),
             PacketDataType::U16 => Box::new(u16::from_ne_bytes(dat.try_into().unwrap())),
@@ -84,16 +140,29 @@ pub enum PVMessage{
     SavePacket,
     ToggleRecieve(bool),
     ChangeSizingMethod(SizingMethod, usize),
-    MethodEntry(String, usize)
+    MethodEntry(String, usize),
+    NameEntry(String, usize),
+    CaptureEntry(String, usize),
+    ExpectEntry(String, usize),
+    Generator(GenKind, usize),
+    GenEntry(String, usize),
+    TargetEntry(String),
+    ExportC,
+    ExportRust,
+    ExportKaitai
 }
 
 
 #[derive(Default,Clone)]
 pub struct PacketView{
     pub(crate) index : usize,
-    lable : String,
-    recieve : bool,
-    pub(crate) fields : Vec<PacketField>
+    pub(crate) lable : String,
+    pub(crate) recieve : bool,
+    pub(crate) fields : Vec<PacketField>,
+    /// Name of the connection it is sent or recieved on, empty for the default one
+    pub(crate) target : String,
+    /// Results of the last check against the fields' expectations
+    pub(crate) checks : Vec<FieldCheck>
 }
 
 
@@ -101,14 +170,20 @@ pub struct PacketView{
 pub enum SizingMethod{
     SizeHeader(usize), //index of field
     FixedSize(usize),  //fixed size
+    Delimiter,         //terminated by the escaped byte sequence in sizing_meth_str
+    UntilClose,        //everything until the peer closes
 }
 
 impl SizingMethod{
     pub(self) fn update(&self, view : &mut PacketView, field : &PacketField) {
         match self{
-            SizingMethod::FixedSize(_) => (),
+            SizingMethod::FixedSize(_) | SizingMethod::Delimiter | SizingMethod::UntilClose => (),
             SizingMethod::SizeHeader(x) => {
-                if !view.recieve{
+                if !view.recieve && view[*x].datatype.is_some_and(|d| d.is_integer()){
+                    if field.generator == GenKind::RandomBytes{
+                        view[*x].data_string = field.gen_string.trim().parse::<usize>().unwrap_or_default().to_string();
+                        return;
+                    }
                     let f = metadata(field.data_string.clone());
                     if let Ok(met) = f{
                         //println!("Got to meta_data");
@@ -123,16 +198,20 @@ impl SizingMethod{
         }
     }
 }
-impl Into<JsonValue> for SizingMethod{
-    fn into(self) -> JsonValue {
-        match self {
+impl From<SizingMethod> for JsonValue{
+    fn from(value : SizingMethod) -> Self {
+        match value {
             SizingMethod::SizeHeader(x) | SizingMethod::FixedSize(x) => {
                 object! {
-                    method : self.to_string(),
+                    method : value.to_string(),
                     size   : x
                 }
             },
-            
+            SizingMethod::Delimiter | SizingMethod::UntilClose => {
+                object! {
+                    method : value.to_string()
+                }
+            }
         }
     }
 }
@@ -146,6 +225,8 @@ impl From<JsonValue> for SizingMethod{
             "FixedSize" =>{
                 Self::FixedSize(value["size"].as_usize().unwrap())
             }
+            "Delimiter" => Self::Delimiter,
+            "UntilClose" => Self::UntilClose,
             _ => panic!("Unknown Sizing Method")
         }
     }
@@ -155,18 +236,56 @@ impl Display for SizingMethod{
         match self {
             SizingMethod::SizeHeader(_) => write!(f, "SizeHeader"),
             SizingMethod::FixedSize(_) => write!(f, "FixedSize"),
+            SizingMethod::Delimiter => write!(f, "Delimiter"),
+            SizingMethod::UntilClose => write!(f, "UntilClose"),
         }
     }
 }
 
+/// Bytes needed to bring `offset` up to the next multiple of `align`
+pub const fn align_padding(offset : usize, align : usize) -> usize{
+    if align == 0{
+        0
+    }
+    else{
+        (align - offset % align) % align
+    }
+}
 
-impl Into<JsonValue> for PacketDataType {
-    fn into(self) -> JsonValue {
-        match self {
-            Self::Bytes(x) => object! {
-                size: x
+/// Turns a delimiter typed into the editor (`\r\n`, `\0\0`, `\xff`) into the bytes it stands for
+pub fn unescape_bytes(s : &str) -> Option<Vec<u8>>{
+    let mut ret = Vec::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next(){
+        if c != '\\'{
+            let mut buf = [0;4];
+            ret.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next()?{
+            'r' => ret.push(b'\r'),
+            'n' => ret.push(b'\n'),
+            't' => ret.push(b'\t'),
+            '0' => ret.push(0),
+            '\\' => ret.push(b'\\'),
+            'x' => {
+                let hex : String = chars.by_ref().take(2).collect();
+                ret.push(u8::from_str_radix(&hex, 16).ok()?);
+            }
+            _ => return None
+        }
+    }
+    Some(ret)
+}
+
+
+impl From<PacketDataType> for JsonValue{
+    fn from(value : PacketDataType) -> Self {
+        match value {
+            PacketDataType::Bytes(x) => object! {
+                sizing_method: x
             },
-            _ => JsonValue::String(format!("{}", self))            
+            _ => JsonValue::String(format!("{}", value))            
         }
     }
 }
@@ -183,6 +302,12 @@ impl From<JsonValue> for PacketDataType{
                 "I32" => Self::I32,
                 "I16" => Self::I16,
                 "I8" => Self::I8,
+                "ULeb128" => Self::ULeb128,
+                "SLeb128" => Self::SLeb128,
+                "ZigZag" => Self::ZigZag,
+                "Padding" => Self::Padding,
+                "Align" => Self::Align,
+                "Script" => Self::Script,
                 _ => panic!("Unexpected PacketDataType")
             }
         }
@@ -197,23 +322,34 @@ impl From<JsonValue> for PacketDataType{
     }
 }
 
-impl Into<JsonValue> for PacketView{
-    fn into(self) -> JsonValue {
+impl From<PacketView> for JsonValue{
+    fn from(value : PacketView) -> Self {
         object! {
-            index: self.index,
-            recieve: self.recieve,            
-            lable: self.lable,
-            fields: self.fields
+            index: value.index,
+            recieve: value.recieve,            
+            lable: value.lable,
+            fields: value.fields,
+            target: value.target
         }
     }
 }
-impl Into<JsonValue> for PacketField{
-    fn into(self) -> JsonValue {
+impl From<PacketField> for JsonValue{
+    fn from(value : PacketField) -> Self {
         object! {
-            index : self.index,
-            sizing_method : self.sizing_method,
-            datatype : self.datatype,
-            data_string : self.data_string
+            index : value.index,
+            name : value.name,
+            sizing_method : value.sizing_method,
+            datatype : value.datatype,
+            data_string : value.data_string,
+            sizing_string : value.sizing_meth_str,
+            capture : value.capture,
+            expect : value.expect_string,
+            generator : format!("{:?}", value.generator),
+            gen_params : value.gen_string,
+            enum : value.enum_names.map(|(id, names)| object! {
+                id : id,
+                values : names.into_iter().map(|(v, n)| array![v, n]).collect::<Vec<_>>()
+            })
         }
     }
 }
@@ -223,16 +359,26 @@ impl From<JsonValue> for PacketField{
         let idx = value["index"].as_usize().unwrap();
         let dattype : PacketDataType = value["datatype"].clone().into();
         let dat_str = value["data_string"].as_str().unwrap();
-        let meth : SizingMethod = value["sizing_method"].clone().into();
+        let meth : Option<SizingMethod> = if value["sizing_method"].is_null() { None } else { Some(value["sizing_method"].clone().into()) };
         Self { 
                 index: idx, 
+                name: value["name"].as_str().unwrap_or_default().to_string(),
                 dtype_combo_state: Self::create_dtype_combo(),
                 datatype: Some(dattype), 
                 data_string: dat_str.to_string(),
+                capture: value["capture"].as_str().unwrap_or_default().to_string(),
+                expect_string: value["expect"].as_str().unwrap_or_default().to_string(),
+                generator: value["generator"].as_str().and_then(GenKind::from_name).unwrap_or(GenKind::Fixed),
+                gen_string: value["gen_params"].as_str().unwrap_or_default().to_string(),
+                gen_state: Default::default(),
+                enum_names: value["enum"]["id"].as_str().map(|id| (id.to_string(), value["enum"]["values"].members()
+                    .filter_map(|x| Some((x[0].as_i64()?, x[1].as_str()?.to_string()))).collect())),
                 smethod_combo_state: Self::create_smeth_combo(),
-                sizing_method: Some(meth),
-                sizing_meth_str : match meth{
-                    SizingMethod::FixedSize(x) | SizingMethod::SizeHeader(x) => x.to_string()
+                sizing_method: meth,
+                sizing_meth_str : match (value["sizing_string"].as_str(), meth){
+                    (Some(s), _) => s.to_string(),
+                    (None, Some(SizingMethod::FixedSize(x) | SizingMethod::SizeHeader(x))) => x.to_string(),
+                    (None, _) => Default::default()
                 }
             }
     }
@@ -244,14 +390,18 @@ impl From<JsonValue> for PacketView{
         let lable = value["lable"].as_str().unwrap().to_string();
         let fields : Vec<PacketField> = value["fields"].as_array().unwrap().iter().map(|x| PacketField::from(x.clone())).collect();
         let recieve = value["recieve"].as_bool().unwrap();
-        Self { index, recieve, lable, fields}
+        let target = value["target"].as_str().unwrap_or_default().to_string();
+        Self { index, recieve, lable, fields, target, checks: Default::default() }
     }
 }
 
 
 impl PacketView{
     pub fn new(index :usize) -> Self{
-        Self { index, recieve: false, lable: Default::default(), fields: Default::default() }
+        Self { index, recieve: false, lable: Default::default(), fields: Default::default(), target: Default::default(), checks: Default::default() }
+    }
+    pub fn with_fields(index : usize, lable : String, fields : Vec<PacketField>) -> Self{
+        Self { index, recieve: false, lable, fields, target: Default::default(), checks: Default::default() }
     }
 
     pub fn get_field(&self, index : usize) -> PacketField{
@@ -270,19 +420,73 @@ impl PacketView{
             },
             PVMessage::DataType(x, i) =>{
                 self[i].datatype = Some(x);
-                
+                if !self[i].generator_kinds().contains(&self[i].generator){
+                    self[i].generator = GenKind::Fixed;
+                }
+                if x.is_filler() && !matches!(self[i].sizing_method, Some(SizingMethod::FixedSize(_))){
+                    self[i].sizing_method = Some(SizingMethod::FixedSize(0));
+                    self[i].sizing_meth_str = Default::default();
+                }
+
             },
             PVMessage::RemoveField(x) => {
                 self.fields.remove(x);
                 self.fields.iter_mut().enumerate().for_each(|(i,x)| x.index = i);
             }
-            PVMessage::OpenFile(x) => self[x].data_string = FileDialog::new().pick_file().unwrap_or_default().as_path().to_str().unwrap().to_string(),
+            PVMessage::OpenFile(x) => {
+                self[x].data_string = FileDialog::new().pick_file().unwrap_or_default().as_path().to_str().unwrap().to_string();
+                if let Some(meth) = self[x].sizing_method{
+                    meth.update(self, &self[x].clone());
+                }
+            },
             PVMessage::SavePacket => {
                 let fpath = FileDialog::new().add_filter("json", &["json"]).save_file().unwrap();
                 let mut f = File::create(fpath).unwrap();
-                f.write(jzon::stringify(self.clone()).as_bytes()).unwrap();
+                f.write_all(jzon::stringify(self.clone()).as_bytes()).unwrap();
+            },
+            PVMessage::ExportC => {
+                if let Some(fpath) = FileDialog::new().add_filter("C header", &["h"]).save_file(){
+                    let mut f = File::create(fpath).unwrap();
+                    f.write_all(codegen::c_header(self).as_bytes()).unwrap();
+                }
+            },
+            PVMessage::ExportRust => match codegen::rust_module(self){
+                Ok(src) => if let Some(fpath) = FileDialog::new().add_filter("rust", &["rs"]).save_file(){
+                    let mut f = File::create(fpath).unwrap();
+                    f.write_all(src.as_bytes()).unwrap();
+                },
+                Err(e) => println!("Couldnt export: {e}")
+            },
+            PVMessage::ExportKaitai => match kaitai::export_ksy(self){
+                Ok(src) => if let Some(fpath) = FileDialog::new().add_filter("Kaitai Struct", &["ksy"]).save_file(){
+                    let mut f = File::create(fpath).unwrap();
+                    f.write_all(src.as_bytes()).unwrap();
+                },
+                Err(e) => println!("Couldnt export: {e}")
             },
             PVMessage::ToggleRecieve(x) => self.recieve = x,
+            PVMessage::TargetEntry(x) => self.target = x,
+            PVMessage::NameEntry(s, x) => self[x].name = s,
+            PVMessage::ExpectEntry(s, x) => self[x].expect_string = s,
+            PVMessage::Generator(g, x) => {
+                self[x].generator = g;
+                self[x].gen_state.set(None);
+                if let Some(meth) = self[x].sizing_method{
+                    meth.update(self, &self[x].clone());
+                }
+            },
+            PVMessage::GenEntry(s, x) => {
+                self[x].gen_string = s;
+                self[x].gen_state.set(None);
+                if let Some(meth) = self[x].sizing_method{
+                    meth.update(self, &self[x].clone());
+                }
+            },
+            PVMessage::CaptureEntry(s, x) => {
+                if s.is_empty() || crate::variables::is_valid_name(&s){
+                    self[x].capture = s;
+                }
+            },
             PVMessage::ChangeSizingMethod(sizing_method, x) => {
                     match sizing_method{
                         SizingMethod::SizeHeader(_) =>{
@@ -298,22 +502,37 @@ impl PacketView{
                     }
                 },
                 PVMessage::MethodEntry(s, x) => {
-                    if let Some(SizingMethod::SizeHeader(_)) = &self[x].sizing_method{
-                        if x < self[x].index{
-                            self[x].sizing_meth_str = s;
-                            self[x].sizing_method.unwrap().update(self, &self[x].clone());
+                    if let Some(meth) = self[x].sizing_method{
+                        let n = s.parse::<usize>().unwrap_or_default();
+                        let valid = match meth{
+                            SizingMethod::Delimiter => unescape_bytes(&s).is_some(),
+                            _ => s.is_empty() || s.parse::<usize>().is_ok()
+                        };
+                        if !valid{
+                            return;
                         }
-                    } 
-                    else{
+                        let meth = match meth{
+                            SizingMethod::SizeHeader(_) => {
+                                // The header has to be decoded before the data it sizes
+                                if !(s.is_empty() || n < x && self[n].datatype.is_some_and(|d| d.is_integer())){
+                                    return;
+                                }
+                                SizingMethod::SizeHeader(n)
+                            },
+                            SizingMethod::FixedSize(_) => SizingMethod::FixedSize(n),
+                            SizingMethod::Delimiter | SizingMethod::UntilClose => meth,
+                        };
                         self[x].sizing_meth_str = s;
-                        self[x].sizing_method.unwrap().update(self, &self[x].clone());
+                        self[x].sizing_method = Some(meth);
+                        meth.update(self, &self[x].clone());
                     }
                 }
 
             //_ => ()
         }
     }
-    pub fn draw(&self) -> Element<'_, Message>{
+    /// `conns` are the connection picker's entries, the picker only shows once there is a choice
+    pub fn draw(&self, conns : &[ConnChoice]) -> Element<'_, Message>{
         let mut col = Column::new();
         col = col.push(
             text!("{}", self.lable)
@@ -322,6 +541,9 @@ impl PacketView{
             row![
                 button("Add field").on_press(Message::PVMessage(self.index, PVMessage::AddField)),
                 button("Save Packet").on_press(Message::PVMessage(self.index, PVMessage::SavePacket)),
+                button("Export C").on_press(Message::PVMessage(self.index, PVMessage::ExportC)),
+                button("Export Rust").on_press(Message::PVMessage(self.index, PVMessage::ExportRust)),
+                button("Export Kaitai").on_press(Message::PVMessage(self.index, PVMessage::ExportKaitai)),
                 toggler(self.recieve).on_toggle(|x| Message::PVMessage(self.index, PVMessage::ToggleRecieve(x)))
             ].spacing(5)
         );
@@ -329,24 +551,293 @@ impl PacketView{
             button("Remove Packet").on_press(Message::RemovePacket(self.index))
         );
         for c in &self.fields{
-            col = col.push(c.draw(self.index));
+            col = col.push(c.draw(self.index, self.recieve));
+        }
+        let action = if !self.recieve{
+            button("Send packet").on_press(Message::SendPacket(self.index))
+        }
+        else{
+            button("Recieve packet").on_press(Message::RecievePacket(self.index))
+        };
+        let mut r = row![action].spacing(5);
+        if conns.len() > 2{
+            let selected = conns.iter().find(|c| c.0 == self.target).cloned();
+            r = r.push(pick_list(conns.to_vec(), selected, |x : ConnChoice| Message::PVMessage(self.index, PVMessage::TargetEntry(x.0))));
+        }
+        col = col.push(r);
+        if self.recieve && !self.checks.is_empty(){
+            col = col.push(self.draw_checks());
         }
-        col = col.push(
-            if !self.recieve{
-                button("Send packet").on_press(Message::SendPacket(self.index))
-            }
-            else{
-                button("Recieve packet").on_press(Message::RecievePacket(self.index))
-            }
-        );
         col.spacing(10).into()
     }
     pub fn add_field(&mut self){
         self.fields.push(PacketField::new(self.fields.len()));
     }
 
-    pub fn to_bytes(&self) -> Vec<u8>{
-        self.fields.iter().map(|x|x.to_bytes()).flatten().collect()
+    /// Decodes one packet from `s` into the fields' data strings, Bytes fields are saved to files
+    pub fn read_from<R : Read>(&mut self, s : &mut R, vars : &Variables) -> std::io::Result<()>{
+        self.read_fields(s, vars, true).map(|_| ())
+    }
+
+    /// Like `read_from` but never asks where Bytes fields should be saved,
+    /// their contents are returned by field index instead
+    pub fn decode<R : Read>(&mut self, s : &mut R, vars : &Variables) -> std::io::Result<Vec<(usize, Vec<u8>)>>{
+        self.read_fields(s, vars, false)
+    }
+
+    /// Decodes a whole message against a copy of this template. None unless every
+    /// byte was consumed, otherwise a one line summary of the fields
+    pub fn describe(&self, data : &[u8], vars : &Variables) -> Option<String>{
+        let mut view = self.clone();
+        let mut cur = std::io::Cursor::new(data);
+        let payloads = view.decode(&mut cur, vars).ok()?;
+        if cur.position() as usize != data.len(){
+            return None;
+        }
+        let values : Vec<String> = view.fields.iter().filter(|f| f.datatype.is_some_and(|d| !d.is_filler() && !matches!(d, PacketDataType::Script))).map(|f| {
+            match payloads.iter().find(|(i, _)| *i == f.index){
+                Some((_, dat)) => format!("{}=<{} bytes>", f.key(), dat.len()),
+                None => match f.enum_name(){
+                    Some(name) => format!("{}={}({name})", f.key(), f.data_string),
+                    None => format!("{}={}", f.key(), f.data_string)
+                }
+            }
+        }).collect();
+        Some(format!("#{} {}: {}", self.index, self.lable, values.join(" ")))
+    }
+
+    /// A Bytes field that runs until the peer closes, the message has no end of its own
+    pub fn reads_until_close(&self) -> bool{
+        self.fields.iter().any(|f| matches!(f.datatype, Some(PacketDataType::Bytes(_))) && matches!(f.sizing_method, Some(SizingMethod::UntilClose)))
+    }
+
+    fn read_fields<R : Read>(&mut self, s : &mut R, vars : &Variables, interactive : bool) -> std::io::Result<Vec<(usize, Vec<u8>)>>{
+        self.checks.clear();
+        let mut payloads = Vec::new();
+        // Script fields are computed over everything read before them
+        let mut s = Recorder::new(s);
+        let s = &mut s;
+        // Bytes consumed so far, Align fields pad relative to it
+        let mut offset = 0usize;
+        for i in 0..self.fields.len(){
+            match self.fields[i].datatype.unwrap(){
+                PacketDataType::Bytes(_) => {
+                    let sizing_method = self.fields[i].sizing_method.unwrap_or(SizingMethod::FixedSize(0));
+                    let mut payload = Vec::new();
+                    let fdiag = if interactive{
+                        FileDialog::new()
+                        .add_filter("binary", &["bin",""])
+                        .save_file()
+                    }
+                    else{
+                        None
+                    };
+                    if fdiag.is_some() || !interactive{
+                        let mut f : Box<dyn Write + '_> = match fdiag{
+                            Some(fpath) => Box::new(File::create(fpath)?),
+                            None => Box::new(&mut payload)
+                        };
+                        match sizing_method {
+                            SizingMethod::SizeHeader(_) | SizingMethod::FixedSize(_) => {
+                                let mut rsize = 
+                                    match sizing_method {
+                                        SizingMethod::SizeHeader(x) => self.get_field(x).data_string.parse::<usize>().unwrap_or_default(),
+                                        SizingMethod::FixedSize(x) => x,
+                                        _ => unreachable!()
+                                    };
+                                let mut dat = [0;4096];
+                                // Never ask for more than the field has left, the rest belongs to the next field or message
+                                while rsize > 0{
+                                    let count = rsize.min(dat.len());
+                                    s.read_exact(&mut dat[..count])?;
+                                    rsize -= count;
+                                    offset += count;
+                                    f.write_all(&dat[..count])?;
+                                }
+                            },
+                            SizingMethod::Delimiter => {
+                                let delim = unescape_bytes(&self.fields[i].sizing_meth_str).unwrap_or_default();
+                                let mut dat : Vec<u8> = Vec::new();
+                                let mut c : [u8;1] = [0];
+                                while !dat.ends_with(&delim) || delim.is_empty(){
+                                    if s.read(&mut c)? == 0{
+                                        break;
+                                    }
+                                    dat.push(c[0]);
+                                }
+                                offset += dat.len();
+                                if dat.ends_with(&delim){
+                                    dat.truncate(dat.len() - delim.len());
+                                }
+                                else if !interactive{
+                                    // More of the field may still be on its way
+                                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                                }
+                                f.write_all(&dat)?;
+                            },
+                            SizingMethod::UntilClose => {
+                                offset += std::io::copy(s, &mut f)? as usize;
+                            }
+                        }
+                        drop(f);
+                        if !interactive{
+                            payloads.push((i, payload));
+                        }
+                    }
+                },
+                PacketDataType::CStr => {
+                    let mut dat : Vec<u8> = Vec::new();
+                    let mut c : [u8;1] = [0];
+                    let mut terminated = false;
+                    while s.read(&mut c)? > 0{
+                        if c[0] == 0{
+                            terminated = true;
+                            break;
+                        }
+                        dat.push(c[0]);
+                    }
+                    if !terminated && !interactive{
+                        return Err(std::io::ErrorKind::UnexpectedEof.into());
+                    }
+                    dat.push(0);
+                    offset += dat.len();
+                    dat.pop();
+                    self.fields[i].data_string = String::from_utf8_lossy(&dat).to_string();
+                },
+                PacketDataType::Script => {
+                    let expected = self.script_fields(vars).and_then(|fields| scripting::eval_bytes(&self.fields[i].data_string, fields, &s.data, vars))
+                        .map_err(|e| std::io::Error::other(format!("Script for field {i} failed: {e}")))?;
+                    let mut dat : Vec<u8> = vec![0; expected.len()];
+                    s.read_exact(&mut dat)?;
+                    offset += dat.len();
+                    if dat != expected{
+                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Field {i} is {dat:02x?}, script computed {expected:02x?}")));
+                    }
+                },
+                dtype if dtype.is_varint() => {
+                    let dat = varint::read_varint(s)?;
+                    offset += dat.len();
+                    self.fields[i].data_string = dtype.bytes_to_val(dat).as_ref().to_string();
+                },
+                dtype if dtype.is_filler() => {
+                    let n = match (dtype, self.fields[i].sizing_method){
+                        (PacketDataType::Padding, Some(SizingMethod::FixedSize(n))) => n,
+                        (PacketDataType::Align, Some(SizingMethod::FixedSize(n))) => align_padding(offset, n),
+                        _ => 0
+                    };
+                    let mut dat : Vec<u8> = vec![0; n];
+                    s.read_exact(&mut dat)?;
+                    offset += n;
+                },
+                _ => {
+                    let dtype = self.fields[i].datatype.unwrap();
+                    let mut dat : Vec<u8> = vec![0; dtype.data_size()];
+                    s.read_exact(&mut dat)?;
+                    offset += dat.len();
+                    self.fields[i].data_string = dtype.bytes_to_val(dat).as_ref().to_string();
+                }
+            }
+        }
+        Ok(payloads)
+    }
+
+    /// Compares the decoded values against the fields' expectations
+    pub fn check(&mut self, vars : &Variables){
+        self.checks = self.fields.iter().filter(|f| !f.expect_string.is_empty() && f.datatype.is_some_and(|d| !d.is_filler())).map(|f| {
+            let (expected, parsed) = match substitute(&f.expect_string, vars){
+                Ok(expected) => {
+                    let parsed = Expectation::parse(&expected);
+                    (expected, parsed)
+                },
+                Err(e) => (f.expect_string.clone(), Err(e))
+            };
+            let (passed, note) = match parsed{
+                Ok(exp) => (exp.matches(&f.data_string), None),
+                Err(e) => (false, Some(e))
+            };
+            FieldCheck { field: f.index, expected, actual: f.data_string.clone(), passed, note }
+        }).collect();
+    }
+
+    /// None until a recieved packet has been checked
+    pub fn verdict(&self) -> Option<bool>{
+        (!self.checks.is_empty()).then(|| self.checks.iter().all(|c| c.passed))
+    }
+
+    fn draw_checks(&self) -> Element<'_, Message>{
+        let ok = Color::from_rgb(0.0, 0.6, 0.0);
+        let bad = Color::from_rgb(0.8, 0.0, 0.0);
+        let mut col = Column::new();
+        col = col.push(match self.verdict(){
+            Some(true) => text("All fields match").color(ok),
+            _ => text!("{} of {} fields mismatch", self.checks.iter().filter(|c| !c.passed).count(), self.checks.len()).color(bad),
+        });
+        for c in &self.checks{
+            let f = &self.fields[c.field];
+            let name = if f.name.is_empty() { c.field.to_string() } else { format!("{} {}", c.field, f.name) };
+            let status = match (&c.note, c.passed){
+                (Some(e), _) => text!("bad expectation: {e}").color(bad),
+                (None, true) => text("ok").color(ok),
+                (None, false) => text("MISMATCH").color(bad),
+            };
+            col = col.push(
+                row![
+                    text(name).width(Length::FillPortion(1)),
+                    text!("expected {}", c.expected).width(Length::FillPortion(2)),
+                    text!("got {}", c.actual).width(Length::FillPortion(2)).color_maybe((!c.passed).then_some(bad)),
+                    status.width(Length::FillPortion(1))
+                ].spacing(5)
+            );
+        }
+        col.spacing(2).into()
+    }
+
+    /// (variable, value) for every field bound to a variable, read after a decode
+    pub fn captures(&self) -> impl Iterator<Item = (String, String)> + '_{
+        self.fields.iter().filter(|f| !f.capture.is_empty()).map(|f| (f.capture.clone(), f.data_string.clone()))
+    }
+
+    /// Values handed to scripts, keyed by field name or index when unnamed
+    fn script_fields(&self, vars : &Variables) -> Result<rhai::Map, String>{
+        let fields = self.fields.iter()
+            .filter(|f| f.datatype.is_some_and(|d| !d.is_filler() && !matches!(d, PacketDataType::Script)))
+            .map(|f| Ok((f.key(), substitute(&f.data_string, vars)?)))
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(scripting::field_map(fields))
+    }
+
+    /// Length of a Script field's output for the current values, used where a
+    /// fixed size has to be written out ahead of time
+    pub fn script_len(&self, field : &PacketField) -> usize{
+        let vars = Variables::new();
+        self.script_fields(&vars).and_then(|fields| scripting::eval_bytes(&field.data_string, fields, &[], &vars)).map(|x| x.len()).unwrap_or_default()
+    }
+
+    /// Encodes the packet, Err names the first field that cant be encoded
+    pub fn to_bytes(&self, vars : &Variables) -> Result<Vec<u8>, String>{
+        Ok(self.encode_spans(vars)?.0)
+    }
+
+    /// Encoded packet along with the byte range every field ended up in
+    pub fn encode_spans(&self, vars : &Variables) -> Result<(Vec<u8>, Vec<Range<usize>>), String>{
+        let mut ret : Vec<u8> = Vec::new();
+        let mut spans = Vec::new();
+        for f in &self.fields{
+            let start = ret.len();
+            if let (Some(PacketDataType::Align), Some(SizingMethod::FixedSize(n))) = (f.datatype, f.sizing_method){
+                ret.resize(ret.len() + align_padding(ret.len(), n), f.fill_byte());
+            }
+            else if let Some(PacketDataType::Script) = f.datatype{
+                let dat = self.script_fields(vars).and_then(|fields| scripting::eval_bytes(&f.data_string, fields, &ret, vars))
+                    .map_err(|e| format!("Script for field {} failed: {e}", f.key()))?;
+                ret.extend(dat);
+            }
+            else{
+                ret.extend(f.to_bytes(vars).map_err(|e| format!("Field {}: {e}", f.key()))?);
+            }
+            spans.push(start..ret.len());
+        }
+        Ok((ret, spans))
     }
 }
 
@@ -369,16 +860,41 @@ impl PacketField{
     pub(self) fn new(index : usize) -> Self{
         Self { 
                 index,
+                name: Default::default(),
                 dtype_combo_state: Self::create_dtype_combo(),
                 data_string: Default::default(),
+                capture: Default::default(),
+                expect_string: Default::default(),
+                generator: GenKind::Fixed,
+                gen_string: Default::default(),
+                gen_state: Default::default(),
+                enum_names: None,
                 datatype: None,
                 smethod_combo_state: Self::create_smeth_combo(),
                 sizing_method: None,
                 sizing_meth_str: Default::default()
             }
         }
+    /// Name of the current value in the field's Kaitai enum
+    pub fn enum_name(&self) -> Option<&str>{
+        let val = self.data_string.trim().parse::<i64>().ok()?;
+        self.enum_names.as_ref()?.1.iter().find(|(v, _)| *v == val).map(|(_, n)| n.as_str())
+    }
+    pub fn typed(index : usize, name : String, datatype : PacketDataType, sizing_method : Option<SizingMethod>) -> Self{
+        Self {
+                name,
+                datatype: Some(datatype),
+                sizing_method,
+                sizing_meth_str: match sizing_method{
+                    Some(SizingMethod::FixedSize(x) | SizingMethod::SizeHeader(x)) => x.to_string(),
+                    _ => Default::default()
+                },
+                ..Self::new(index)
+            }
+        }
     pub fn is_valid_entry(&self, dat_str : &str) -> bool{
-        if dat_str == ""{
+        // Variable references are only checked once they are substituted
+        if dat_str.is_empty() || dat_str.starts_with('$'){
             true
         }
         else if let Some(dat_type) = self.datatype{
@@ -391,6 +907,9 @@ impl PacketField{
                 PacketDataType::I32 => dat_str.parse::<i32>().is_ok() || dat_str == "-",
                 PacketDataType::I16 => dat_str.parse::<i16>().is_ok() || dat_str == "-",
                 PacketDataType::I8  => dat_str.parse::<i8>().is_ok()  || dat_str == "-",
+                PacketDataType::ULeb128 => dat_str.parse::<u64>().is_ok(),
+                PacketDataType::SLeb128 | PacketDataType::ZigZag => dat_str.parse::<i64>().is_ok() || dat_str == "-",
+                PacketDataType::Padding | PacketDataType::Align => dat_str.parse::<u8>().is_ok(),
                 _ => true
             }
         }
@@ -398,30 +917,71 @@ impl PacketField{
             true
         }
     }
-    pub(self) fn to_bytes(&self) -> Vec<u8>{
-        if let Some(dat) = self.datatype{
-            match dat{
-                PacketDataType::Bytes(_) => {
-                    let mut f = File::open(self.data_string.clone()).expect("File Not found");
-                    let mut ret : Vec<u8> = Default::default();
-                    f.read_to_end(&mut ret).unwrap();
-                    ret
-                },
-                PacketDataType::CStr => std::ffi::CString::new(self.data_string.as_str()).unwrap().as_bytes_with_nul().iter().cloned().collect(),
-                PacketDataType::U64 => self.data_string.parse::<u64>().expect("Invalid Integer Value").to_ne_bytes().iter().cloned().collect(),
-                PacketDataType::U32 => self.data_string.parse::<u32>().expect("Invalid Integer Value").to_ne_bytes().iter().cloned().collect(),
-                PacketDataType::U16 => self.data_string.parse::<u16>().expect("Invalid Integer Value").to_ne_bytes().iter().cloned().collect(),
-                PacketDataType::U8  => self.data_string.parse::<u8>().expect("Invalid Integer Value").to_ne_bytes().iter().cloned().collect(),
-                PacketDataType::I64 => self.data_string.parse::<i64>().expect("Invalid Integer Value").to_ne_bytes().iter().cloned().collect(),
-                PacketDataType::I32 => self.data_string.parse::<i32>().expect("Invalid Integer Value").to_ne_bytes().iter().cloned().collect(),
-                PacketDataType::I16 => self.data_string.parse::<i16>().expect("Invalid Integer Value").to_ne_bytes().iter().cloned().collect(),
-                PacketDataType::I8  => self.data_string.parse::<i8>().expect("Invalid Integer Value").to_ne_bytes().iter().cloned().collect(),
+    pub(self) fn to_bytes(&self, vars : &Variables) -> Result<Vec<u8>, String>{
+        let mut data = substitute(&self.data_string, vars)?;
+        if let Some(dtype) = self.datatype.filter(|_| self.generator != GenKind::Fixed){
+            match generators::generate(self.generator, &self.gen_string, dtype, &self.gen_state)?{
+                Generated::Value(x) => data = x,
+                Generated::Bytes(mut ret) => {
+                    if let Some(SizingMethod::Delimiter) = self.sizing_method{
+                        ret.extend(unescape_bytes(&self.sizing_meth_str).unwrap_or_default());
+                    }
+                    return Ok(ret);
+                }
             }
         }
-        else{
-            Default::default()
+        let Some(dat) = self.datatype else { return Ok(Default::default()) };
+        fn int<T : std::str::FromStr>(data : &str, dat : PacketDataType) -> Result<T, String>{
+            data.trim().parse::<T>().map_err(|_| format!("{data:?} is not a valid {dat}"))
         }
-    } 
+        Ok(match dat{
+            // A fixed size field without a file is sent as that many zero bytes
+            PacketDataType::Bytes(SizingMethod::FixedSize(n)) if data.is_empty() && matches!(self.sizing_method, Some(SizingMethod::FixedSize(m)) if m == n) => vec![0; n],
+            PacketDataType::Bytes(_) => {
+                let mut ret : Vec<u8> = Default::default();
+                File::open(&data).and_then(|mut f| f.read_to_end(&mut ret)).map_err(|e| format!("Couldnt read {data:?}: {e}"))?;
+                if let Some(SizingMethod::Delimiter) = self.sizing_method{
+                    ret.extend(unescape_bytes(&self.sizing_meth_str).unwrap_or_default());
+                }
+                ret
+            },
+            PacketDataType::CStr => std::ffi::CString::new(data.as_str()).map_err(|_| "CStr cant contain a nul byte".to_string())?.as_bytes_with_nul().to_vec(),
+            PacketDataType::U64 => int::<u64>(&data, dat)?.to_ne_bytes().to_vec(),
+            PacketDataType::U32 => int::<u32>(&data, dat)?.to_ne_bytes().to_vec(),
+            PacketDataType::U16 => int::<u16>(&data, dat)?.to_ne_bytes().to_vec(),
+            PacketDataType::U8  => int::<u8>(&data, dat)?.to_ne_bytes().to_vec(),
+            PacketDataType::I64 => int::<i64>(&data, dat)?.to_ne_bytes().to_vec(),
+            PacketDataType::I32 => int::<i32>(&data, dat)?.to_ne_bytes().to_vec(),
+            PacketDataType::I16 => int::<i16>(&data, dat)?.to_ne_bytes().to_vec(),
+            PacketDataType::I8  => int::<i8>(&data, dat)?.to_ne_bytes().to_vec(),
+            PacketDataType::ULeb128 => varint::encode_uleb128(int::<u64>(&data, dat)?),
+            PacketDataType::SLeb128 => varint::encode_sleb128(int::<i64>(&data, dat)?),
+            PacketDataType::ZigZag  => varint::encode_uleb128(varint::zigzag_encode(int::<i64>(&data, dat)?)),
+            PacketDataType::Padding => match self.sizing_method{
+                Some(SizingMethod::FixedSize(n)) => vec![self.fill_byte(); n],
+                _ => Default::default()
+            },
+            // Depend on where the field lands, PacketView::to_bytes handles them
+            PacketDataType::Align | PacketDataType::Script => Default::default(),
+        })
+    }
+
+    /// Generators that make sense for the field's type, the first is always Fixed
+    pub fn generator_kinds(&self) -> &'static [GenKind]{
+        match self.datatype{
+            Some(PacketDataType::Bytes(_)) => &GenKind::BYTES,
+            Some(d) if d.is_integer() => &GenKind::INTEGER,
+            _ => &[GenKind::Fixed]
+        }
+    }
+
+    pub fn key(&self) -> String{
+        if self.name.is_empty() { self.index.to_string() } else { self.name.clone() }
+    }
+
+    pub fn fill_byte(&self) -> u8{
+        self.data_string.parse::<u8>().unwrap_or_default()
+    }
 
     fn create_dtype_combo() -> ComboState<PacketDataType>{
         ComboState::new(
@@ -435,7 +995,13 @@ impl PacketField{
                     PacketDataType::I64,
                     PacketDataType::I32,
                     PacketDataType::I16,
-                    PacketDataType::I8
+                    PacketDataType::I8,
+                    PacketDataType::ULeb128,
+                    PacketDataType::SLeb128,
+                    PacketDataType::ZigZag,
+                    PacketDataType::Padding,
+                    PacketDataType::Align,
+                    PacketDataType::Script
                 ]
             )
     }
@@ -443,15 +1009,22 @@ impl PacketField{
         ComboState::new(
             vec![
                 SizingMethod::FixedSize(0),
-                SizingMethod::SizeHeader(0)
+                SizingMethod::SizeHeader(0),
+                SizingMethod::Delimiter,
+                SizingMethod::UntilClose
             ]
         )
     }
 
-    pub fn draw(&self, parent_index : usize) -> Element<'_, Message>{
+    pub fn draw(&self, parent_index : usize, recieve : bool) -> Element<'_, Message>{
         let mut row = Row::new();
-        let idx = self.index.clone();
+        let idx = self.index;
         row = row.push(text::Text::new(format!("{}", self.index)));
+        row = row.push(
+            text_input("Name", &self.name)
+                .on_input(move |s| Message::PVMessage(parent_index, PVMessage::NameEntry(s, idx)))
+                .width(Length::FillPortion(1))
+        );
         row = row.push(
             ComboBox::new(
                 &self.dtype_combo_state, "Please select a data type", self.datatype.as_ref(), 
@@ -462,13 +1035,32 @@ impl PacketField{
             ).width(Length::FillPortion(1))
         );
 
-        if let Some(PacketDataType::Bytes(_)) = self.datatype{
+        let kinds = self.generator_kinds();
+        if !recieve && kinds.len() > 1{
+            row = row.push(
+                pick_list(kinds, Some(self.generator), move |x| Message::PVMessage(parent_index, PVMessage::Generator(x, idx)))
+            );
+        }
+        let generated = !recieve && self.generator != GenKind::Fixed;
+        if generated{
+            if let Some(placeholder) = self.generator.placeholder(){
+                row = row.push(
+                    text_input(placeholder, &self.gen_string)
+                        .on_input(move |s| Message::PVMessage(parent_index, PVMessage::GenEntry(s, idx)))
+                        .width(Length::FillPortion(1))
+                );
+            }
+        }
+        else if let Some(PacketDataType::Bytes(_)) = self.datatype{
             row = row.push(
                 button("Select a file")
                 .on_press(Message::PVMessage(parent_index, PVMessage::OpenFile(self.index)))
             );
-            let field_idx = self.index.clone();
-            let p_idx = parent_index.clone();
+        }
+
+        if let Some(PacketDataType::Bytes(_)) = self.datatype{
+            let field_idx = self.index;
+            let p_idx = parent_index;
             row = row.push(
                 combo_box(
                     &self.smethod_combo_state,
@@ -480,27 +1072,61 @@ impl PacketField{
 
             if let Some(meth) = self.sizing_method{
                 let (f_idx, p_idx) = (self.index, parent_index);
-                row = row.push(
-                    match meth{
-                        SizingMethod::SizeHeader(_) => text_input("Field index for Sizing", &self.sizing_meth_str)
-                            .on_input(move |s| Message::PVMessage(p_idx, PVMessage::MethodEntry(s, f_idx))),
-                        SizingMethod::FixedSize(_) => text_input("Size of Data", &self.sizing_meth_str)
-                            .on_input(move |s| Message::PVMessage(p_idx, PVMessage::MethodEntry(s, f_idx))),
-                    }
-                );
+                let placeholder = match meth{
+                    SizingMethod::SizeHeader(_) => Some("Field index for Sizing"),
+                    SizingMethod::FixedSize(_) => Some("Size of Data"),
+                    SizingMethod::Delimiter => Some("Delimiter e.g. \\r\\n"),
+                    SizingMethod::UntilClose => None,
+                };
+                if let Some(placeholder) = placeholder{
+                    row = row.push(
+                        text_input(placeholder, &self.sizing_meth_str)
+                            .on_input(move |s| Message::PVMessage(p_idx, PVMessage::MethodEntry(s, f_idx)))
+                    );
+                }
             }
             
         }
-        else{
-            let p2 = parent_index.clone();
+        else if let Some(dtype) = self.datatype.filter(|d| d.is_filler()){
+            let (f_idx, p_idx) = (self.index, parent_index);
+            row = row.push(
+                text(if let PacketDataType::Padding = dtype { "Padding bytes" } else { "Align to" })
+            );
+            row = row.push(
+                text_input("Count", &self.sizing_meth_str)
+                    .on_input(move |s| Message::PVMessage(p_idx, PVMessage::MethodEntry(s, f_idx)))
+                    .width(Length::FillPortion(1))
+            );
+            row = row.push(
+                text_input("Fill byte", &self.data_string)
+                    .on_input(move |s| Message::PVMessage(p_idx, PVMessage::DataEntry(s, f_idx)))
+                    .width(Length::FillPortion(1))
+            );
+        }
+        else if !generated{
+            let p2 = parent_index;
             
             row = row.push(
                 text_input(
-                    "Enter data here",
+                    if let Some(PacketDataType::Script) = self.datatype { "Rhai script returning a blob" } else { "Enter data here" },
                     &self.data_string
                 ).on_input(move |x|{Message::PVMessage(p2, PVMessage::DataEntry(x, self.index))}).width(Length::FillPortion(3)),
             );
         }
+        if recieve && self.datatype.is_some_and(|d| !d.is_filler() && !matches!(d, PacketDataType::Bytes(_))){
+            row = row.push(
+                text_input("Expect", &self.expect_string)
+                    .on_input(move |s| Message::PVMessage(parent_index, PVMessage::ExpectEntry(s, idx)))
+                    .width(Length::FillPortion(1))
+            );
+        }
+        if recieve && self.datatype.is_some_and(|d| !d.is_filler()){
+            row = row.push(
+                text_input("Capture as", &self.capture)
+                    .on_input(move |s| Message::PVMessage(parent_index, PVMessage::CaptureEntry(s, idx)))
+                    .width(Length::FillPortion(1))
+            );
+        }
         row = row.push(
             button("remove field")
                 .on_press(Message::PVMessage(parent_index, PVMessage::RemoveField(self.index)))
@@ -516,4 +1142,94 @@ impl PacketField{
         .center_y(Fill)
         .into()
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn view(fields : Vec<PacketField>) -> PacketView{
+        PacketView::with_fields(0, "test".to_string(), fields)
+    }
+
+    fn field(index : usize, datatype : PacketDataType, data : &str) -> PacketField{
+        PacketField{ data_string: data.to_string(), ..PacketField::typed(index, String::new(), datatype, None) }
+    }
+
+    #[test]
+    fn substitutes_variables(){
+        let v = view(vec![field(0, PacketDataType::U16, "${id}"), field(1, PacketDataType::CStr, "hi")]);
+        let vars = Variables::from([("id".to_string(), "258".to_string())]);
+        assert_eq!(v.to_bytes(&vars).unwrap(), [258u16.to_ne_bytes().as_slice(), b"hi\0"].concat());
+    }
+
+    #[test]
+    fn undefined_variable_is_an_error(){
+        let v = view(vec![field(0, PacketDataType::U32, "${idd}")]);
+        let e = v.to_bytes(&Variables::new()).unwrap_err();
+        assert!(e.contains("${idd}"), "{e}");
+    }
+
+    #[test]
+    fn bad_integer_is_an_error(){
+        for data in ["", "-", "300"]{
+            let v = view(vec![field(0, PacketDataType::U8, data)]);
+            assert!(v.to_bytes(&Variables::new()).is_err(), "{data:?}");
+        }
+    }
+
+    #[test]
+    fn failing_script_is_an_error(){
+        let v = view(vec![field(0, PacketDataType::U8, "1"), field(1, PacketDataType::Script, "throw \"nope\"")]);
+        let e = v.to_bytes(&Variables::new()).unwrap_err();
+        assert!(e.contains("nope"), "{e}");
+        let v = view(vec![field(0, PacketDataType::U8, "1"), field(1, PacketDataType::Script, "[packet[0] + 1]")]);
+        assert_eq!(v.to_bytes(&Variables::new()).unwrap(), [1, 2]);
+    }
+
+    #[test]
+    fn generator_error_is_an_error(){
+        let mut f = field(0, PacketDataType::U8, "");
+        f.generator = GenKind::Random;
+        f.gen_string = "9..3".to_string();
+        assert!(view(vec![f]).to_bytes(&Variables::new()).is_err());
+    }
+
+    #[test]
+    fn counter_counts_once_per_encode(){
+        let mut f = field(0, PacketDataType::U8, "");
+        f.generator = GenKind::Counter;
+        f.gen_string = "254,1".to_string();
+        let v = view(vec![f]);
+        let sent : Vec<Vec<u8>> = (0..3).map(|_| v.to_bytes(&Variables::new()).unwrap()).collect();
+        assert_eq!(sent, [vec![254], vec![255], vec![254]]);
+    }
+
+    #[test]
+    fn missing_file_is_an_error(){
+        let v = view(vec![field(0, PacketDataType::Bytes(SizingMethod::FixedSize(0)), "")]);
+        assert!(v.to_bytes(&Variables::new()).is_err());
+    }
+
+    #[test]
+    fn decoded_fields_are_checked(){
+        let mut v = view(vec![field(0, PacketDataType::U8, ""), field(1, PacketDataType::U16, ""), field(2, PacketDataType::CStr, "")]);
+        assert_eq!(v.verdict(), None);
+        v[0].expect_string = "${ver}".to_string();
+        v[1].expect_string = "..0x100".to_string();
+        v[2].expect_string = "/^ok\\d$/".to_string();
+        let vars = Variables::from([("ver".to_string(), "2".to_string())]);
+        let dat = [&[2u8][..], &300u16.to_ne_bytes(), b"ok7\0"].concat();
+        v.decode(&mut std::io::Cursor::new(&dat), &vars).unwrap();
+        v.check(&vars);
+        let passed : Vec<bool> = v.checks.iter().map(|c| c.passed).collect();
+        assert_eq!(passed, [true, false, true]);
+        assert_eq!((v.checks[0].expected.as_str(), v.checks[1].actual.as_str()), ("2", "300"));
+        assert_eq!(v.verdict(), Some(false));
+
+        v[1].expect_string = "1..".to_string();
+        v[0].expect_string = "${missing}".to_string();
+        v.check(&vars);
+        assert!(!v.checks[0].passed && v.checks[0].note.is_some());
+        assert!(v.checks[1].passed);
+    }
+}
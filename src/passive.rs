@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::io::{self, Cursor, ErrorKind};
+use std::time::{Duration, Instant};
+
+use iced::{widget::{button, row, text}, Element};
+
+use crate::framing::{FrameRule, Framing};
+use crate::packet::PacketView;
+use crate::state::Message;
+use crate::variables::Variables;
+
+// Passive receive: everything coming in is buffered and split into messages by
+// trying every recieve-mode template against the front of the buffer. A
+// template fits when it decodes without running out of data and all of its
+// Expect values hold, so magic bytes and discriminator fields are written as
+// expectations. Among the fitting templates the one with the most expectations
+// wins. Templates without any only fit when the sizes add up: the message
+// takes exactly what is buffered or is followed by something that decodes too.
+// With a length prefix or delimiter framing rule, or on a transport that keeps
+// message boundaries, the messages are cut by that instead and each one goes
+// to the template that decodes all of it. A tick only splits for so long, the
+// rest of the buffer waits for the next one.
+
+pub enum Frame{
+    /// Template index, message length and a summary for the log
+    Matched(usize, usize, String),
+    /// Bytes no template can start with, up to where one might
+    Unknown(usize),
+    NeedMore
+}
+
+#[derive(Clone, Copy)]
+pub enum Fit{
+    Yes(usize, usize),
+    NeedMore,
+    No
+}
+
+/// Decodes against a copy of the template, Yes carries (length, expectations met)
+pub fn fit(view : &PacketView, dat : &[u8], vars : &Variables) -> Fit{
+    let mut view = view.clone();
+    let mut cur = Cursor::new(dat);
+    match view.decode(&mut cur, vars){
+        Ok(_) => {
+            view.check(vars);
+            if view.verdict() == Some(false){
+                return Fit::No;
+            }
+            Fit::Yes(cur.position() as usize, view.checks.len())
+        },
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Fit::NeedMore,
+        Err(_) => Fit::No
+    }
+}
+
+/// Most offsets tried when looking for where a template could start, what
+/// lies past them is looked at by the next call
+pub const SCAN_WINDOW : usize = 1024;
+
+/// Longest a tick spends splitting the buffer, what is left waits for the next one
+pub const TICK_BUDGET : Duration = Duration::from_millis(10);
+
+/// How many messages ahead a template without expectations is checked
+const CHAIN_DEPTH : usize = 4;
+
+/// What one next_frame call already worked out, by offset into the buffer, so
+/// the chains looked at from different offsets share the work
+#[derive(Default)]
+struct Memo{
+    /// (template position, offset)
+    fits : HashMap<(usize, usize), Fit>,
+    /// (offset, depth), whether the chain from there holds up
+    chains : HashMap<(usize, usize), bool>
+}
+
+/// Whether what follows a message decodes too, or could once more arrives
+fn consistent(views : &[PacketView], dat : &[u8], at : usize, vars : &Variables, depth : usize, memo : &mut Memo) -> bool{
+    if at == dat.len(){
+        return true;
+    }
+    if depth >= CHAIN_DEPTH{
+        return false;
+    }
+    if let Some(x) = memo.chains.get(&(at, depth)){
+        return *x;
+    }
+    let x = matches!(best_fit(views, dat, at, vars, true, depth + 1, memo), Some(Frame::Matched(..) | Frame::NeedMore));
+    memo.chains.insert((at, depth), x);
+    x
+}
+
+fn best_fit(views : &[PacketView], dat : &[u8], at : usize, vars : &Variables, idle : bool, depth : usize, memo : &mut Memo) -> Option<Frame>{
+    let mut best : Option<(&PacketView, usize, usize)> = None;
+    let mut waiting = false;
+    for (i, v) in views.iter().enumerate().filter(|(_, v)| v.recieve && !v.fields.is_empty()){
+        match *memo.fits.entry((i, at)).or_insert_with(|| fit(v, &dat[at..], vars)){
+            Fit::Yes(len, score) if len > 0 => {
+                // Nothing identifies it, so only trust the sizes when they add up
+                if score == 0 && !consistent(views, dat, at + len, vars, depth, memo){
+                    continue;
+                }
+                if best.is_none_or(|b| score > b.2){
+                    best = Some((v, len, score));
+                }
+            },
+            Fit::NeedMore => waiting = true,
+            _ => ()
+        }
+    }
+    match best{
+        // Give the more specific templates a chance to see the rest of their message first
+        Some((_, _, 0)) if waiting && !idle => Some(Frame::NeedMore),
+        // Only the front of the buffer is logged, the chain behind it just has to hold up
+        Some((v, len, _)) => {
+            let summary = if depth == 0 { v.describe(&dat[at..at + len], vars).unwrap_or_default() } else { String::new() };
+            Some(Frame::Matched(v.index, len, summary))
+        },
+        None if waiting => Some(Frame::NeedMore),
+        None => None
+    }
+}
+
+/// The template that takes up all of a frame cut by a length prefix or delimiter
+fn classify(views : &[PacketView], frame : &[u8], vars : &Variables) -> Frame{
+    let mut best : Option<(&PacketView, usize)> = None;
+    for v in views.iter().filter(|v| v.recieve && !v.fields.is_empty()){
+        if let Fit::Yes(len, score) = fit(v, frame, vars) && len == frame.len() && best.is_none_or(|b| score > b.1){
+            best = Some((v, score));
+        }
+    }
+    match best{
+        Some((v, _)) => Frame::Matched(v.index, frame.len(), v.describe(frame, vars).unwrap_or_default()),
+        None => Frame::Unknown(frame.len())
+    }
+}
+
+/// Decides what the front of the buffer is. `idle` is set when nothing new
+/// arrived since the last call, so nothing more specific is coming for now.
+/// Past `deadline` the offsets already ruled out go as unknown and the scan
+/// carries on from there next call
+pub fn next_frame(views : &[PacketView], framing : &Framing, vars : &Variables, idle : bool, deadline : Instant) -> Frame{
+    let dat = &framing.buf;
+    if dat.is_empty(){
+        return Frame::NeedMore;
+    }
+    if framing.rule != FrameRule::Template || framing.by_message(){
+        return match framing.split(){
+            Ok(Some(len)) => classify(views, &dat[..len], vars),
+            Ok(None) => Frame::NeedMore,
+            // Nothing left to frame by, so nothing buffered can be trusted
+            Err(_) => Frame::Unknown(dat.len())
+        };
+    }
+    let mut memo = Memo::default();
+    if let Some(f) = best_fit(views, dat, 0, vars, idle, 0, &mut memo){
+        return f;
+    }
+    // Skip ahead to where some template could start
+    let window = dat.len().min(SCAN_WINDOW);
+    for skip in 1..window{
+        if Instant::now() >= deadline || best_fit(views, dat, skip, vars, true, 0, &mut memo).is_some(){
+            return Frame::Unknown(skip);
+        }
+    }
+    Frame::Unknown(window)
+}
+
+#[derive(Default)]
+pub struct Passive{
+    pub(crate) running : bool,
+    pub(crate) run_id : usize,
+    pub(crate) matched : usize,
+    pub(crate) unknown : usize,
+    /// The last tick ran out of time with frames still buffered, the next one
+    /// splits those before reading more
+    pub(crate) backlog : bool,
+    /// Why the connection ended, kept until the backlog is through
+    pub(crate) closed : Option<io::Error>
+}
+
+impl Passive{
+    pub fn draw(&self, conn : usize, buffered : usize) -> Element<'_, Message>{
+        let toggle = if self.running{
+            button("Stop passive recieve").on_press(Message::StopPassive(conn))
+        }
+        else{
+            button("Passive recieve").on_press(Message::StartPassive(conn))
+        };
+        row![
+            toggle,
+            text!("{} matched, {} unknown, {} bytes buffered", self.matched, self.unknown, buffered)
+        ].spacing(5).into()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::packet::{PacketDataType, PacketField};
+
+    fn view() -> PacketView{
+        let mut fields = vec![
+            PacketField::typed(0, String::new(), PacketDataType::U8, None),
+            PacketField::typed(1, String::new(), PacketDataType::U8, None),
+        ];
+        fields[0].expect_string = "0xaa".to_string();
+        let mut view = PacketView::with_fields(0, "t".to_string(), fields);
+        view.recieve = true;
+        view
+    }
+
+    #[test]
+    fn skips_garbage_a_window_at_a_time(){
+        let (views, vars) = ([view()], Variables::new());
+        let mut framing = Framing::default();
+        framing.buf = vec![0; 3000];
+        framing.buf.extend([0xaa, 1]);
+        let mut skipped = Vec::new();
+        loop{
+            match next_frame(&views, &framing, &vars, true, Instant::now() + Duration::from_secs(60)){
+                Frame::Unknown(len) => {
+                    skipped.push(len);
+                    framing.take(len);
+                },
+                Frame::Matched(idx, len, _) => {
+                    assert_eq!((idx, len), (0, 2));
+                    break;
+                },
+                Frame::NeedMore => panic!("the message is all there")
+            }
+        }
+        assert_eq!(skipped, [SCAN_WINDOW, SCAN_WINDOW, 3000 - 2 * SCAN_WINDOW]);
+    }
+
+    #[test]
+    fn scan_stops_at_the_deadline(){
+        let (views, vars) = ([view()], Variables::new());
+        let mut framing = Framing::default();
+        framing.buf = vec![0; 3000];
+        // Only the front was ruled out before time ran out
+        assert!(matches!(next_frame(&views, &framing, &vars, true, Instant::now()), Frame::Unknown(1)));
+    }
+
+    #[test]
+    fn chains_are_worked_out_once(){
+        // Templates that fit anything but never add up past the chain depth
+        let mut views = Vec::new();
+        for i in 0..16{
+            let mut v = PacketView::with_fields(i, "t".to_string(), vec![PacketField::typed(0, String::new(), PacketDataType::U16, None)]);
+            v.recieve = true;
+            views.push(v);
+        }
+        let mut framing = Framing::default();
+        framing.buf = vec![0; 201];
+        let start = Instant::now();
+        assert!(matches!(next_frame(&views, &framing, &Variables::new(), false, Instant::now() + Duration::from_secs(60)), Frame::Unknown(_)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,399 @@
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use crate::session::{Direction, SessionEntry};
+
+// pcapng export of a session and pcap/pcapng import. Exports use LINKTYPE_RAW so
+// only an IP header has to be made up around each TCP segment or UDP datagram.
+
+const LINKTYPE_NULL : u32 = 0;
+const LINKTYPE_ETHERNET : u32 = 1;
+const LINKTYPE_RAW : u32 = 101;
+const LINKTYPE_LINUX_SLL : u32 = 113;
+const LINKTYPE_IPV4 : u32 = 228;
+const LINKTYPE_IPV6 : u32 = 229;
+const LINKTYPE_LINUX_SLL2 : u32 = 276;
+
+const SEGMENT_SIZE : usize = 1460;
+
+/// A TCP or UDP payload pulled out of a capture
+pub struct Captured{
+    pub time : SystemTime,
+    pub src : SocketAddr,
+    pub dst : SocketAddr,
+    pub data : Vec<u8>
+}
+
+fn checksum(chunks : &[&[u8]]) -> u16{
+    let mut sum : u32 = 0;
+    for c in chunks.iter().flat_map(|c| c.chunks(2)){
+        sum += u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)]) as u32;
+    }
+    while sum >> 16 != 0{
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn ip_packet(src : SocketAddr, dst : SocketAddr, proto : u8, mut transport : Vec<u8>, csum_at : usize) -> Vec<u8>{
+    let len = transport.len();
+    let mut ret = Vec::new();
+    match (src.ip(), dst.ip()){
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            let pseudo = [&s.octets()[..], &d.octets()[..], &[0, proto], &(len as u16).to_be_bytes()].concat();
+            let csum = checksum(&[&pseudo, &transport]);
+            transport[csum_at..csum_at + 2].copy_from_slice(&csum.to_be_bytes());
+            ret.extend([0x45, 0]);
+            ret.extend(((20 + len) as u16).to_be_bytes());
+            ret.extend([0, 0, 0x40, 0, 64, proto, 0, 0]);
+            ret.extend(s.octets());
+            ret.extend(d.octets());
+            let csum = checksum(&[&ret]);
+            ret[10..12].copy_from_slice(&csum.to_be_bytes());
+        },
+        (s, d) => {
+            let v6 = |x : IpAddr| match x{
+                IpAddr::V4(x) => x.to_ipv6_mapped(),
+                IpAddr::V6(x) => x
+            };
+            let (s, d) = (v6(s), v6(d));
+            let pseudo = [&s.octets()[..], &d.octets()[..], &(len as u32).to_be_bytes(), &[0, 0, 0, proto]].concat();
+            let csum = checksum(&[&pseudo, &transport]);
+            transport[csum_at..csum_at + 2].copy_from_slice(&csum.to_be_bytes());
+            ret.extend([0x60, 0, 0, 0]);
+            ret.extend((len as u16).to_be_bytes());
+            ret.extend([proto, 64]);
+            ret.extend(s.octets());
+            ret.extend(d.octets());
+        }
+    }
+    ret.extend(transport);
+    ret
+}
+
+fn block(kind : u32, body : &[u8]) -> Vec<u8>{
+    let padded = body.len().div_ceil(4) * 4;
+    let total = (12 + padded) as u32;
+    let mut ret = Vec::with_capacity(total as usize);
+    ret.extend(kind.to_le_bytes());
+    ret.extend(total.to_le_bytes());
+    ret.extend(body);
+    ret.resize(8 + padded, 0);
+    ret.extend(total.to_le_bytes());
+    ret
+}
+
+/// Builds a pcapng file with one packet per TCP segment / UDP datagram of the
+/// session. `flow` gives the (local, peer) addresses each entry went between,
+/// local being the side packetmancer was on; every pair is its own stream.
+pub fn write_pcapng(entries : &[SessionEntry], flow : impl Fn(&SessionEntry) -> (SocketAddr, SocketAddr), udp : bool) -> Vec<u8>{
+    let mut ret = Vec::new();
+    let mut shb = Vec::new();
+    shb.extend(0x1A2B3C4Du32.to_le_bytes());
+    shb.extend(1u16.to_le_bytes());
+    shb.extend(0u16.to_le_bytes());
+    shb.extend((-1i64).to_le_bytes());
+    ret.extend(block(0x0A0D0D0A, &shb));
+    let mut idb = Vec::new();
+    idb.extend((LINKTYPE_RAW as u16).to_le_bytes());
+    idb.extend(0u16.to_le_bytes());
+    idb.extend(0u32.to_le_bytes());
+    ret.extend(block(1, &idb));
+
+    // Sequence numbers only have to be consistent for Wireshark to follow the stream
+    let mut seqs : HashMap<(SocketAddr, SocketAddr), (u32, u32)> = HashMap::new();
+    for e in entries{
+        let (local, peer) = flow(e);
+        let (src, dst) = if e.direction == Direction::Sent { (local, peer) } else { (peer, local) };
+        for chunk in e.data.chunks(if udp { 65507 } else { SEGMENT_SIZE }){
+            let packet = if udp{
+                let mut h = Vec::new();
+                h.extend(src.port().to_be_bytes());
+                h.extend(dst.port().to_be_bytes());
+                h.extend(((8 + chunk.len()) as u16).to_be_bytes());
+                h.extend([0, 0]);
+                h.extend(chunk);
+                ip_packet(src, dst, 17, h, 6)
+            }
+            else{
+                let (local_seq, peer_seq) = seqs.entry((local, peer)).or_insert((1000, 5000));
+                let (seq, ack) = if e.direction == Direction::Sent { (local_seq, *peer_seq) } else { (peer_seq, *local_seq) };
+                let mut h = Vec::new();
+                h.extend(src.port().to_be_bytes());
+                h.extend(dst.port().to_be_bytes());
+                h.extend(seq.to_be_bytes());
+                h.extend(ack.to_be_bytes());
+                h.extend([0x50, 0x18, 0xff, 0xff, 0, 0, 0, 0]);
+                h.extend(chunk);
+                *seq = seq.wrapping_add(chunk.len() as u32);
+                ip_packet(src, dst, 6, h, 16)
+            };
+            let ts = e.time.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+            let mut epb = Vec::new();
+            epb.extend(0u32.to_le_bytes());
+            epb.extend(((ts >> 32) as u32).to_le_bytes());
+            epb.extend((ts as u32).to_le_bytes());
+            epb.extend((packet.len() as u32).to_le_bytes());
+            epb.extend((packet.len() as u32).to_le_bytes());
+            epb.extend(packet);
+            ret.extend(block(6, &epb));
+        }
+    }
+    ret
+}
+
+struct Cursor<'a>{
+    dat : &'a [u8],
+    le : bool
+}
+
+impl Cursor<'_>{
+    fn u16(&self, at : usize) -> Option<u16>{
+        let b : [u8;2] = self.dat.get(at..at + 2)?.try_into().ok()?;
+        Some(if self.le { u16::from_le_bytes(b) } else { u16::from_be_bytes(b) })
+    }
+    fn u32(&self, at : usize) -> Option<u32>{
+        let b : [u8;4] = self.dat.get(at..at + 4)?.try_into().ok()?;
+        Some(if self.le { u32::from_le_bytes(b) } else { u32::from_be_bytes(b) })
+    }
+}
+
+/// Pulls the TCP/UDP payloads out of a classic pcap or a pcapng file. Packets
+/// without a payload (handshakes, bare ACKs) are skipped.
+pub fn read_capture(dat : &[u8]) -> Result<Vec<Captured>, String>{
+    let magic = dat.get(0..4).ok_or("File too short")?;
+    let mut ret = Vec::new();
+    if magic == [0x0A, 0x0D, 0x0D, 0x0A]{
+        read_pcapng(dat, &mut ret)?;
+    }
+    else{
+        let (le, nanos) = match magic{
+            [0xd4, 0xc3, 0xb2, 0xa1] => (true, false),
+            [0xa1, 0xb2, 0xc3, 0xd4] => (false, false),
+            [0x4d, 0x3c, 0xb2, 0xa1] => (true, true),
+            [0xa1, 0xb2, 0x3c, 0x4d] => (false, true),
+            _ => return Err("Not a pcap or pcapng file".to_string())
+        };
+        let c = Cursor { dat, le };
+        let linktype = c.u32(20).ok_or("Truncated pcap header")? & 0xffff;
+        let mut pos = 24;
+        while let (Some(sec), Some(frac), Some(len)) = (c.u32(pos), c.u32(pos + 4), c.u32(pos + 8)){
+            let frame = dat.get(pos + 16..pos + 16 + len as usize).ok_or("Truncated packet record")?;
+            let time = UNIX_EPOCH + Duration::from_secs(sec as u64) + if nanos { Duration::from_nanos(frac as u64) } else { Duration::from_micros(frac as u64) };
+            ret.extend(link_payload(linktype, frame, time));
+            pos += 16 + len as usize;
+        }
+    }
+    Ok(ret)
+}
+
+fn read_pcapng(dat : &[u8], ret : &mut Vec<Captured>) -> Result<(), String>{
+    let mut c = Cursor { dat, le: true };
+    // (linktype, timestamp units per second) per interface of the current section
+    let mut ifaces : Vec<(u32, u64)> = Vec::new();
+    let mut pos = 0;
+    while pos + 12 <= dat.len(){
+        if dat[pos..pos + 4] == [0x0A, 0x0D, 0x0D, 0x0A]{
+            c.le = dat.get(pos + 8..pos + 12) == Some(&[0x4D, 0x3C, 0x2B, 0x1A]);
+            ifaces.clear();
+        }
+        let kind = c.u32(pos).ok_or("Truncated block")?;
+        let len = c.u32(pos + 4).ok_or("Truncated block")? as usize;
+        if len < 12 || pos + len > dat.len(){
+            return Err("Corrupt block length".to_string());
+        }
+        let body = &dat[pos + 8..pos + len - 4];
+        match kind{
+            1 => {
+                let linktype = c.u16(pos + 8).unwrap_or_default() as u32;
+                let mut units = 1_000_000;
+                // walk the options looking for if_tsresol
+                let mut opt = pos + 16;
+                while let (Some(code), Some(olen)) = (c.u16(opt), c.u16(opt + 2)){
+                    if code == 0 || opt + 4 > pos + len - 4{
+                        break;
+                    }
+                    if code == 9{
+                        let r = dat[opt + 4];
+                        units = if r & 0x80 != 0 { 1u64.checked_shl((r & 0x7f) as u32) } else { 10u64.checked_pow(r as u32) }
+                            .ok_or("Unsupported timestamp resolution")?;
+                    }
+                    opt += 4 + (olen as usize).div_ceil(4) * 4;
+                }
+                ifaces.push((linktype, units));
+            },
+            6 => {
+                let iface = c.u32(pos + 8).unwrap_or_default() as usize;
+                let (linktype, units) = *ifaces.get(iface).ok_or("Packet on an undeclared interface")?;
+                let ts = ((c.u32(pos + 12).unwrap_or_default() as u64) << 32) | c.u32(pos + 16).unwrap_or_default() as u64;
+                let caplen = c.u32(pos + 20).unwrap_or_default() as usize;
+                let frame = body.get(20..20 + caplen).ok_or("Truncated packet block")?;
+                let nanos = (ts % units) as u128 * 1_000_000_000 / units as u128;
+                let time = UNIX_EPOCH + Duration::from_secs(ts / units) + Duration::from_nanos(nanos as u64);
+                ret.extend(link_payload(linktype, frame, time));
+            },
+            3 => {
+                let (linktype, _) = *ifaces.first().ok_or("Packet on an undeclared interface")?;
+                let caplen = (c.u32(pos + 8).unwrap_or_default() as usize).min(body.len().saturating_sub(4));
+                let frame = body.get(4..4 + caplen).ok_or("Truncated packet block")?;
+                ret.extend(link_payload(linktype, frame, UNIX_EPOCH));
+            },
+            _ => ()
+        }
+        pos += len;
+    }
+    Ok(())
+}
+
+fn link_payload(linktype : u32, frame : &[u8], time : SystemTime) -> Option<Captured>{
+    let ip = match linktype{
+        LINKTYPE_ETHERNET => {
+            let mut at = 12;
+            while frame.get(at..at + 2)? == [0x81, 0x00] || frame.get(at..at + 2)? == [0x88, 0xa8]{
+                at += 4;
+            }
+            frame.get(at + 2..)?
+        },
+        LINKTYPE_NULL => frame.get(4..)?,
+        LINKTYPE_LINUX_SLL => frame.get(16..)?,
+        LINKTYPE_LINUX_SLL2 => frame.get(20..)?,
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 | 12 | 14 => frame,
+        _ => return None
+    };
+    let (src_ip, dst_ip, proto, transport) : (IpAddr, IpAddr, u8, &[u8]) = match ip.first()? >> 4{
+        4 => {
+            let ihl = ((ip[0] & 0xf) as usize) * 4;
+            let total = u16::from_be_bytes(ip.get(2..4)?.try_into().ok()?) as usize;
+            let src : [u8;4] = ip.get(12..16)?.try_into().ok()?;
+            let dst : [u8;4] = ip.get(16..20)?.try_into().ok()?;
+            (src.into(), dst.into(), ip[9], ip.get(ihl..total.min(ip.len()))?)
+        },
+        6 => {
+            let plen = u16::from_be_bytes(ip.get(4..6)?.try_into().ok()?) as usize;
+            let src : [u8;16] = ip.get(8..24)?.try_into().ok()?;
+            let dst : [u8;16] = ip.get(24..40)?.try_into().ok()?;
+            (src.into(), dst.into(), ip[6], ip.get(40..(40 + plen).min(ip.len()))?)
+        },
+        _ => return None
+    };
+    let sport = u16::from_be_bytes(transport.get(0..2)?.try_into().ok()?);
+    let dport = u16::from_be_bytes(transport.get(2..4)?.try_into().ok()?);
+    let data = match proto{
+        6 => transport.get(((transport.get(12)? >> 4) as usize) * 4..)?,
+        17 => transport.get(8..)?,
+        _ => return None
+    };
+    if data.is_empty(){
+        return None;
+    }
+    Some(Captured { time, src: SocketAddr::new(src_ip, sport), dst: SocketAddr::new(dst_ip, dport), data: data.to_vec() })
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn entry(direction : Direction, secs : u64, data : Vec<u8>) -> SessionEntry{
+        SessionEntry { direction, time: UNIX_EPOCH + Duration::from_micros(secs * 1_000_000 + 250), data, note: None, conn: None }
+    }
+
+    #[test]
+    fn tcp_session_round_trips(){
+        let local : SocketAddr = "10.0.0.1:50000".parse().unwrap();
+        let peer : SocketAddr = "10.0.0.2:9000".parse().unwrap();
+        let big : Vec<u8> = (0..2000u32).map(|x| x as u8).collect();
+        let file = write_pcapng(&[entry(Direction::Sent, 5, b"ping".to_vec()), entry(Direction::Recieved, 6, big.clone())], |_| (local, peer), false);
+        let got = read_capture(&file).unwrap();
+        // the reply is split into MSS sized segments
+        assert_eq!(got.len(), 3);
+        assert_eq!((got[0].src, got[0].dst, got[0].data.as_slice()), (local, peer, &b"ping"[..]));
+        assert_eq!(got[0].time, UNIX_EPOCH + Duration::from_micros(5_000_250));
+        assert_eq!((got[1].src, got[1].dst), (peer, local));
+        assert_eq!([got[1].data.clone(), got[2].data.clone()].concat(), big);
+        assert_eq!(got[1].data.len(), SEGMENT_SIZE);
+    }
+
+    #[test]
+    fn connections_are_separate_streams(){
+        let a : SocketAddr = "10.0.0.1:50000".parse().unwrap();
+        let b : SocketAddr = "10.0.0.1:50001".parse().unwrap();
+        let peer : SocketAddr = "10.0.0.2:9000".parse().unwrap();
+        let mut entries = vec![entry(Direction::Sent, 1, b"one".to_vec()), entry(Direction::Sent, 2, b"two".to_vec())];
+        entries[1].conn = Some("second".to_string());
+        let file = write_pcapng(&entries, |e| (if e.conn.is_some() { b } else { a }, peer), false);
+        let got = read_capture(&file).unwrap();
+        assert_eq!((got[0].src, got[1].src), (a, b));
+    }
+
+    #[test]
+    fn udp_over_ipv6_round_trips(){
+        let local : SocketAddr = "[::1]:4000".parse().unwrap();
+        let peer : SocketAddr = "[::1]:5000".parse().unwrap();
+        let file = write_pcapng(&[entry(Direction::Recieved, 1, vec![1, 2, 3])], |_| (local, peer), true);
+        let got = read_capture(&file).unwrap();
+        assert_eq!(got.len(), 1);
+        assert_eq!((got[0].src, got[0].dst, got[0].data.clone()), (peer, local, vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn ip_headers_have_valid_checksums(){
+        let packet = ip_packet("1.2.3.4:1".parse().unwrap(), "5.6.7.8:2".parse().unwrap(), 17, vec![0, 1, 0, 2, 0, 9, 0, 0, 42], 6);
+        assert_eq!(checksum(&[&packet[..20]]), 0);
+    }
+
+    #[test]
+    fn classic_pcap_over_ethernet(){
+        let ip = ip_packet("192.168.1.1:1234".parse().unwrap(), "192.168.1.2:80".parse().unwrap(), 17, vec![0x04, 0xd2, 0, 80, 0, 10, 0, 0, b'h', b'i'], 6);
+        let frame = [&[0u8; 12][..], &[0x08, 0x00], &ip].concat();
+        let mut file = Vec::new();
+        file.extend([0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0]);
+        file.extend([0; 8]);
+        file.extend(65535u32.to_le_bytes());
+        file.extend(LINKTYPE_ETHERNET.to_le_bytes());
+        for x in [7u32, 9, frame.len() as u32, frame.len() as u32]{
+            file.extend(x.to_le_bytes());
+        }
+        file.extend(&frame);
+        let got = read_capture(&file).unwrap();
+        assert_eq!(got.len(), 1);
+        assert_eq!(got[0].data, b"hi");
+        assert_eq!(got[0].dst, "192.168.1.2:80".parse().unwrap());
+        assert_eq!(got[0].time, UNIX_EPOCH + Duration::from_secs(7) + Duration::from_micros(9));
+    }
+
+    #[test]
+    fn bad_input_is_an_error(){
+        assert!(read_capture(b"ab").is_err());
+        assert!(read_capture(b"not a capture").is_err());
+        let mut file = write_pcapng(&[entry(Direction::Sent, 1, b"x".to_vec())], |_| ("1.1.1.1:1".parse().unwrap(), "2.2.2.2:2".parse().unwrap()), false);
+        file.truncate(file.len() - 8);
+        assert!(read_capture(&file).is_err());
+
+        let header = write_pcapng(&[], |_| ("1.1.1.1:1".parse().unwrap(), "2.2.2.2:2".parse().unwrap()), false);
+        // a simple packet block with no room for its length field
+        let mut spb = header.clone();
+        spb.extend(block(3, &[]));
+        assert!(read_capture(&spb).is_err());
+
+        // interfaces with an if_tsresol that doesn't fit in 64 bits
+        for r in [0x80 | 64, 20]{
+            let mut idb = header.clone();
+            idb.extend(block(1, &[LINKTYPE_RAW as u8, 0, 0, 0, 0, 0, 0, 0, 9, 0, 1, 0, r, 0, 0, 0, 0, 0, 0, 0]));
+            assert!(read_capture(&idb).is_err());
+        }
+    }
+
+    #[test]
+    fn fine_timestamps_dont_overflow(){
+        let mut file = write_pcapng(&[], |_| ("1.1.1.1:1".parse().unwrap(), "2.2.2.2:2".parse().unwrap()), false);
+        file.extend(block(1, &[LINKTYPE_RAW as u8, 0, 0, 0, 0, 0, 0, 0, 9, 0, 1, 0, 12, 0, 0, 0, 0, 0, 0, 0]));
+        let ts = 1_500_999_999_999_999u64;
+        let mut epb = Vec::new();
+        epb.extend(1u32.to_le_bytes());
+        epb.extend(((ts >> 32) as u32).to_le_bytes());
+        epb.extend((ts as u32).to_le_bytes());
+        epb.extend(0u32.to_le_bytes());
+        epb.extend(0u32.to_le_bytes());
+        file.extend(block(6, &epb));
+        assert!(read_capture(&file).is_ok());
+    }
+}
@@ -0,0 +1,621 @@
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::io::{self, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::time::Duration;
+
+use iced::{widget::{button, pick_list, row, text, text_input, Column}, Element, Length};
+
+use crate::framing::{FrameRule, Framing, FramingMessage};
+use crate::packet::PacketView;
+use crate::passive::{self, Fit};
+use crate::session::{Direction, SessionEntry};
+use crate::state::Message;
+use crate::variables::Variables;
+
+// Man in the middle between a client and its server. Everything is polled from
+// ticks like the load test so the UI stays live: the upstream is dialed on a
+// thread of its own and writes only go as far as the socket takes them, the
+// rest waits for the next poll. Each direction is split into messages by the
+// proxy's framing rule, or by the templates when framing by template. In the
+// log Sent is client -> server and Recieved server -> client, every message is
+// annotated with the first template that decodes it completely. A message held
+// by a Pause rule stops forwarding in both directions until it is let through,
+// so nothing overtakes it. A side that closes has what it sent passed on first,
+// then the close is passed on as a shutdown of the other side's write half,
+// and the connection ends once both directions are drained.
+
+/// Unsent bytes for one side past which nothing more is read from the other
+const MAX_PENDING : usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleDirection{
+    Both,
+    ToServer,
+    ToClient
+}
+
+impl RuleDirection{
+    const ALL : [RuleDirection; 3] = [RuleDirection::Both, RuleDirection::ToServer, RuleDirection::ToClient];
+
+    fn matches(&self, dir : Direction) -> bool{
+        match self{
+            RuleDirection::Both => true,
+            RuleDirection::ToServer => dir == Direction::Sent,
+            RuleDirection::ToClient => dir == Direction::Recieved,
+        }
+    }
+}
+
+impl Display for RuleDirection{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self{
+            RuleDirection::Both => write!(f, "Both ways"),
+            RuleDirection::ToServer => write!(f, "Client -> server"),
+            RuleDirection::ToClient => write!(f, "Server -> client"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleAction{
+    Pause,
+    Drop
+}
+
+impl RuleAction{
+    const ALL : [RuleAction; 2] = [RuleAction::Pause, RuleAction::Drop];
+}
+
+impl Display for RuleAction{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self{
+            RuleAction::Pause => write!(f, "Pause for editing"),
+            RuleAction::Drop => write!(f, "Drop"),
+        }
+    }
+}
+
+/// Which messages a rule applies to, None being any message at all
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatchChoice(pub(crate) Option<usize>, pub(crate) String);
+
+impl Display for MatchChoice{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0{
+            Some(i) => write!(f, "#{i} {}", self.1),
+            None => write!(f, "Any message"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InterceptRule{
+    pub(crate) direction : RuleDirection,
+    pub(crate) packet : Option<usize>,
+    pub(crate) action : RuleAction
+}
+
+#[derive(Debug, Clone)]
+pub enum ProxyMessage{
+    ListenEntry(String),
+    UpstreamEntry(String),
+    AddRule,
+    RuleDirection(usize, RuleDirection),
+    RulePacket(usize, MatchChoice),
+    RuleAction(usize, RuleAction),
+    RemoveRule(usize),
+    EditEntry(String),
+    Framing(FramingMessage)
+}
+
+struct Pending{
+    direction : Direction,
+    data : Vec<u8>,
+    paused : bool
+}
+
+/// How far one direction is with closing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Closing{
+    #[default]
+    Open,
+    /// The sender closed, what it sent is still going out
+    Draining,
+    /// Passed on as a shutdown of the reciever's write half
+    Shut
+}
+
+#[derive(Default)]
+pub struct Proxy{
+    /// A port, or address:port to listen on one interface only
+    pub(crate) listen_string : String,
+    pub(crate) upstream_string : String,
+    pub(crate) rules : Vec<InterceptRule>,
+    pub(crate) running : bool,
+    pub(crate) run_id : usize,
+    pub(crate) status : String,
+    /// Hex of the held message, forwarded as edited
+    pub(crate) edit_string : String,
+    /// How both directions are split into messages
+    pub(crate) framing : Framing,
+    listener : Option<TcpListener>,
+    client : Option<TcpStream>,
+    server : Option<TcpStream>,
+    /// The upstream connection for the accepted client, while it is dialed
+    dialing : Option<Receiver<io::Result<TcpStream>>>,
+    /// Read from the client and from the server, not yet split into messages
+    from_client : Framing,
+    from_server : Framing,
+    /// Forwarded but not yet taken by the socket
+    to_client : Vec<u8>,
+    to_server : Vec<u8>,
+    queue : VecDeque<Pending>,
+    /// Client -> server and server -> client
+    closing : [Closing; 2]
+}
+
+fn to_hex(dat : &[u8]) -> String{
+    dat.iter().map(|b| format!("{b:02x}")).collect::<Vec<String>>().join(" ")
+}
+
+fn parse_hex(s : &str) -> Option<Vec<u8>>{
+    let digits : String = s.chars().filter(|c| !c.is_whitespace()).collect();
+    if !digits.len().is_multiple_of(2){
+        return None;
+    }
+    (0..digits.len()).step_by(2).map(|i| u8::from_str_radix(&digits[i..i + 2], 16).ok()).collect()
+}
+
+/// Writes as much of `out` as the nonblocking (or briefly timing out) socket
+/// takes right now
+pub(crate) fn write_some(s : &mut impl Write, out : &mut Vec<u8>) -> io::Result<()>{
+    while !out.is_empty(){
+        match s.write(out){
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => { out.drain(..n); },
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => break,
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(())
+}
+
+/// Length of the next whole message in `buf`, None to wait for more. When
+/// framing by template the first template that decodes the front of the
+/// buffer says, what no template decodes goes as it arrived
+fn next_message(buf : &Framing, views : &[PacketView], vars : &Variables, idle : bool) -> io::Result<Option<usize>>{
+    if buf.buf.is_empty(){
+        return Ok(None);
+    }
+    if buf.rule != FrameRule::Template{
+        return buf.split();
+    }
+    let mut waiting = false;
+    for v in views.iter().filter(|v| !v.fields.is_empty()){
+        match passive::fit(v, &buf.buf, vars){
+            Fit::Yes(len, _) if len > 0 => return Ok(Some(len)),
+            Fit::NeedMore => waiting = true,
+            _ => ()
+        }
+    }
+    // A template still waiting on its message gets until the side goes quiet
+    Ok((!waiting || idle).then_some(buf.buf.len()))
+}
+
+impl Proxy{
+    pub fn start(&mut self, run_id : usize) -> io::Result<()>{
+        self.stop();
+        let addr = if self.listen_string.parse::<u16>().is_ok() { format!("0.0.0.0:{}", self.listen_string) } else { self.listen_string.clone() };
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        self.status = format!("Listening on {}", listener.local_addr()?);
+        self.listener = Some(listener);
+        self.running = true;
+        self.run_id = run_id;
+        Ok(())
+    }
+
+    pub fn stop(&mut self){
+        self.running = false;
+        self.listener = None;
+        self.close();
+    }
+
+    fn close(&mut self){
+        self.client = None;
+        self.server = None;
+        self.dialing = None;
+        self.from_client.clear();
+        self.from_server.clear();
+        self.to_client.clear();
+        self.to_server.clear();
+        self.queue.clear();
+        self.edit_string.clear();
+        self.closing = Default::default();
+    }
+
+    /// Bytes still to go in `dir`, queued or waiting on the socket
+    fn pending(&self, dir : Direction) -> usize{
+        let out = if dir == Direction::Sent { self.to_server.len() } else { self.to_client.len() };
+        out + self.queue.iter().filter(|p| p.direction == dir).map(|p| p.data.len()).sum::<usize>()
+    }
+
+    /// (client, upstream) of the connection being proxied
+    pub fn endpoints(&self) -> Option<(SocketAddr, SocketAddr)>{
+        Some((self.client.as_ref()?.peer_addr().ok()?, self.server.as_ref()?.peer_addr().ok()?))
+    }
+
+    pub fn held(&self) -> bool{
+        self.queue.front().is_some_and(|p| p.paused)
+    }
+
+    fn accept(&mut self) -> io::Result<bool>{
+        let Some(listener) = &self.listener else { return Ok(false) };
+        let (client, peer) = match listener.accept(){
+            Ok(x) => x,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(e)
+        };
+        client.set_nonblocking(true)?;
+        let upstream = self.upstream_string.clone();
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let res = upstream.to_socket_addrs().and_then(|mut x| x.next().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Couldnt resolve {upstream}"))))
+                .and_then(|addr| TcpStream::connect_timeout(&addr, Duration::from_secs(5)));
+            // The client may have been dropped meanwhile
+            let _ = tx.send(res);
+        });
+        self.status = format!("Connecting {peer} to {}", self.upstream_string);
+        self.client = Some(client);
+        self.dialing = Some(rx);
+        self.from_client = self.framing.clone();
+        self.from_client.clear();
+        self.from_server = self.from_client.clone();
+        Ok(true)
+    }
+
+    /// Takes the upstream connection once it is dialed. False while it is not there yet
+    fn dialed(&mut self) -> io::Result<bool>{
+        let Some(rx) = &self.dialing else { return Ok(true) };
+        let res = match rx.try_recv(){
+            Ok(x) => x,
+            Err(TryRecvError::Empty) => return Ok(false),
+            Err(TryRecvError::Disconnected) => Err(io::Error::other("Dialing upstream failed"))
+        };
+        self.dialing = None;
+        let peer = self.client.as_ref().and_then(|c| c.peer_addr().ok());
+        match res{
+            Ok(server) => {
+                server.set_nonblocking(true)?;
+                self.status = match (peer, server.peer_addr()){
+                    (Some(peer), Ok(upstream)) => format!("Proxying {peer} <-> {upstream}"),
+                    _ => "Proxying".to_string()
+                };
+                self.server = Some(server);
+                Ok(true)
+            },
+            Err(e) => {
+                // Turn the client away but keep listening
+                self.status = format!("Couldnt reach {} for {}: {e}", self.upstream_string, peer.map_or("the client".to_string(), |p| p.to_string()));
+                self.close();
+                Ok(false)
+            }
+        }
+    }
+
+    /// Moves whatever is waiting on either side. Returns the log entries and
+    /// whether anything happened, the caller polls again sooner if so
+    pub fn poll(&mut self, views : &[PacketView], vars : &Variables) -> io::Result<(Vec<SessionEntry>, bool)>{
+        let mut log = Vec::new();
+        if self.client.is_none(){
+            return Ok((log, self.accept()?));
+        }
+        if !self.dialed()?{
+            return Ok((log, false));
+        }
+        let mut active = false;
+        for (i, dir) in [Direction::Sent, Direction::Recieved].into_iter().enumerate(){
+            // Nothing more comes from a closed side, and the rest is left in the
+            // socket while the other side is behind
+            if self.closing[i] != Closing::Open || self.pending(dir) >= MAX_PENDING{
+                continue;
+            }
+            let (sock, buf, who) = match dir{
+                Direction::Sent => (self.client.as_mut(), &mut self.from_client, "Client"),
+                Direction::Recieved => (self.server.as_mut(), &mut self.from_server, "Server"),
+            };
+            let Some(sock) = sock else { break };
+            let idle = match buf.fill(sock){
+                Ok(false) => {
+                    self.status = format!("{who} closed its side, passing on what is left");
+                    self.closing[i] = Closing::Draining;
+                    true
+                },
+                Ok(true) => false,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => true,
+                Err(e) => {
+                    self.status = format!("{who} connection failed: {e}, waiting for the next client");
+                    self.close();
+                    return Ok((log, true));
+                }
+            };
+            active |= !idle || self.closing[i] != Closing::Open;
+            loop{
+                let buf = if dir == Direction::Sent { &mut self.from_client } else { &mut self.from_server };
+                let (data, note) = match next_message(buf, views, vars, idle){
+                    Ok(Some(len)) => (buf.take(len), None),
+                    // Nothing more is coming to finish the message
+                    Ok(None) if self.closing[i] != Closing::Open && !buf.buf.is_empty() => (buf.take_all(), Some("cut short by the close".to_string())),
+                    Ok(None) => break,
+                    // Out of step, pass on what is there rather than hold it forever
+                    Err(e) => (buf.take_all(), Some(format!("unframed: {e}")))
+                };
+                log.push(self.intercept(dir, data, note, views, vars));
+            }
+        }
+        self.flush()?;
+        active |= self.pass_on_close();
+        Ok((log, active))
+    }
+
+    /// Shuts the write half towards the other side of a closed side once all it
+    /// sent went out, and ends the connection when both are done. True if
+    /// anything changed
+    fn pass_on_close(&mut self) -> bool{
+        let mut changed = false;
+        for (i, dir) in [Direction::Sent, Direction::Recieved].into_iter().enumerate(){
+            if self.closing[i] != Closing::Draining || self.pending(dir) > 0{
+                continue;
+            }
+            let to = if dir == Direction::Sent { &self.server } else { &self.client };
+            // Failing means it is gone already, which the next read finds out
+            if let Some(s) = to{
+                let _ = s.shutdown(Shutdown::Write);
+            }
+            self.closing[i] = Closing::Shut;
+            changed = true;
+        }
+        if self.closing == [Closing::Shut; 2]{
+            self.status = "Both sides closed, waiting for the next client".to_string();
+            self.close();
+        }
+        changed
+    }
+
+    fn intercept(&mut self, direction : Direction, data : Vec<u8>, note : Option<String>, views : &[PacketView], vars : &Variables) -> SessionEntry{
+        let matched = views.iter().find_map(|v| v.describe(&data, vars).map(|d| (v.index, d)));
+        let rule = self.rules.iter().find(|r| r.direction.matches(direction) && r.packet.is_none_or(|p| matched.as_ref().is_some_and(|m| m.0 == p)));
+        let mut note = matched.map(|m| m.1).or(note).unwrap_or_default();
+        match rule.map(|r| r.action){
+            Some(RuleAction::Drop) => note.push_str(" [dropped]"),
+            action => {
+                let paused = action == Some(RuleAction::Pause);
+                if paused{
+                    note.push_str(" [paused]");
+                }
+                self.queue.push_back(Pending { direction, data: data.clone(), paused });
+            }
+        }
+        SessionEntry::new(direction, data).with_note((!note.is_empty()).then(|| note.trim().to_string()))
+    }
+
+    /// Forwards queued messages up to the first held one
+    fn flush(&mut self) -> io::Result<()>{
+        while let Some(p) = self.queue.front(){
+            if p.paused{
+                if self.edit_string.is_empty(){
+                    self.edit_string = to_hex(&p.data);
+                }
+                break;
+            }
+            let p = self.queue.pop_front().unwrap();
+            match p.direction{
+                Direction::Sent => self.to_server.extend(p.data),
+                Direction::Recieved => self.to_client.extend(p.data),
+            }
+        }
+        if let Some(s) = &mut self.server{
+            write_some(s, &mut self.to_server)?;
+        }
+        if let Some(s) = &mut self.client{
+            write_some(s, &mut self.to_client)?;
+        }
+        Ok(())
+    }
+
+    /// Lets the held message through as edited, logging it again if it changed
+    pub fn forward_held(&mut self) -> io::Result<Option<SessionEntry>>{
+        let Some(p) = self.queue.front_mut().filter(|p| p.paused) else { return Ok(None) };
+        let data = parse_hex(&self.edit_string).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Edited message is not valid hex"))?;
+        let edited = (data != p.data).then(|| SessionEntry::new(p.direction, data.clone()).with_note(Some("edited before forwarding".to_string())));
+        p.data = data;
+        p.paused = false;
+        self.edit_string.clear();
+        self.flush()?;
+        Ok(edited)
+    }
+
+    pub fn drop_held(&mut self) -> io::Result<()>{
+        if self.held(){
+            self.queue.pop_front();
+            self.edit_string.clear();
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn packet_removed(&mut self, idx : usize){
+        self.rules.retain(|r| r.packet != Some(idx));
+        for r in &mut self.rules{
+            if let Some(p) = r.packet.filter(|p| *p > idx){
+                r.packet = Some(p - 1);
+            }
+        }
+    }
+
+    pub fn update(&mut self, msg : ProxyMessage){
+        match msg{
+            ProxyMessage::ListenEntry(x) => self.listen_string = x,
+            ProxyMessage::UpstreamEntry(x) => self.upstream_string = x,
+            ProxyMessage::AddRule => self.rules.push(InterceptRule { direction: RuleDirection::Both, packet: None, action: RuleAction::Pause }),
+            ProxyMessage::RuleDirection(i, x) => self.rules[i].direction = x,
+            ProxyMessage::RulePacket(i, x) => self.rules[i].packet = x.0,
+            ProxyMessage::RuleAction(i, x) => self.rules[i].action = x,
+            ProxyMessage::RemoveRule(i) => { self.rules.remove(i); },
+            ProxyMessage::EditEntry(x) => if x.chars().all(|c| c.is_ascii_hexdigit() || c.is_whitespace()) { self.edit_string = x },
+            // Takes effect with the next client
+            ProxyMessage::Framing(x) => self.framing.update(x),
+        }
+    }
+
+    pub fn draw<'a>(&'a self, packets : &[PacketView]) -> Element<'a, Message>{
+        let mut choices = vec![MatchChoice(None, String::new())];
+        choices.extend(packets.iter().map(|p| MatchChoice(Some(p.index), p.lable.clone())));
+        let run = if self.running{
+            button("Stop").on_press(Message::StopProxy)
+        }
+        else{
+            button("Start").on_press(Message::StartProxy)
+        };
+        let mut col = Column::new();
+        col = col.push(
+            row![
+                text("Proxy"),
+                text_input("Listen port", &self.listen_string).on_input(|x| Message::ProxyMessage(ProxyMessage::ListenEntry(x))).width(Length::Fixed(150.0)),
+                text_input("Upstream host:port", &self.upstream_string).on_input(|x| Message::ProxyMessage(ProxyMessage::UpstreamEntry(x))).width(Length::Fixed(250.0)),
+                run,
+                button("Add intercept rule").on_press(Message::ProxyMessage(ProxyMessage::AddRule)),
+                text(&self.status)
+            ].spacing(5)
+        );
+        col = col.push(row![text("Proxy framing"), self.framing.draw(|x| Message::ProxyMessage(ProxyMessage::Framing(x)))].spacing(5));
+        for (i, r) in self.rules.iter().enumerate(){
+            let selected = choices.iter().find(|c| c.0 == r.packet).cloned();
+            col = col.push(
+                row![
+                    text("Intercept"),
+                    pick_list(RuleDirection::ALL, Some(r.direction), move |x| Message::ProxyMessage(ProxyMessage::RuleDirection(i, x))),
+                    pick_list(choices.clone(), selected, move |x| Message::ProxyMessage(ProxyMessage::RulePacket(i, x))),
+                    pick_list(RuleAction::ALL, Some(r.action), move |x| Message::ProxyMessage(ProxyMessage::RuleAction(i, x))),
+                    button("Remove").on_press(Message::ProxyMessage(ProxyMessage::RemoveRule(i)))
+                ].spacing(5)
+            );
+        }
+        if let Some(p) = self.queue.front().filter(|p| p.paused){
+            let dir = if p.direction == Direction::Sent { "client -> server" } else { "server -> client" };
+            col = col.push(
+                row![
+                    text!("Held {dir}, {} bytes", p.data.len()),
+                    text_input("Hex bytes", &self.edit_string).on_input(|x| Message::ProxyMessage(ProxyMessage::EditEntry(x))),
+                    button("Forward").on_press(Message::ForwardHeld),
+                    button("Drop").on_press(Message::DropHeld)
+                ].spacing(5)
+            );
+        }
+        col.spacing(5).into()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::io::Read;
+    use std::time::Instant;
+
+    use super::*;
+
+    /// Plain TCP upstream that sends everything back
+    fn echo_server() -> String{
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            while let Ok(n) = sock.read(&mut buf) && n > 0{
+                sock.write_all(&buf[..n]).unwrap();
+            }
+        });
+        addr
+    }
+
+    /// Polls until `n` messages were logged
+    fn run(proxy : &mut Proxy, n : usize) -> Vec<SessionEntry>{
+        let mut log = Vec::new();
+        let start = Instant::now();
+        while log.len() < n && start.elapsed() < Duration::from_secs(5){
+            log.extend(proxy.poll(&[], &Variables::new()).unwrap().0);
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        log
+    }
+
+    #[test]
+    fn frames_each_direction(){
+        let mut proxy = Proxy { listen_string: "127.0.0.1:0".to_string(), upstream_string: echo_server(), ..Default::default() };
+        proxy.framing.rule = FrameRule::Delimiter;
+        proxy.framing.delimiter_string = "\\n".to_string();
+        proxy.start(1).unwrap();
+        let mut client = TcpStream::connect(proxy.listener.as_ref().unwrap().local_addr().unwrap()).unwrap();
+        client.write_all(b"a\nb").unwrap();
+        let log = run(&mut proxy, 2);
+        // "b" has no end yet and stays with the proxy
+        assert_eq!(log.iter().map(|e| (e.direction, e.data.as_slice())).collect::<Vec<_>>(), [(Direction::Sent, &b"a\n"[..]), (Direction::Recieved, &b"a\n"[..])]);
+        client.write_all(b"\n").unwrap();
+        let log = run(&mut proxy, 2);
+        assert_eq!(log.iter().map(|e| (e.direction, e.data.as_slice())).collect::<Vec<_>>(), [(Direction::Sent, &b"b\n"[..]), (Direction::Recieved, &b"b\n"[..])]);
+        let mut got = [0; 4];
+        client.read_exact(&mut got).unwrap();
+        assert_eq!(&got, b"a\nb\n");
+    }
+
+    #[test]
+    fn upstream_close_is_passed_on_after_its_data(){
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let upstream = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            let (mut sock, _) = listener.accept().unwrap();
+            sock.write_all(b"hello\nbye").unwrap();
+        });
+        let mut proxy = Proxy { listen_string: "127.0.0.1:0".to_string(), upstream_string: upstream, ..Default::default() };
+        proxy.framing.rule = FrameRule::Delimiter;
+        proxy.framing.delimiter_string = "\\n".to_string();
+        proxy.start(1).unwrap();
+        let mut client = TcpStream::connect(proxy.listener.as_ref().unwrap().local_addr().unwrap()).unwrap();
+        let log = run(&mut proxy, 2);
+        // The unfinished message goes out too before the close
+        assert_eq!(log.iter().map(|e| e.data.as_slice()).collect::<Vec<_>>(), [&b"hello\n"[..], &b"bye"[..]]);
+        assert_eq!(log[1].note.as_deref(), Some("cut short by the close"));
+        assert_eq!(proxy.closing, [Closing::Open, Closing::Shut]);
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut got = Vec::new();
+        client.read_to_end(&mut got).unwrap();
+        assert_eq!(got, b"hello\nbye");
+        // The client can still talk until it closes too
+        assert!(proxy.client.is_some());
+        drop(client);
+        let start = Instant::now();
+        while proxy.client.is_some() && start.elapsed() < Duration::from_secs(5){
+            proxy.poll(&[], &Variables::new()).unwrap();
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(proxy.client.is_none());
+        assert_eq!(proxy.status, "Both sides closed, waiting for the next client");
+    }
+
+    #[test]
+    fn unreachable_upstream_turns_the_client_away(){
+        // Nothing listens on a port that was just freed
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let mut proxy = Proxy { listen_string: "127.0.0.1:0".to_string(), upstream_string: upstream, ..Default::default() };
+        proxy.start(1).unwrap();
+        let _client = TcpStream::connect(proxy.listener.as_ref().unwrap().local_addr().unwrap()).unwrap();
+        let start = Instant::now();
+        while !proxy.status.starts_with("Couldnt reach") && start.elapsed() < Duration::from_secs(5){
+            proxy.poll(&[], &Variables::new()).unwrap();
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(proxy.status.starts_with("Couldnt reach"), "{}", proxy.status);
+        assert!(proxy.client.is_none() && proxy.running);
+    }
+}
@@ -0,0 +1,324 @@
+use std::fmt::Display;
+use std::io;
+use std::time::Duration;
+
+use iced::{widget::{button, pick_list, row, text, text_input, Column}, Color, Element, Length};
+use jzon::{object, JsonValue};
+
+use crate::conn::ConnChoice;
+use crate::framing::Framing;
+use crate::packet::PacketView;
+use crate::state::Message;
+use crate::transport::{Connection, Handoff};
+use crate::variables::Variables;
+
+// A scenario is an ordered list of steps, each sending a PacketView or waiting
+// for one to arrive. Steps run one at a time, the delay before each step is
+// slept off the UI thread and the step itself runs on the next update. Expect
+// steps take the connection along to wait for their message off the UI thread.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepAction{
+    Send,
+    Expect
+}
+
+impl StepAction{
+    const ALL : [StepAction; 2] = [StepAction::Send, StepAction::Expect];
+}
+
+impl Display for StepAction{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self{
+            StepAction::Send => write!(f, "Send"),
+            StepAction::Expect => write!(f, "Expect"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StepStatus{
+    Pending,
+    Waiting,
+    Passed,
+    Failed(String)
+}
+
+/// Entry in the packet picker, shows the label next to the index
+#[derive(Debug, Clone, PartialEq)]
+pub struct PacketChoice(pub(crate) usize, pub(crate) String);
+
+impl Display for PacketChoice{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{} {}", self.0, self.1)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Step{
+    pub(crate) action : StepAction,
+    pub(crate) packet : Option<usize>,
+    /// Connection to use instead of the packet's own, empty to keep it
+    pub(crate) target : String,
+    pub(crate) delay_string : String,
+    pub(crate) timeout_string : String,
+    /// Rhai run before a Send or after an Expect, see scripting.rs
+    pub(crate) hook : String,
+    pub(crate) status : StepStatus
+}
+
+impl Default for Step{
+    fn default() -> Self {
+        Self { action: StepAction::Send, packet: None, target: Default::default(), delay_string: "0".to_string(), timeout_string: "1000".to_string(), hook: Default::default(), status: StepStatus::Pending }
+    }
+}
+
+impl Step{
+    pub fn delay(&self) -> Duration{
+        Duration::from_millis(self.delay_string.parse().unwrap_or_default())
+    }
+
+    /// None falls back to the connection's read timeout
+    pub fn timeout(&self) -> Option<Duration>{
+        self.timeout_string.parse::<u64>().ok().filter(|x| *x > 0).map(Duration::from_millis)
+    }
+}
+
+/// What an Expect step read, coming back with the connection it read from
+#[derive(Clone)]
+pub struct Expectation{
+    pub(crate) conn : usize,
+    pub(crate) sock : Handoff,
+    /// The connection's framing, holding whatever was read past the message
+    pub(crate) framing : Framing,
+    pub(crate) frame : Result<Vec<u8>, (io::ErrorKind, String)>
+}
+
+impl std::fmt::Debug for Expectation{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Expectation({})", self.conn)
+    }
+}
+
+/// Waits up to `timeout` for one message framed for `view`, None waiting forever
+pub fn expect(conn : usize, mut sock : Connection, mut framing : Framing, view : &PacketView, vars : &Variables, timeout : Option<Duration>) -> Expectation{
+    let frame = sock.set_read_timeout(timeout)
+        .and_then(|_| framing.next_frame(&mut sock, view, vars))
+        .map_err(|e| (e.kind(), e.to_string()));
+    Expectation { conn, sock: Handoff::new(sock), framing, frame }
+}
+
+#[derive(Debug, Clone)]
+pub enum ScMessage{
+    NameEntry(String),
+    AddStep,
+    RemoveStep(usize),
+    ActionEntry(usize, StepAction),
+    PacketEntry(usize, usize),
+    TargetEntry(usize, String),
+    DelayEntry(usize, String),
+    TimeoutEntry(usize, String),
+    HookEntry(usize, String),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Scenario{
+    pub(crate) index : usize,
+    pub(crate) name : String,
+    pub(crate) steps : Vec<Step>,
+    /// Step that runs on the next ScenarioStep message
+    pub(crate) running : Option<usize>,
+    pub(crate) run_id : usize
+}
+
+impl Scenario{
+    pub fn new(index : usize) -> Self{
+        Self { index, ..Default::default() }
+    }
+
+    pub fn update(&mut self, msg : ScMessage){
+        match msg{
+            ScMessage::NameEntry(x) => self.name = x,
+            ScMessage::AddStep => self.steps.push(Step::default()),
+            ScMessage::RemoveStep(x) => {
+                // `running` points into the steps
+                if self.running.is_none(){
+                    self.steps.remove(x);
+                }
+            },
+            ScMessage::ActionEntry(x, a) => self.steps[x].action = a,
+            ScMessage::PacketEntry(x, p) => self.steps[x].packet = Some(p),
+            ScMessage::TargetEntry(x, t) => self.steps[x].target = t,
+            ScMessage::DelayEntry(x, s) => {
+                if s.is_empty() || s.parse::<u64>().is_ok(){
+                    self.steps[x].delay_string = s;
+                }
+            },
+            ScMessage::HookEntry(x, s) => self.steps[x].hook = s,
+            ScMessage::TimeoutEntry(x, s) => {
+                if s.is_empty() || s.parse::<u64>().is_ok(){
+                    self.steps[x].timeout_string = s;
+                }
+            },
+        }
+    }
+
+    /// Keeps step references pointing at the same packets after one is removed
+    pub fn packet_removed(&mut self, idx : usize){
+        for s in self.steps.iter_mut(){
+            s.packet = match s.packet{
+                Some(p) if p == idx => None,
+                Some(p) if p > idx => Some(p - 1),
+                x => x
+            };
+        }
+    }
+
+    pub fn start(&mut self, run_id : usize){
+        self.steps.iter_mut().for_each(|s| s.status = StepStatus::Pending);
+        self.running = (!self.steps.is_empty()).then_some(0);
+        self.run_id = run_id;
+    }
+
+    pub fn stop(&mut self){
+        if let Some(i) = self.running.take(){
+            self.steps[i].status = StepStatus::Failed("Stopped".to_string());
+        }
+    }
+
+    pub fn verdict(&self) -> Option<bool>{
+        if self.running.is_some() || self.steps.is_empty(){
+            return None;
+        }
+        if self.steps.iter().any(|s| matches!(s.status, StepStatus::Failed(_))){
+            return Some(false);
+        }
+        self.steps.iter().all(|s| s.status == StepStatus::Passed).then_some(true)
+    }
+
+    pub fn draw<'a>(&'a self, packets : &[PacketView], conns : &[ConnChoice]) -> Element<'a, Message>{
+        let idx = self.index;
+        let choices : Vec<PacketChoice> = packets.iter().map(|p| PacketChoice(p.index, p.lable.clone())).collect();
+        let mut col = Column::new();
+        let verdict = match self.verdict(){
+            Some(true) => text("PASSED").color(Color::from_rgb(0.0, 0.6, 0.0)),
+            Some(false) => text("FAILED").color(Color::from_rgb(0.8, 0.0, 0.0)),
+            None if self.running.is_some() => text("Running"),
+            None => text(""),
+        };
+        let run = if self.running.is_some(){
+            button("Stop").on_press(Message::StopScenario(idx))
+        }
+        else{
+            button("Run").on_press(Message::RunScenario(idx))
+        };
+        col = col.push(
+            row![
+                text_input("Scenario name", &self.name).on_input(move |x| Message::ScenarioMessage(idx, ScMessage::NameEntry(x))).width(Length::Fixed(200.0)),
+                button("Add Step").on_press(Message::ScenarioMessage(idx, ScMessage::AddStep)),
+                run,
+                button("Remove Scenario").on_press(Message::RemoveScenario(idx)),
+                verdict
+            ].spacing(5)
+        );
+        for (i, s) in self.steps.iter().enumerate(){
+            let selected = s.packet.and_then(|p| choices.iter().find(|c| c.0 == p).cloned());
+            let status = match &s.status{
+                StepStatus::Pending => text(""),
+                StepStatus::Waiting => text("..."),
+                StepStatus::Passed => text("pass").color(Color::from_rgb(0.0, 0.6, 0.0)),
+                StepStatus::Failed(e) => text!("fail: {e}").color(Color::from_rgb(0.8, 0.0, 0.0)),
+            };
+            let mut r = row![
+                text!("{i}"),
+                pick_list(StepAction::ALL, Some(s.action), move |x| Message::ScenarioMessage(idx, ScMessage::ActionEntry(i, x))),
+                pick_list(choices.clone(), selected, move |x : PacketChoice| Message::ScenarioMessage(idx, ScMessage::PacketEntry(i, x.0))).placeholder("Packet"),
+                text_input("Delay ms", &s.delay_string).on_input(move |x| Message::ScenarioMessage(idx, ScMessage::DelayEntry(i, x))).width(Length::Fixed(100.0)),
+            ].spacing(5);
+            if conns.len() > 2{
+                let target = conns.iter().find(|c| c.0 == s.target).cloned();
+                r = r.push(pick_list(conns.to_vec(), target, move |x : ConnChoice| Message::ScenarioMessage(idx, ScMessage::TargetEntry(i, x.0))));
+            }
+            if s.action == StepAction::Expect{
+                r = r.push(text_input("Timeout ms", &s.timeout_string).on_input(move |x| Message::ScenarioMessage(idx, ScMessage::TimeoutEntry(i, x))).width(Length::Fixed(100.0)));
+            }
+            let hook = if s.action == StepAction::Send { "Before send hook" } else { "After recieve hook" };
+            r = r.push(text_input(hook, &s.hook).on_input(move |x| Message::ScenarioMessage(idx, ScMessage::HookEntry(i, x))));
+            r = r.push(button("Remove").on_press_maybe(self.running.is_none().then_some(Message::ScenarioMessage(idx, ScMessage::RemoveStep(i)))));
+            r = r.push(status);
+            col = col.push(r);
+        }
+        col.spacing(5).into()
+    }
+}
+
+impl From<StepAction> for JsonValue{
+    fn from(value : StepAction) -> Self {
+        JsonValue::String(value.to_string())
+    }
+}
+
+impl From<Step> for JsonValue{
+    fn from(value : Step) -> Self {
+        object! {
+            action: value.action,
+            packet: value.packet,
+            target: value.target,
+            delay: value.delay_string,
+            timeout: value.timeout_string,
+            hook: value.hook
+        }
+    }
+}
+
+impl From<JsonValue> for Step{
+    fn from(value : JsonValue) -> Self {
+        let action = match value["action"].as_str(){
+            Some("Expect") => StepAction::Expect,
+            _ => StepAction::Send
+        };
+        Self {
+            action,
+            packet: value["packet"].as_usize(),
+            target: value["target"].as_str().unwrap_or_default().to_string(),
+            delay_string: value["delay"].as_str().unwrap_or("0").to_string(),
+            timeout_string: value["timeout"].as_str().unwrap_or_default().to_string(),
+            hook: value["hook"].as_str().unwrap_or_default().to_string(),
+            status: StepStatus::Pending
+        }
+    }
+}
+
+impl From<Scenario> for JsonValue{
+    fn from(value : Scenario) -> Self {
+        object! {
+            name: value.name,
+            steps: value.steps
+        }
+    }
+}
+
+impl From<JsonValue> for Scenario{
+    fn from(value : JsonValue) -> Self {
+        let steps = value["steps"].members().map(|x| Step::from(x.clone())).collect();
+        Self { index: 0, name: value["name"].as_str().unwrap_or_default().to_string(), steps, running: None, run_id: 0 }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn steps_stay_while_running(){
+        let mut sc = Scenario::new(0);
+        sc.update(ScMessage::AddStep);
+        sc.update(ScMessage::AddStep);
+        sc.start(1);
+        sc.update(ScMessage::RemoveStep(1));
+        assert_eq!(sc.steps.len(), 2);
+        sc.stop();
+        sc.update(ScMessage::RemoveStep(1));
+        assert_eq!(sc.steps.len(), 1);
+    }
+}
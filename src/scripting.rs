@@ -0,0 +1,76 @@
+use rhai::{Dynamic, Engine, Map, Scope};
+
+use crate::variables::Variables;
+
+// Rhai scripts for Script fields and scenario hooks. Every script sees
+//   fields   map of field name (or index when unnamed) to its current value
+//   vars     the workspace variables, changes are written back after a hook
+//   packet   blob of the bytes before the field, only set for Script fields.
+//            Index it (`packet[i]`) for integers, iterating a blob yields u8s
+// Rhai has no file or network access of its own, the limits below stop runaway
+// scripts from hanging the UI.
+
+fn engine() -> Engine{
+    let mut engine = Engine::new();
+    engine.set_max_operations(1_000_000)
+        .set_max_call_levels(32)
+        .set_max_expr_depths(64, 32)
+        .set_max_string_size(1 << 20)
+        .set_max_array_size(1 << 16)
+        .set_max_map_size(1 << 12);
+    engine
+}
+
+fn to_dynamic(val : &str) -> Dynamic{
+    match val.parse::<i64>(){
+        Ok(x) => x.into(),
+        Err(_) => val.into()
+    }
+}
+
+pub fn field_map<I : IntoIterator<Item = (String, String)>>(fields : I) -> Map{
+    fields.into_iter().map(|(k, v)| (k.into(), to_dynamic(&v))).collect()
+}
+
+fn var_map(vars : &Variables) -> Map{
+    vars.iter().map(|(k, v)| (k.as_str().into(), v.as_str().into())).collect()
+}
+
+fn read_back(map : Map) -> impl Iterator<Item = (String, String)>{
+    map.into_iter().map(|(k, v)| (k.to_string(), v.to_string()))
+}
+
+/// Runs a Script field, the result has to be a blob, a string or an array of bytes
+pub fn eval_bytes(script : &str, fields : Map, packet : &[u8], vars : &Variables) -> Result<Vec<u8>, String>{
+    let mut scope = Scope::new();
+    scope.push("fields", fields);
+    scope.push("vars", var_map(vars));
+    scope.push("packet", Dynamic::from_blob(packet.to_vec()));
+    let res = engine().eval_with_scope::<Dynamic>(&mut scope, script).map_err(|e| e.to_string())?;
+    if res.is_blob(){
+        return Ok(res.into_blob().unwrap());
+    }
+    if res.is_string(){
+        return Ok(res.into_string().unwrap().into_bytes());
+    }
+    if res.is_array(){
+        let ints = res.into_typed_array::<i64>()?;
+        return ints.into_iter().map(|x| u8::try_from(x).map_err(|_| format!("{x} is not a byte"))).collect();
+    }
+    Err(format!("Script returned {} instead of a blob", res.type_name()))
+}
+
+/// Runs a scenario hook. Returns the fields and variables as the script left
+/// them, a hook fails the step by throwing or by returning false
+pub fn run_hook(script : &str, fields : Map, vars : &Variables) -> Result<(Vec<(String, String)>, Variables), String>{
+    let mut scope = Scope::new();
+    scope.push("fields", fields);
+    scope.push("vars", var_map(vars));
+    let res = engine().eval_with_scope::<Dynamic>(&mut scope, script).map_err(|e| e.to_string())?;
+    if res.as_bool() == Ok(false){
+        return Err("Hook returned false".to_string());
+    }
+    let fields = read_back(scope.get_value::<Map>("fields").unwrap_or_default()).collect();
+    let vars = read_back(scope.get_value::<Map>("vars").unwrap_or_default()).collect();
+    Ok((fields, vars))
+}
@@ -0,0 +1,77 @@
+use std::io;
+use std::time::Duration;
+
+use iced::{widget::{pick_list, row, text_input}, Element, Length};
+use serialport::{FlowControl, Parity, StopBits, TTYPort};
+
+use crate::state::Message;
+
+// Serial line settings for talking to devices over a UART. Always 8 data bits,
+// everything else matches what the device's firmware was built with.
+
+#[derive(Debug, Clone)]
+pub enum SerialMessage{
+    PathEntry(String),
+    BaudEntry(String),
+    Parity(Parity),
+    StopBits(StopBits),
+    FlowControl(FlowControl)
+}
+
+#[derive(Clone)]
+pub struct SerialSettings{
+    pub(crate) path : String,
+    pub(crate) baud_string : String,
+    pub(crate) parity : Parity,
+    pub(crate) stop_bits : StopBits,
+    pub(crate) flow_control : FlowControl
+}
+
+impl Default for SerialSettings{
+    fn default() -> Self {
+        Self {
+            path: Default::default(),
+            baud_string: "115200".to_string(),
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None
+        }
+    }
+}
+
+impl SerialSettings{
+    pub fn baud(&self) -> u32{
+        self.baud_string.parse().unwrap_or(115200)
+    }
+
+    pub fn open(&self) -> io::Result<TTYPort>{
+        let port = serialport::new(&self.path, self.baud())
+            .parity(self.parity)
+            .stop_bits(self.stop_bits)
+            .flow_control(self.flow_control)
+            // Reads block like a socket's until a timeout is set on the connection
+            .timeout(Duration::MAX)
+            .open_native()?;
+        Ok(port)
+    }
+
+    pub fn update(&mut self, msg : SerialMessage){
+        match msg{
+            SerialMessage::PathEntry(x) => self.path = x,
+            SerialMessage::BaudEntry(x) => if x.is_empty() || x.parse::<u32>().is_ok() { self.baud_string = x },
+            SerialMessage::Parity(x) => self.parity = x,
+            SerialMessage::StopBits(x) => self.stop_bits = x,
+            SerialMessage::FlowControl(x) => self.flow_control = x,
+        }
+    }
+
+    pub fn draw(&self) -> Element<'_, Message>{
+        row![
+            text_input("Device e.g. /dev/ttyUSB0", &self.path).on_input(|x| Message::SerialMessage(SerialMessage::PathEntry(x))),
+            text_input("Baud", &self.baud_string).on_input(|x| Message::SerialMessage(SerialMessage::BaudEntry(x))).width(Length::Fixed(100.0)),
+            pick_list([Parity::None, Parity::Odd, Parity::Even], Some(self.parity), |x| Message::SerialMessage(SerialMessage::Parity(x))),
+            pick_list([StopBits::One, StopBits::Two], Some(self.stop_bits), |x| Message::SerialMessage(SerialMessage::StopBits(x))),
+            pick_list([FlowControl::None, FlowControl::Software, FlowControl::Hardware], Some(self.flow_control), |x| Message::SerialMessage(SerialMessage::FlowControl(x))),
+        ].spacing(5).into()
+    }
+}
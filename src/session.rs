@@ -0,0 +1,63 @@
+use std::{io::{self, Read}, time::SystemTime};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction{
+    Sent,
+    Recieved
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionEntry{
+    pub direction : Direction,
+    pub time : SystemTime,
+    pub data : Vec<u8>,
+    /// Shown next to the bytes, e.g. which template a proxied message decoded as
+    pub note : Option<String>,
+    /// Name of the connection it went over, None outside the connection list e.g. for the proxy
+    pub conn : Option<String>
+}
+
+impl SessionEntry{
+    pub fn new(direction : Direction, data : Vec<u8>) -> Self{
+        Self { direction, time: SystemTime::now(), data, note: None, conn: None }
+    }
+
+    pub fn with_conn(mut self, conn : &str) -> Self{
+        self.conn = Some(conn.to_string());
+        self
+    }
+
+    pub fn with_note(mut self, note : Option<String>) -> Self{
+        self.note = note;
+        self
+    }
+
+    pub fn hex_preview(&self, max : usize) -> String{
+        let mut ret : Vec<String> = self.data.iter().take(max).map(|b| format!("{b:02x}")).collect();
+        if self.data.len() > max{
+            ret.push("..".to_string());
+        }
+        ret.join(" ")
+    }
+}
+
+/// Passes reads through while keeping a copy of every byte, so a decoded packet
+/// can be logged exactly as it came off the wire
+pub struct Recorder<'a, R : Read>{
+    inner : &'a mut R,
+    pub data : Vec<u8>
+}
+
+impl<'a, R : Read> Recorder<'a, R>{
+    pub fn new(inner : &'a mut R) -> Self{
+        Self { inner, data: Vec::new() }
+    }
+}
+
+impl<R : Read> Read for Recorder<'_, R>{
+    fn read(&mut self, buf : &mut [u8]) -> io::Result<usize>{
+        let count = self.inner.read(buf)?;
+        self.data.extend_from_slice(&buf[..count]);
+        Ok(count)
+    }
+}
@@ -1,173 +1,1166 @@
-use std::{fs::{read_to_string, File}, io::{Read, Write}, ops::{Index, IndexMut}};
+use std::{collections::HashMap, fs::{read_to_string, File}, io::{Read, Write}, ops::{Index, IndexMut}};
 
-use iced::{widget::{button, row, text, text_input, Column}, Element};
+use iced::{widget::{button, row, scrollable, text, text_input, Column}, Element, Length::{self, Fill}, Task};
 use rfd::FileDialog;
 
-use crate::packet::{PVMessage, PacketView, PacketDataType};
-use std::net::TcpStream;
+use crate::packet::{PVMessage, PacketView};
+use crate::scenario::{self, Expectation, ScMessage, Scenario, Step, StepAction, StepStatus};
+use crate::session::{Direction, SessionEntry};
+use crate::variables::{self, Variables};
+use crate::{cimport, dissector, fuzz, kaitai, pcap, scripting};
+use crate::conn::{self, Conn};
+use crate::framing::FramingMessage;
+use crate::fuzz::{Finding, FuzzMessage, Fuzzer, Probe};
+use crate::load::{self, LoadMessage, LoadStats, LoadTest};
+use crate::passive::{self, Frame, Passive};
+use crate::proxy::{self, Proxy, ProxyMessage};
+use crate::mock::{MockMessage, MockServer};
+use crate::serial::SerialMessage;
+use crate::timeouts::{self, TimeoutMessage};
+use crate::tls::TlsMessage;
+use crate::websocket::WsMessage;
+use crate::transport::{Handoff, Transport};
+use jzon::object;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
 
 #[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum Message{
     AddPacket,
     RemovePacket(usize),
     PVMessage(usize, PVMessage),
     IpEntry(String),  
     PortEntry(String),
-    Connect,
-    Disconnect,
+    Connect(usize),
+    Disconnect(usize),
     SendPacket(usize),
     RecievePacket(usize),
-    OpenPacket
+    OpenPacket,
+    ImportCHeader,
+    ImportKaitai,
+    ExportDissector,
+    ExportCapture,
+    ImportCapture,
+    ClearLog,
+    DecodeWithEntry(String),
+    DissectorPortEntry(String),
+    DecodeEntry(usize),
+    ReplayEntry(usize),
+    AddScenario,
+    RemoveScenario(usize),
+    ScenarioMessage(usize, ScMessage),
+    RunScenario(usize),
+    StopScenario(usize),
+    ScenarioStep(usize, usize),
+    SaveWorkspace,
+    OpenWorkspace,
+    NewVarEntry(String),
+    AddVariable,
+    VarValueEntry(String, String),
+    RemoveVariable(String),
+    FuzzMessage(FuzzMessage),
+    StartFuzz,
+    StopFuzz,
+    FuzzCase(usize),
+    FuzzProbed(usize, Finding, Box<Probe>),
+    ReplayFinding(usize),
+    LoadMessage(LoadMessage),
+    StartLoad,
+    StopLoad,
+    LoadTick(usize),
+    TlsMessage(TlsMessage),
+    TransportSelect(Transport),
+    UnixPathEntry(String),
+    SerialMessage(SerialMessage),
+    WsMessage(WsMessage),
+    FramingMessage(FramingMessage),
+    TimeoutMessage(TimeoutMessage),
+    ReconnectTick(usize, usize),
+    Redialed(usize, usize, Result<Handoff, String>),
+    Expected(usize, usize, Box<Expectation>),
+    ProxyMessage(ProxyMessage),
+    StartProxy,
+    StopProxy,
+    ProxyTick(usize),
+    ForwardHeld,
+    DropHeld,
+    StartPassive(usize),
+    StopPassive(usize),
+    PassiveTick(usize, usize),
+    Listen(usize),
+    StopListening(usize),
+    AcceptTick(usize, usize),
+    Accepted(usize, usize, Result<Handoff, String>),
+    AddConnection,
+    RemoveConnection(usize),
+    SelectConnection(usize),
+    DefaultConnection(usize),
+    ConnNameEntry(usize, String),
+    MockMessage(MockMessage),
+    StartMock,
+    StopMock,
+    MockTick(usize),
+    RecordMock
 }
 
-#[derive(Default)]
 pub struct State{
     packet_views : Vec<PacketView>,
-    current_ip : String,
-    current_port : String,
-    sock : Option<TcpStream>,
+    conns : Vec<Conn>,
+    // Connection the settings panel edits
+    selected : usize,
+    // Connection used by packets, steps and runs that dont name one
+    default_conn : usize,
+    session : Vec<SessionEntry>,
+    // (local, peer) last seen for each connection name, None for the proxy and
+    // imported captures, used when exporting the session
+    endpoints : HashMap<Option<String>, (SocketAddr, SocketAddr)>,
+    decode_with : String,
+    // Port the exported dissector registers on
+    dissector_port : String,
+    scenarios : Vec<Scenario>,
+    // Bumped on every scenario, fuzz, load, listen, proxy, mock or passive run so ticks scheduled by an earlier run are dropped
+    runs : usize,
+    variables : Variables,
+    new_var : String,
+    fuzzer : Fuzzer,
+    load : LoadTest,
+    proxy : Proxy,
+    mock : MockServer,
+}
+
+impl Default for State{
+    fn default() -> Self {
+        Self {
+            packet_views: Default::default(),
+            conns: vec![Conn::new("conn0".to_string())],
+            selected: 0,
+            default_conn: 0,
+            session: Default::default(),
+            endpoints: Default::default(),
+            decode_with: Default::default(),
+            dissector_port: Default::default(),
+            scenarios: Default::default(),
+            runs: 0,
+            variables: Default::default(),
+            new_var: Default::default(),
+            fuzzer: Default::default(),
+            load: Default::default(),
+            proxy: Default::default(),
+            mock: Default::default()
+        }
+    }
 }
 
 impl State{
-    pub fn update(&mut self, msg : Message){
+    pub fn update(&mut self, msg : Message) -> Task<Message>{
+        let mut task = self.handle(msg);
+        // Anything above may have found a connection gone
+        for c in 0..self.conns.len(){
+            let conn = &mut self.conns[c];
+            if std::mem::take(&mut conn.lost) && conn.timeouts.auto_reconnect && conn.outbound{
+                self.runs += 1;
+                self.conns[c].reconnect = Some((self.runs, 0));
+                task = Task::batch([task, self.schedule_reconnect(c)]);
+            }
+        }
+        task
+    }
+
+    fn handle(&mut self, msg : Message) -> Task<Message>{
         match msg{
             Message::PVMessage(i, x) => self[i].update(x),
             Message::AddPacket => self.add_packet(),
-            Message::IpEntry(x) => self.current_ip = x,
-            Message::PortEntry(x) => self.current_port = x,
-            Message::Connect => self.connect().unwrap(),
-            Message::Disconnect => self.disconnect(),
+            Message::IpEntry(x) => self.editing().current_ip = x,
+            Message::PortEntry(x) => self.editing().current_port = x,
+            Message::Connect(c) => {
+                let conn = &mut self.conns[c];
+                conn.reconnect = None;
+                match conn.connect(){
+                    Ok(_) => self.connected(c),
+                    Err(e) => {
+                        conn.status = timeouts::describe("Connect", &e);
+                        println!("Couldnt connect: {e}");
+                    }
+                }
+            },
+            Message::Listen(c) => return self.listen(c),
+            Message::StopListening(c) => self.conns[c].listener = None,
+            Message::AcceptTick(c, run) => return self.accept_tick(c, run),
+            Message::Accepted(c, run, res) => self.accepted(c, run, res),
+            Message::TlsMessage(x) => self.editing().tls.update(x),
+            Message::TransportSelect(x) => self.editing().transport = x,
+            Message::UnixPathEntry(x) => self.editing().unix_path = x,
+            Message::SerialMessage(x) => self.editing().serial.update(x),
+            Message::WsMessage(x) => self.editing().websocket.update(x),
+            Message::FramingMessage(x) => self.editing().framing.update(x),
+            Message::TimeoutMessage(x) => self.editing().timeouts.update(x),
+            Message::ReconnectTick(c, run) => return self.reconnect_tick(c, run),
+            Message::Redialed(c, run, res) => return self.redialed(c, run, res),
+            Message::Disconnect(c) => {
+                self.conns[c].reconnect = None;
+                self.conns[c].disconnect();
+            },
+            Message::AddConnection => {
+                let name = (self.conns.len()..).map(|n| format!("conn{n}")).find(|n| self.conns.iter().all(|c| &c.name != n)).unwrap();
+                self.conns.push(Conn::new(name));
+            },
+            Message::RemoveConnection(c) => {
+                if self.conns.len() > 1{
+                    self.conns[c].disconnect();
+                    self.conns.remove(c);
+                    if self.selected > c{
+                        self.selected -= 1;
+                    }
+                    self.selected = self.selected.min(self.conns.len() - 1);
+                    // Removing the default hands the role to the first connection
+                    self.default_conn = match self.default_conn{
+                        d if d == c => 0,
+                        d if d > c => d - 1,
+                        d => d
+                    };
+                }
+            },
+            Message::SelectConnection(c) => self.selected = c,
+            Message::DefaultConnection(c) => self.default_conn = c,
+            Message::ConnNameEntry(c, x) => {
+                // An empty name would read as the default connection, a taken one
+                // would merge the packets and steps of both connections
+                if !x.is_empty() && self.conns.iter().enumerate().all(|(i, conn)| i == c || conn.name != x){
+                    let old = std::mem::replace(&mut self.conns[c].name, x.clone());
+                    // Packets and steps follow the rename
+                    self.packet_views.iter_mut().filter(|v| v.target == old).for_each(|v| v.target = x.clone());
+                    self.scenarios.iter_mut().flat_map(|sc| sc.steps.iter_mut()).filter(|st| st.target == old).for_each(|st| st.target = x.clone());
+                }
+            },
             Message::RemovePacket(x) => {
                 self.packet_views.remove(x);
                 self.packet_views.iter_mut().enumerate().for_each(|(i,x)| x.index = i);
+                self.scenarios.iter_mut().for_each(|s| s.packet_removed(x));
+                self.fuzzer.packet_removed(x);
+                self.load.packet_removed(x);
+                self.proxy.packet_removed(x);
+                self.mock.packet_removed(x);
+            },
+            Message::SendPacket(x) => {
+                if let Err(e) = self.send(x){
+                    println!("Couldnt send packet {x}: {e}");
+                }
             },
-            Message::SendPacket(x) => self.send(&self.packet_views[x].clone()).unwrap(),
             Message::OpenPacket => {
                 let fpath = FileDialog::new().add_filter("json", &["json"]).pick_file().unwrap();
                 let fstr = read_to_string(fpath).unwrap();
                 let obj = jzon::parse(&fstr).unwrap();
                 self.packet_views.push(PacketView::from(obj));
             }
-            Message::RecievePacket(x) => self.recieve(x).unwrap(),
-            
+            Message::RecievePacket(x) => {
+                if let Err(e) = self.recieve(x){
+                    println!("Couldnt recieve packet {x}: {e}");
+                }
+            },
+            Message::ImportCHeader => {
+                if let Some(fpath) = FileDialog::new().add_filter("C header", &["h", "hpp"]).pick_file(){
+                    let fstr = read_to_string(fpath).unwrap();
+                    match cimport::parse_header(&fstr, self.packet_views.len()){
+                        Ok(views) => self.packet_views.extend(views),
+                        Err(e) => println!("Couldnt import C header: {e}")
+                    }
+                }
+            },
+            Message::ExportDissector => {
+                let Ok(port) = self.dissector_port.parse::<u16>() else {
+                    println!("Enter the port the dissector should be registered on");
+                    return Task::none();
+                };
+                if let Some(fpath) = FileDialog::new().add_filter("lua", &["lua"]).save_file(){
+                    let mut f = File::create(fpath).unwrap();
+                    f.write_all(dissector::lua_dissector(&self.packet_views, port).as_bytes()).unwrap();
+                }
+            },
+            Message::ExportCapture => {
+                // Connections that never came up get made up addresses, each on its own local port
+                let mut flows = self.endpoints.clone();
+                for e in &self.session{
+                    if !flows.contains_key(&e.conn){
+                        let conn = &self.conns[self.conn_index(e.conn.as_deref().unwrap_or_default())];
+                        let local = SocketAddr::from(([127, 0, 0, 1], 50000 + flows.len() as u16));
+                        let peer = format!("{}:{}", conn.current_ip, conn.current_port).parse().unwrap_or(SocketAddr::from(([127, 0, 0, 1], 9000)));
+                        flows.insert(e.conn.clone(), (local, peer));
+                    }
+                }
+                if let Some(fpath) = FileDialog::new().add_filter("pcapng", &["pcapng"]).save_file(){
+                    let file = pcap::write_pcapng(&self.session, |e| flows[&e.conn], false);
+                    if let Err(e) = std::fs::write(fpath, file){
+                        println!("Couldnt export capture: {e}");
+                    }
+                }
+            },
+            Message::ImportCapture => {
+                if let Some(fpath) = FileDialog::new().add_filter("capture", &["pcap", "pcapng", "cap"]).pick_file(){
+                    match pcap::read_capture(&std::fs::read(fpath).unwrap()){
+                        Ok(packets) => {
+                            // Whoever spoke first is treated as our side of the conversation
+                            let Some(first) = packets.first() else { return Task::none() };
+                            let local = first.src;
+                            self.endpoints = HashMap::from([(None, (first.src, first.dst))]);
+                            self.session = packets.into_iter().map(|p| SessionEntry{
+                                direction: if p.src == local { Direction::Sent } else { Direction::Recieved },
+                                time: p.time,
+                                data: p.data,
+                                note: None,
+                                conn: None
+                            }).collect();
+                        },
+                        Err(e) => println!("Couldnt import capture: {e}")
+                    }
+                }
+            },
+            Message::ClearLog => self.session.clear(),
+            Message::DissectorPortEntry(x) => {
+                if x.is_empty() || x.parse::<u16>().is_ok(){
+                    self.dissector_port = x;
+                }
+            },
+            Message::DecodeWithEntry(x) => {
+                if x.is_empty() || x.parse::<usize>().is_ok(){
+                    self.decode_with = x;
+                }
+            },
+            Message::DecodeEntry(x) => {
+                let Some(packet) = self.decode_with.parse::<usize>().ok().and_then(|i| self.packet_views.get_mut(i)) else {
+                    println!("Enter the index of the packet to decode with");
+                    return Task::none();
+                };
+                match packet.read_from(&mut self.session[x].data.as_slice(), &self.variables){
+                    Ok(()) => {
+                        packet.check(&self.variables);
+                        self.variables.extend(packet.captures());
+                    },
+                    Err(e) => println!("Couldnt decode entry {x} as packet {}: {e}", packet.index)
+                }
+            },
+            Message::ReplayEntry(x) => {
+                // Back over the connection it went over the first time
+                let c = self.conn_index(self.session[x].conn.as_deref().unwrap_or_default());
+                let dat = self.session[x].data.clone();
+                if let Err(e) = self.send_bytes(c, dat){
+                    println!("Couldnt replay entry {x}: {e}");
+                }
+            },
+            Message::ImportKaitai => {
+                if let Some(fpath) = FileDialog::new().add_filter("Kaitai Struct", &["ksy"]).pick_file(){
+                    let fstr = read_to_string(fpath).unwrap();
+                    match kaitai::import_ksy(&fstr, self.packet_views.len()){
+                        Ok(views) => self.packet_views.extend(views),
+                        Err(e) => println!("Couldnt import Kaitai spec: {e}")
+                    }
+                }
+            },
+            Message::AddScenario => self.scenarios.push(Scenario::new(self.scenarios.len())),
+            Message::RemoveScenario(x) => {
+                self.scenarios.remove(x);
+                self.scenarios.iter_mut().enumerate().for_each(|(i,x)| x.index = i);
+            },
+            Message::ScenarioMessage(i, x) => self.scenarios[i].update(x),
+            Message::RunScenario(x) => {
+                self.runs += 1;
+                self.scenarios[x].start(self.runs);
+                return self.schedule_step(x);
+            },
+            Message::StopScenario(x) => self.scenarios[x].stop(),
+            Message::ScenarioStep(x, run) => return self.scenario_step(x, run),
+            Message::Expected(x, run, e) => return self.expected(x, run, *e),
+            Message::SaveWorkspace => {
+                if let Some(fpath) = FileDialog::new().add_filter("json", &["json"]).save_file(){
+                    let obj = object! {
+                        packets: self.packet_views.clone(),
+                        scenarios: self.scenarios.clone(),
+                        variables: self.variables.clone()
+                    };
+                    let mut f = File::create(fpath).unwrap();
+                    f.write_all(jzon::stringify_pretty(obj, 2).as_bytes()).unwrap();
+                }
+            },
+            Message::OpenWorkspace => {
+                if let Some(fpath) = FileDialog::new().add_filter("json", &["json"]).pick_file(){
+                    let fstr = read_to_string(fpath).unwrap();
+                    let obj = match jzon::parse(&fstr){
+                        Ok(x) => x,
+                        Err(e) => {
+                            println!("Couldnt open workspace: {e}");
+                            return Task::none();
+                        }
+                    };
+                    self.packet_views = obj["packets"].members().map(|x| PacketView::from(x.clone())).collect();
+                    self.packet_views.iter_mut().enumerate().for_each(|(i,x)| x.index = i);
+                    self.scenarios = obj["scenarios"].members().map(|x| Scenario::from(x.clone())).collect();
+                    self.scenarios.iter_mut().enumerate().for_each(|(i,x)| x.index = i);
+                    self.variables = obj["variables"].entries().map(|(k, v)| (k.to_string(), v.as_str().unwrap_or_default().to_string())).collect();
+                }
+            },
+            Message::NewVarEntry(x) => {
+                if x.is_empty() || variables::is_valid_name(&x){
+                    self.new_var = x;
+                }
+            },
+            Message::AddVariable => {
+                if !self.new_var.is_empty(){
+                    self.variables.entry(std::mem::take(&mut self.new_var)).or_default();
+                }
+            },
+            Message::VarValueEntry(k, v) => {
+                self.variables.insert(k, v);
+            },
+            Message::RemoveVariable(k) => {
+                self.variables.remove(&k);
+            },
+            Message::FuzzMessage(x) => self.fuzzer.update(x),
+            Message::StartFuzz => {
+                if self.fuzzer.packet.is_none(){
+                    println!("Select the packet to fuzz");
+                    return Task::none();
+                }
+                self.runs += 1;
+                self.fuzzer.run_id = self.runs;
+                self.fuzzer.running = (self.fuzzer.cases() > 0).then_some(0);
+                return Task::done(Message::FuzzCase(self.fuzzer.run_id));
+            },
+            Message::StopFuzz => self.fuzzer.running = None,
+            Message::FuzzCase(run) => return self.fuzz_case(run),
+            Message::FuzzProbed(run, finding, probe) => return self.fuzz_probed(run, finding, *probe),
+            Message::LoadMessage(x) => self.load.update(x),
+            Message::StartLoad => {
+                if self.load.packet.filter(|p| *p < self.packet_views.len()).is_none_or(|p| self.conns[self.packet_conn(p)].sock.is_none()){
+                    println!("Connect and select the packet to send first");
+                    return Task::none();
+                }
+                self.runs += 1;
+                self.load.run_id = self.runs;
+                self.load.running = true;
+                self.load.stats = Some(LoadStats::new());
+                self.load.awaiting = None;
+                self.load.unsent.clear();
+                return Task::done(Message::LoadTick(self.load.run_id));
+            },
+            Message::StopLoad => self.stop_load(),
+            Message::ProxyMessage(x) => self.proxy.update(x),
+            Message::StartProxy => {
+                self.runs += 1;
+                match self.proxy.start(self.runs){
+                    Ok(_) => return Task::done(Message::ProxyTick(self.runs)),
+                    Err(e) => println!("Couldnt start proxy: {e}")
+                }
+            },
+            Message::StopProxy => {
+                self.proxy.stop();
+                self.proxy.status.clear();
+            },
+            Message::ProxyTick(run) => return self.proxy_tick(run),
+            Message::StartPassive(c) => {
+                if self.conns[c].sock.is_some(){
+                    self.runs += 1;
+                    self.conns[c].passive = Passive { running: true, run_id: self.runs, ..Default::default() };
+                    return Task::done(Message::PassiveTick(c, self.runs));
+                }
+            },
+            Message::StopPassive(c) => self.conns[c].passive.running = false,
+            Message::PassiveTick(c, run) => return self.passive_tick(c, run),
+            Message::ForwardHeld => match self.proxy.forward_held(){
+                Ok(entry) => self.session.extend(entry),
+                Err(e) => println!("Couldnt forward message: {e}")
+            },
+            Message::DropHeld => {
+                if let Err(e) = self.proxy.drop_held(){
+                    println!("Couldnt forward message: {e}");
+                }
+            },
+            Message::LoadTick(run) => return self.load_tick(run),
+            Message::MockMessage(x) => self.mock.update(x),
+            Message::StartMock => {
+                self.runs += 1;
+                match self.mock.start(self.runs, &self.packet_views, &self.variables){
+                    Ok(_) => return Task::done(Message::MockTick(self.runs)),
+                    Err(e) => println!("Couldnt start mock server: {e}")
+                }
+            },
+            Message::StopMock => {
+                self.mock.stop();
+                self.mock.status.clear();
+            },
+            Message::MockTick(run) => return self.mock_tick(run),
+            Message::RecordMock => self.mock.record(&self.session),
+            Message::ReplayFinding(x) => {
+                let c = self.fuzzer.packet.filter(|p| *p < self.packet_views.len()).map_or(self.default_conn, |p| self.packet_conn(p));
+                let dat = self.fuzzer.findings[x].data.clone();
+                if let Err(e) = self.send_bytes(c, dat){
+                    println!("Couldnt replay finding {x}: {e}");
+                }
+            },
+        };
+        Task::none()
+    }
+
+    /// Sleeps off the delay of the scenario's current step, then runs it
+    fn schedule_step(&mut self, x : usize) -> Task<Message>{
+        let sc = &mut self.scenarios[x];
+        let Some(i) = sc.running else { return Task::none() };
+        let run = sc.run_id;
+        sc.steps[i].status = StepStatus::Waiting;
+        let delay = sc.steps[i].delay();
+        Task::perform(async move { std::thread::sleep(delay) }, move |_| Message::ScenarioStep(x, run))
+    }
+
+    fn scenario_step(&mut self, x : usize, run : usize) -> Task<Message>{
+        let Some(sc) = self.scenarios.get(x).filter(|s| s.run_id == run) else { return Task::none() };
+        let Some(i) = sc.running.filter(|i| *i < sc.steps.len()) else { return Task::none() };
+        let step = sc.steps[i].clone();
+        match self.run_step(x, run, &step){
+            Ok(Some(wait)) => wait,
+            Ok(None) => self.step_done(x, Ok(())),
+            Err(e) => self.step_done(x, Err(e))
+        }
+    }
+
+    /// Moves the scenario on past its current step, or stops it there on an error
+    fn step_done(&mut self, x : usize, res : Result<(), String>) -> Task<Message>{
+        let sc = &mut self.scenarios[x];
+        let Some(i) = sc.running else { return Task::none() };
+        match res{
+            Ok(()) => {
+                sc.steps[i].status = StepStatus::Passed;
+                sc.running = (i + 1 < sc.steps.len()).then_some(i + 1);
+            },
+            Err(e) => {
+                sc.steps[i].status = StepStatus::Failed(e);
+                sc.running = None;
+            }
+        }
+        self.schedule_step(x)
+    }
+
+    fn expected(&mut self, x : usize, run : usize, e : Expectation) -> Task<Message>{
+        let Some(conn) = self.conns.get_mut(e.conn) else { return Task::none() };
+        // Unless it was connected again by hand meanwhile
+        let Some(s) = e.sock.take().filter(|_| conn.sock.is_none()) else {
+            let msg = format!("{} was reconnected while waiting", conn.name);
+            return self.expect_failed(x, run, msg);
+        };
+        conn.sock = Some(s);
+        conn.framing.rejoin(e.framing);
+        if let Err(e) = conn.restore_read_timeout(){
+            println!("Couldnt restore the connection: {e}");
+        }
+        let frame = e.frame.map_err(|(kind, msg)| std::io::Error::new(kind, msg));
+        let step = self.scenarios.get(x).filter(|s| s.run_id == run).and_then(|s| s.steps.get(s.running?)).cloned();
+        let Some((hook, p)) = step.and_then(|s| Some((s.hook, s.packet.filter(|p| *p < self.packet_views.len())?))) else {
+            // Stopped meanwhile, the message is still part of the session
+            let conn = &mut self.conns[e.conn];
+            match frame{
+                Ok(dat) => self.session.push(SessionEntry::new(Direction::Recieved, dat).with_conn(&conn.name)),
+                Err(e) => conn.io_failed("Recieve", &e)
+            }
+            return Task::none();
+        };
+        let res = self.recieved(e.conn, p, frame).map_err(|e| match e.kind(){
+            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => "Timed out".to_string(),
+            _ => e.to_string()
+        }).and_then(|_| {
+            let failed : Vec<String> = self.packet_views[p].checks.iter().filter(|c| !c.passed).map(|c| c.field.to_string()).collect();
+            if !failed.is_empty(){
+                return Err(format!("Mismatch in field {}", failed.join(", ")));
+            }
+            self.run_hook(p, &hook)
+        });
+        self.step_done(x, res)
+    }
+
+    /// Fails the Expect step of run `run` if that is still what the scenario is on
+    fn expect_failed(&mut self, x : usize, run : usize, e : String) -> Task<Message>{
+        if self.scenarios.get(x).is_some_and(|s| s.run_id == run){
+            return self.step_done(x, Err(e));
+        }
+        Task::none()
+    }
+
+    /// Sends one mutated packet and reports anything that looks like the peer choking on it
+    fn fuzz_case(&mut self, run : usize) -> Task<Message>{
+        let Some(case) = self.fuzzer.running.filter(|_| self.fuzzer.run_id == run) else { return Task::none() };
+        let Some(p) = self.fuzzer.packet.filter(|p| *p < self.packet_views.len()) else {
+            self.fuzzer.running = None;
+            return Task::none();
+        };
+        let c = self.packet_conn(p);
+        let (data, mutation) = match fuzz::mutate(&self.packet_views[p], &self.variables, self.fuzzer.seed(), case){
+            Ok(x) => x,
+            Err(e) => {
+                println!("Couldnt fuzz packet {p}: {e}");
+                self.fuzzer.running = None;
+                return Task::none();
+            }
+        };
+        // The connection goes along with the case and comes back with the result
+        let conn = &mut self.conns[c];
+        let (sock, dial) = (conn.sock.take(), conn.dialer());
+        let (timeout, expect_reply) = (self.fuzzer.timeout(), self.fuzzer.expect_reply);
+        let finding = Finding { case, mutation, data, outcome: String::new() };
+        Task::perform(
+            async move {
+                let probe = fuzz::probe(c, sock, dial, &finding.data, timeout, expect_reply);
+                (finding, probe)
+            },
+            move |(finding, probe)| Message::FuzzProbed(run, finding, Box::new(probe))
+        )
+    }
+
+    fn fuzz_probed(&mut self, run : usize, mut finding : Finding, probe : Probe) -> Task<Message>{
+        let Some(conn) = self.conns.get_mut(probe.conn) else { return Task::none() };
+        // Unless it was connected again by hand meanwhile
+        if let Some(s) = probe.sock.take().filter(|_| conn.sock.is_none()){
+            let res = if probe.reconnected{
+                conn.attach(s, true)
+            }
+            else{
+                conn.sock = Some(s);
+                conn.restore_read_timeout()
+            };
+            match res{
+                Ok(_) if probe.reconnected => self.connected(probe.conn),
+                Ok(_) => (),
+                Err(e) => println!("Couldnt restore the connection: {e}")
+            }
+        }
+        let name = &self.conns[probe.conn].name;
+        if probe.sent{
+            self.session.push(SessionEntry::new(Direction::Sent, finding.data.clone()).with_conn(name));
+        }
+        if let Some(reply) = probe.reply{
+            self.session.push(SessionEntry::new(Direction::Recieved, reply).with_conn(name));
+        }
+        if run != self.fuzzer.run_id{
+            return Task::none();
+        }
+        let case = finding.case;
+        if let Some(outcome) = probe.outcome{
+            finding.outcome = outcome;
+            self.fuzzer.findings.push(finding);
+        }
+        // Stopped while the case was out
+        if self.fuzzer.running != Some(case){
+            return Task::none();
+        }
+        self.fuzzer.running = (!probe.fatal && case + 1 < self.fuzzer.cases()).then_some(case + 1);
+        Task::done(Message::FuzzCase(run))
+    }
+
+    /// Sends whatever the load test's rate allows right now. Load traffic stays
+    /// out of the session log, it would swamp it
+    fn load_tick(&mut self, run : usize) -> Task<Message>{
+        if !self.load.running || self.load.run_id != run{
+            return Task::none();
+        }
+        let (Some(p), Some(mut stats)) = (self.load.packet.filter(|p| *p < self.packet_views.len()), self.load.stats.take()) else {
+            self.load.running = false;
+            return Task::none();
+        };
+        let c = self.packet_conn(p);
+        let count = self.load.count();
+        let tick = Instant::now();
+        while tick.elapsed() < load::TICK_BUDGET{
+            if self.load.awaiting.is_some(){
+                match self.load_reply(c, &mut stats){
+                    Ok(true) => continue,
+                    Ok(false) => break,
+                    Err(e) => {
+                        stats.error = Some(e);
+                        self.load.running = false;
+                        break;
+                    }
+                }
+            }
+            if self.load.unsent.is_empty(){
+                if (count != 0 && stats.sent >= count) || self.load.next_due(&stats).is_some(){
+                    break;
+                }
+                self.load.unsent = match self.packet_views[p].to_bytes(&self.variables){
+                    Ok(x) => x,
+                    Err(e) => {
+                        stats.error = Some(e);
+                        self.load.running = false;
+                        break;
+                    }
+                };
+            }
+            match self.load_send(c, &mut stats){
+                Ok(true) => (),
+                // The socket is full, the rest goes on a later tick
+                Ok(false) => break,
+                Err(e) => {
+                    stats.error = Some(e);
+                    self.load.running = false;
+                    break;
+                }
+            }
+        }
+        let delay = if self.load.awaiting.is_some() { None } else { self.load.next_due(&stats) };
+        let finished = count != 0 && stats.sent >= count && self.load.awaiting.is_none();
+        self.load.stats = Some(stats);
+        if finished || !self.load.running{
+            self.stop_load();
+            return Task::none();
+        }
+        match delay{
+            // Wake up at least every 100ms so the stats keep moving
+            Some(d) => Task::perform(async move { std::thread::sleep(d.min(Duration::from_millis(100))) }, move |_| Message::LoadTick(run)),
+            None => Task::done(Message::LoadTick(run))
+        }
+    }
+
+    fn mock_tick(&mut self, run : usize) -> Task<Message>{
+        if !self.mock.running || run != self.mock.run_id{
+            return Task::none();
+        }
+        if self.mock.poll(){
+            Task::perform(async { std::thread::sleep(Duration::from_millis(100)) }, move |_| Message::MockTick(run))
+        }
+        else{
+            Task::none()
+        }
+    }
+
+    fn proxy_tick(&mut self, run : usize) -> Task<Message>{
+        if !self.proxy.running || run != self.proxy.run_id{
+            return Task::none();
+        }
+        match self.proxy.poll(&self.packet_views, &self.variables){
+            Ok((entries, active)) => {
+                self.session.extend(entries);
+                if let Some(e) = self.proxy.endpoints(){
+                    self.endpoints.insert(None, e);
+                }
+                if active{
+                    Task::done(Message::ProxyTick(run))
+                }
+                else{
+                    Task::perform(async { std::thread::sleep(Duration::from_millis(20)) }, move |_| Message::ProxyTick(run))
+                }
+            },
+            Err(e) => {
+                self.proxy.stop();
+                self.proxy.status = format!("Stopped: {e}");
+                Task::none()
+            }
+        }
+    }
+
+    fn passive_tick(&mut self, c : usize, run : usize) -> Task<Message>{
+        let Some(conn) = self.conns.get_mut(c).filter(|x| x.passive.running && x.passive.run_id == run) else { return Task::none() };
+        let Some(s) = &mut conn.sock else {
+            conn.passive.running = false;
+            return Task::none();
+        };
+        let (idle, closed) = if let Some(e) = conn.passive.closed.take(){
+            (true, Some(e))
+        }
+        else if std::mem::take(&mut conn.passive.backlog){
+            (false, None)
+        }
+        else{
+            // Short timeout so the UI gets a turn between reads
+            let res = s.set_read_timeout(Some(Duration::from_millis(5))).and_then(|_| conn.framing.fill(s));
+            let _ = s.set_read_timeout(conn.timeouts.read());
+            match res{
+                Ok(false) => (true, Some(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))),
+                Ok(true) => (false, None),
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => (true, None),
+                Err(e) => {
+                    println!("Couldnt recieve: {e}");
+                    (true, Some(e))
+                }
+            }
         };
+        let tick = Instant::now();
+        loop{
+            if tick.elapsed() >= passive::TICK_BUDGET{
+                conn.passive.backlog = true;
+                break;
+            }
+            match passive::next_frame(&self.packet_views, &conn.framing, &self.variables, idle, tick + passive::TICK_BUDGET){
+                Frame::Matched(idx, len, summary) => {
+                    let dat = conn.framing.take(len);
+                    // Show the values in the template like a manual recieve would
+                    let view = &mut self.packet_views[idx];
+                    if view.decode(&mut std::io::Cursor::new(&dat), &self.variables).is_ok(){
+                        view.check(&self.variables);
+                        self.variables.extend(view.captures());
+                    }
+                    self.session.push(SessionEntry::new(Direction::Recieved, dat).with_note(Some(summary)).with_conn(&conn.name));
+                    conn.passive.matched += 1;
+                },
+                Frame::Unknown(len) => {
+                    let dat = conn.framing.take(len);
+                    self.session.push(SessionEntry::new(Direction::Recieved, dat).with_note(Some("unknown".to_string())).with_conn(&conn.name));
+                    conn.passive.unknown += 1;
+                },
+                Frame::NeedMore => break
+            }
+        }
+        if conn.passive.backlog{
+            conn.passive.closed = closed;
+            return Task::done(Message::PassiveTick(c, run));
+        }
+        if let Some(e) = closed{
+            if !conn.framing.buf.is_empty(){
+                let dat = conn.framing.take_all();
+                self.session.push(SessionEntry::new(Direction::Recieved, dat).with_note(Some("unknown, cut off by the peer closing".to_string())).with_conn(&conn.name));
+                conn.passive.unknown += 1;
+            }
+            conn.passive.running = false;
+            conn.lose(timeouts::describe("Recieve", &e));
+            return Task::none();
+        }
+        if idle{
+            Task::perform(async { std::thread::sleep(Duration::from_millis(20)) }, move |_| Message::PassiveTick(c, run))
+        }
+        else{
+            Task::done(Message::PassiveTick(c, run))
+        }
+    }
+
+    fn stop_load(&mut self){
+        self.load.running = false;
+        if let Some(stats) = &mut self.load.stats{
+            stats.end.get_or_insert_with(Instant::now);
+        }
+    }
+
+    /// Writes what the socket takes of the packet going out. True once all of
+    /// it is gone, false while the socket pushes back
+    fn load_send(&mut self, c : usize, stats : &mut LoadStats) -> Result<bool, String>{
+        let conn = &mut self.conns[c];
+        let s = conn.sock.as_mut().ok_or("Not connected")?;
+        let before = self.load.unsent.len();
+        s.set_write_timeout(Some(load::WRITE_POLL)).map_err(|e| e.to_string())?;
+        let res = proxy::write_some(s, &mut self.load.unsent);
+        s.set_write_timeout(conn.timeouts.write()).map_err(|e| e.to_string())?;
+        res.map_err(|e| e.to_string())?;
+        stats.bytes += (before - self.load.unsent.len()) as u64;
+        if !self.load.unsent.is_empty(){
+            return Ok(false);
+        }
+        stats.sent += 1;
+        if self.load.wait_reply{
+            self.load.awaiting = Some(Instant::now());
+        }
+        Ok(true)
+    }
+
+    /// Reads for the awaited reply a little while. True once it arrived or
+    /// timed out and the next packet may go
+    fn load_reply(&mut self, c : usize, stats : &mut LoadStats) -> Result<bool, String>{
+        let Some(sent_at) = self.load.awaiting else { return Ok(true) };
+        let conn = &mut self.conns[c];
+        let s = conn.sock.as_mut().ok_or("Not connected")?;
+        let Some(left) = self.load.timeout().checked_sub(sent_at.elapsed()).filter(|d| !d.is_zero()) else {
+            stats.timeouts += 1;
+            self.load.awaiting = None;
+            return Ok(true);
+        };
+        let mut buf = [0; 4096];
+        s.set_read_timeout(Some(left.min(load::REPLY_POLL))).map_err(|e| e.to_string())?;
+        let res = s.read(&mut buf);
+        s.set_read_timeout(conn.timeouts.read()).map_err(|e| e.to_string())?;
+        match res{
+            Ok(0) => Err("Peer closed the connection".to_string()),
+            Ok(_) => {
+                stats.add_rtt(sent_at.elapsed());
+                self.load.awaiting = None;
+                Ok(true)
+            },
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => Ok(false),
+            Err(e) => Err(e.to_string())
+        }
+    }
+
+    /// Lets a scenario hook read and change the packet's values and the variables
+    fn run_hook(&mut self, p : usize, hook : &str) -> Result<(), String>{
+        if hook.trim().is_empty(){
+            return Ok(());
+        }
+        let packet = &mut self.packet_views[p];
+        let fields = scripting::field_map(
+            packet.fields.iter().filter(|f| f.datatype.is_some_and(|d| !d.is_filler())).map(|f| (f.key(), f.data_string.clone()))
+        );
+        let (fields, vars) = scripting::run_hook(hook, fields, &self.variables)?;
+        for (k, v) in fields{
+            if let Some(f) = packet.fields.iter_mut().find(|f| f.key() == k){
+                f.data_string = v;
+            }
+        }
+        self.variables = vars;
+        Ok(())
+    }
+
+    /// Runs a Send step right away, an Expect step hands back the task waiting for its message
+    fn run_step(&mut self, x : usize, run : usize, step : &Step) -> Result<Option<Task<Message>>, String>{
+        let p = step.packet.filter(|p| *p < self.packet_views.len()).ok_or("No packet selected")?;
+        let c = if step.target.is_empty() { self.packet_conn(p) } else { self.conn_index(&step.target) };
+        if self.conns[c].sock.is_none(){
+            return Err(format!("{} is not connected", self.conns[c].name));
+        }
+        match step.action{
+            StepAction::Send => {
+                self.run_hook(p, &step.hook)?;
+                self.send_on(c, p).map_err(|e| e.to_string())?;
+                Ok(None)
+            },
+            StepAction::Expect => {
+                // The connection goes along with the step and comes back with the message
+                let conn = &mut self.conns[c];
+                let Some(sock) = conn.sock.take() else { return Err(format!("{} is not connected", conn.name)) };
+                let framing = conn.framing.clone();
+                conn.framing.clear();
+                let timeout = step.timeout().or(conn.timeouts.read());
+                let (view, vars) = (self.packet_views[p].clone(), self.variables.clone());
+                Ok(Some(Task::perform(
+                    async move { scenario::expect(c, sock, framing, &view, &vars, timeout) },
+                    move |e| Message::Expected(x, run, Box::new(e))
+                )))
+            }
+        }
     }
     pub fn add_packet(&mut self){
         self.packet_views.push(PacketView::new(self.packet_views.len()));
     }
-    pub fn draw(&self) -> Element<Message>{
+    pub fn draw(&self) -> Element<'_, Message>{
         let mut col = Column::new();
         col = col.push(
             row(
                 [
                     button("New Packet").on_press(Message::AddPacket).into(),
                     button("Open Packet").on_press(Message::OpenPacket).into(),
-                    
+                    button("Import C Header").on_press(Message::ImportCHeader).into(),
+                    button("Import Kaitai").on_press(Message::ImportKaitai).into(),
+                    text_input("Dissector port", &self.dissector_port).on_input(Message::DissectorPortEntry).width(Length::Fixed(120.0)).into(),
+                    button("Export Dissector").on_press(Message::ExportDissector).into(),
+                    button("Import Capture").on_press(Message::ImportCapture).into(),
+                    button("New Scenario").on_press(Message::AddScenario).into(),
+                    button("Open Workspace").on_press(Message::OpenWorkspace).into(),
+                    button("Save Workspace").on_press(Message::SaveWorkspace).into(),
                 ]
             ).spacing(5)
         );
-        let conn_row = {
-            if self.sock.is_none() {
-                row(
-                    [
-                            button("Connect").on_press(Message::Connect).into(),
-                        ]
-                    )
-            }
-            else {
-                row(
-                    [
-                            button("Disconnect").on_press(Message::Disconnect).into(),
-                            text!("Connected to: {}:{}", self.current_ip, self.current_port).into()
-                        ]
-                    ).spacing(5)
-            }
-        };
-
+        let mut conns = Column::new();
+        for (i, c) in self.conns.iter().enumerate(){
+            conns = conns.push(c.draw(i, i == self.selected, i == self.default_conn, self.conns.len() > 1));
+        }
+        conns = conns.push(button("New Connection").on_press(Message::AddConnection));
+        col = col.push(conns.spacing(5));
+        col = col.push(self.conns[self.selected].draw_settings());
+        let choices = conn::choices(&self.conns);
+        for v in &self.packet_views{
+            col = col.push(v.draw(&choices));
+        }
+        for sc in &self.scenarios{
+            col = col.push(sc.draw(&self.packet_views, &choices));
+        }
+        col = col.push(self.draw_variables());
+        col = col.push(self.load.draw(&self.packet_views));
+        col = col.push(self.fuzzer.draw(&self.packet_views));
+        col = col.push(self.proxy.draw(&self.packet_views));
+        col = col.push(self.mock.draw(&self.packet_views));
+        col = col.push(self.draw_log());
+        col.spacing(10).into()
+    }
+    fn draw_variables(&self) -> Element<'_, Message>{
+        let mut col = Column::new();
         col = col.push(
-            conn_row
+            row![
+                text("Variables"),
+                text_input("Variable name", &self.new_var).on_input(Message::NewVarEntry).on_submit(Message::AddVariable).width(Length::Fixed(200.0)),
+                button("Add").on_press(Message::AddVariable)
+            ].spacing(5)
         );
+        for (k, v) in &self.variables{
+            col = col.push(
+                row![
+                    text!("${{{k}}}").width(Length::Fixed(200.0)),
+                    text_input("Value", v).on_input(move |x| Message::VarValueEntry(k.clone(), x)),
+                    button("Remove").on_press(Message::RemoveVariable(k.clone()))
+                ].spacing(5)
+            );
+        }
+        col.spacing(5).into()
+    }
+    fn draw_log(&self) -> Element<'_, Message>{
+        let mut col = Column::new();
         col = col.push(
-            row(
-                [
-                    text_input("Input IP", &self.current_ip).on_input(Message::IpEntry).into(),
-                    text_input("Input Port", &self.current_port).on_input(Message::PortEntry).into()
-                ]
-            ).spacing(5)
+            row![
+                text("Session log"),
+                button("Clear").on_press(Message::ClearLog),
+                button("Export Capture").on_press(Message::ExportCapture),
+                text_input("Decode with packet #", &self.decode_with).on_input(Message::DecodeWithEntry).width(Length::Fixed(200.0))
+            ].spacing(5)
         );
-        for v in &self.packet_views{
-            col = col.push(v.draw());
+        let start = self.session.first().map(|e| e.time).unwrap_or(UNIX_EPOCH);
+        let mut entries = Column::new();
+        for (i, e) in self.session.iter().enumerate(){
+            let elapsed = e.time.duration_since(start).unwrap_or_default().as_secs_f64();
+            let arrow = if e.direction == Direction::Sent { "->" } else { "<-" };
+            // Only worth telling apart once there is more than one
+            let conn = match &e.conn{
+                Some(c) if self.conns.len() > 1 => format!("[{c}] "),
+                _ => String::new()
+            };
+            entries = entries.push(
+                row![
+                    text!("{elapsed:>9.3}s {conn}{arrow} {:>6} bytes  {}  {}", e.data.len(), e.hex_preview(24), e.note.as_deref().unwrap_or_default()).width(Fill),
+                    button("Decode").on_press(Message::DecodeEntry(i)),
+                    button("Replay").on_press(Message::ReplayEntry(i))
+                ].spacing(5)
+            );
         }
-        col.spacing(10).into()
+        col = col.push(scrollable(entries).height(Length::Fixed(200.0)));
+        col.spacing(5).into()
+    }
+    fn editing(&mut self) -> &mut Conn{
+        &mut self.conns[self.selected]
+    }
+
+    /// Index of the connection called `name`, the default one when empty or gone
+    fn conn_index(&self, name : &str) -> usize{
+        self.conns.iter().position(|c| !name.is_empty() && c.name == name).unwrap_or(self.default_conn)
     }
-    fn disconnect(&mut self){
-        if let Some(s) = &self.sock{
-            s.shutdown(std::net::Shutdown::Both).unwrap();
-            self.sock = None
+
+    fn packet_conn(&self, p_idx : usize) -> usize{
+        self.conn_index(&self.packet_views[p_idx].target)
+    }
+
+    /// Keeps the addresses of a connection that just came up for exporting the session
+    fn connected(&mut self, c : usize){
+        if let Some(e) = self.conns[c].endpoints(){
+            self.endpoints.insert(Some(self.conns[c].name.clone()), e);
         }
     }
 
-    fn connect(&mut self) -> std::io::Result<()>{
-        self.disconnect();
-        self.sock = Some(TcpStream::connect(format!("{}:{}", self.current_ip, self.current_port))?);
-        Ok(())
+    fn listen(&mut self, c : usize) -> Task<Message>{
+        if let Err(e) = self.conns[c].listen(){
+            println!("Couldnt listen: {e}");
+            return Task::none();
+        }
+        self.runs += 1;
+        self.conns[c].accept_run = self.runs;
+        Task::done(Message::AcceptTick(c, self.runs))
+    }
+
+    fn accept_tick(&mut self, c : usize, run : usize) -> Task<Message>{
+        let Some(conn) = self.conns.get_mut(c).filter(|x| x.accept_run == run && x.listener.is_some()) else { return Task::none() };
+        match conn.accept(){
+            // A TLS client that stalls mid handshake would otherwise hold up the UI for the handshake timeout
+            Ok(Some(s)) => {
+                let shake = conn.handshaker(s);
+                return Task::perform(async move { shake().map(Handoff::new).map_err(|e| e.to_string()) }, move |res| Message::Accepted(c, run, res));
+            },
+            Ok(None) => return Task::perform(async { std::thread::sleep(Duration::from_millis(100)) }, move |_| Message::AcceptTick(c, run)),
+            Err(e) => println!("Couldnt accept connection: {e}")
+        }
+        Task::none()
+    }
+
+    fn accepted(&mut self, c : usize, run : usize, res : Result<Handoff, String>){
+        // Listening again or connecting by hand meanwhile wins over the late peer
+        let Some(conn) = self.conns.get_mut(c).filter(|x| x.accept_run == run && x.sock.is_none() && x.listener.is_none()) else { return };
+        let res = res.and_then(|h| h.take().ok_or_else(|| "Connection was taken already".to_string()));
+        match res.and_then(|s| conn.attach(s, false).map_err(|e| e.to_string())){
+            Ok(_) => self.connected(c),
+            Err(e) => println!("Couldnt accept connection: {e}")
+        }
     }
 
-    fn send(&mut self, packet : &PacketView) -> std::io::Result<()>{
-        if let Some(s) = &mut self.sock{
-            s.write(&packet.to_bytes())?;
+    fn schedule_reconnect(&self, c : usize) -> Task<Message>{
+        let Some((run, attempt)) = self.conns[c].reconnect else { return Task::none() };
+        let delay = self.conns[c].timeouts.backoff(attempt);
+        Task::perform(async move { std::thread::sleep(delay) }, move |_| Message::ReconnectTick(c, run))
+    }
+
+    /// Dials on the executor's threads, a peer that is down can take the whole connect timeout
+    fn reconnect_tick(&mut self, c : usize, run : usize) -> Task<Message>{
+        let Some(conn) = self.conns.get(c).filter(|x| x.reconnect.is_some_and(|r| r.0 == run)) else { return Task::none() };
+        let dial = conn.dialer();
+        Task::perform(async move { dial().map(Handoff::new).map_err(|e| e.to_string()) }, move |res| Message::Redialed(c, run, res))
+    }
+
+    /// The session log is left alone, so it reads on across the reconnect
+    fn redialed(&mut self, c : usize, run : usize, res : Result<Handoff, String>) -> Task<Message>{
+        // Cancelled or connected by hand meanwhile, a connection that made it anyway just gets dropped
+        let Some(conn) = self.conns.get_mut(c).filter(|x| x.reconnect.is_some_and(|r| r.0 == run)) else { return Task::none() };
+        let res = res.and_then(|h| h.take().ok_or_else(|| "Connection was taken already".to_string()));
+        if conn.redialed(res){
+            return self.schedule_reconnect(c);
+        }
+        self.connected(c);
+        Task::none()
+    }
+
+    fn send(&mut self, p_idx : usize) -> std::io::Result<()>{
+        self.send_on(self.packet_conn(p_idx), p_idx)
+    }
+
+    fn send_on(&mut self, c : usize, p_idx : usize) -> std::io::Result<()>{
+        // Encoded in place so generator state like counters carries over to the next send
+        let dat = self.packet_views[p_idx].to_bytes(&self.variables).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        self.send_bytes(c, dat)
+    }
+
+    fn send_bytes(&mut self, c : usize, dat : Vec<u8>) -> std::io::Result<()>{
+        let conn = &mut self.conns[c];
+        if conn.write(&dat)?{
+            self.session.push(SessionEntry::new(Direction::Sent, dat).with_conn(&conn.name));
         }
         Ok(())
     }
 
     fn recieve(&mut self, p_idx : usize) -> std::io::Result<()>{
-        let packet = &mut self.packet_views[p_idx];
-        if let Some(s) = &mut self.sock{
-            for i in 0..packet.fields.len(){
-                match packet.fields[i].datatype.unwrap(){
-                    PacketDataType::Bytes(sizing_method) => {
-                        let fdiag = FileDialog::new()
-                        .add_filter("binary", &["bin",""])
-                        .save_file();
-                        if let Some(fpath) = fdiag{
-                            let mut f = File::create(fpath.clone())?;
-                            let mut rsize = 
-                                match sizing_method {
-                                    crate::packet::SizingMethod::SizeHeader(x) => packet.get_field(x).data_string.parse::<usize>().unwrap_or_default(),
-                                    crate::packet::SizingMethod::FixedSize(x) => x,
-                                };
-                            let mut dat = [0;4096];
-                            while rsize > 0{
-                                let count = s.read(&mut dat)?;
-                                println!("{count}");
-                                rsize -= count;
-                                f.write(&dat[0..count])?;
-                            }
-                        }
-                    },
-                    PacketDataType::CStr => {
-                        let mut dat : Vec<u8> = Vec::new();
-                        let mut c : [u8;1] = [0];
-                        while s.read(&mut c)? > 0 && c[0] != 0{
-                            dat.push(c[0]);
-                        }
-                        dat.push(0);
-                        packet.fields[i].data_string = std::ffi::CString::from_vec_with_nul(dat).unwrap().to_str().unwrap().to_string();
-                    },
-                    _ => {
-                        let dtype = packet.fields[i].datatype.unwrap();
-                        let mut dat : Vec<u8> = Vec::with_capacity(dtype.data_size());
-                        s.read(&mut dat)?;
-                        packet.fields[i].data_string = dtype.bytes_to_val(dat).as_ref().to_string();
-                    }
+        self.recieve_on(self.packet_conn(p_idx), p_idx)
+    }
+
+    fn recieve_on(&mut self, c : usize, p_idx : usize) -> std::io::Result<()>{
+        let conn = &mut self.conns[c];
+        let Some(s) = &mut conn.sock else { return Ok(()) };
+        let frame = conn.framing.next_frame(s, &self.packet_views[p_idx], &self.variables);
+        self.recieved(c, p_idx, frame)
+    }
+
+    /// Logs and decodes a frame read off connection `c` into packet `p_idx`
+    fn recieved(&mut self, c : usize, p_idx : usize, frame : std::io::Result<Vec<u8>>) -> std::io::Result<()>{
+        let conn = &mut self.conns[c];
+        let dat = match frame{
+            Ok(x) => x,
+            Err(e) => {
+                // Past a timeout the buffer cant be split into messages any more, keep it in the log and start over
+                if !matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) && !conn.framing.buf.is_empty(){
+                    let dat = conn.framing.take_all();
+                    self.session.push(SessionEntry::new(Direction::Recieved, dat).with_note(Some(format!("unframed: {e}"))).with_conn(&conn.name));
                 }
+                conn.io_failed("Recieve", &e);
+                return Err(e);
             }
-        } 
+        };
+        self.session.push(SessionEntry::new(Direction::Recieved, dat.clone()).with_conn(&conn.name));
+        let packet = &mut self.packet_views[p_idx];
+        let mut cur = std::io::Cursor::new(&dat);
+        // The frame is all there is, running out means the message is short, not that the peer left
+        packet.read_from(&mut cur, &self.variables).map_err(|e| match e.kind(){
+            std::io::ErrorKind::UnexpectedEof => std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Message is {} bytes, too short for #{p_idx}", dat.len())),
+            _ => e
+        })?;
+        packet.check(&self.variables);
+        self.variables.extend(packet.captures());
+        if (cur.position() as usize) < dat.len(){
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Message is {} bytes, #{p_idx} covers only {}", dat.len(), cur.position())));
+        }
         Ok(())
     }
-
-
 }
 
 impl Index<usize> for State{
@@ -181,4 +1174,171 @@ impl IndexMut<usize> for State{
     fn index_mut(&mut self, index: usize) -> &mut Self::Output {
         &mut self.packet_views[index]
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::packet::{PacketDataType, PacketField};
+
+    fn state() -> State{
+        let mut s = State::default();
+        let _ = s.update(Message::AddConnection);
+        let _ = s.update(Message::AddConnection);
+        s.add_packet();
+        s.packet_views[0].target = "conn1".to_string();
+        s
+    }
+
+    #[test]
+    fn rename_rejects_taken_names(){
+        let mut s = state();
+        let _ = s.update(Message::ConnNameEntry(2, "conn1".to_string()));
+        assert_eq!(s.conns[2].name, "conn2");
+        assert_eq!(s.packet_conn(0), 1);
+        let _ = s.update(Message::ConnNameEntry(1, "server".to_string()));
+        assert_eq!(s.packet_views[0].target, "server");
+        assert_eq!(s.packet_conn(0), 1);
+    }
+
+    #[test]
+    fn default_stays_put_while_editing(){
+        let mut s = state();
+        s.add_packet();
+        let _ = s.update(Message::SelectConnection(2));
+        assert_eq!(s.packet_conn(1), 0);
+        let _ = s.update(Message::DefaultConnection(2));
+        assert_eq!(s.packet_conn(1), 2);
+        let _ = s.update(Message::RemoveConnection(2));
+        assert_eq!(s.packet_conn(1), 0);
+    }
+    #[test]
+    fn message_length_must_match_the_template(){
+        let mut s = State::default();
+        s.packet_views.push(PacketView::with_fields(0, "t".to_string(), vec![PacketField::typed(0, String::new(), PacketDataType::U16, None)]));
+        s.conns[0].sock = Some(crate::websocket::tests::connect());
+        s.send_bytes(0, vec![1, 2]).unwrap();
+        s.recieve(0).unwrap();
+        assert_eq!(s.packet_views[0].fields[0].data_string, "513");
+        s.send_bytes(0, vec![1, 2, 3]).unwrap();
+        let e = s.recieve(0).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "Message is 3 bytes, #0 covers only 2");
+        s.send_bytes(0, vec![1]).unwrap();
+        let e = s.recieve(0).unwrap_err();
+        assert_eq!(e.to_string(), "Message is 1 bytes, too short for #0");
+        // Neither is taken for the peer going away
+        assert!(s.conns[0].sock.is_some());
+    }
+
+    #[test]
+    fn expect_step_gets_the_connection_back(){
+        let mut s = State::default();
+        s.packet_views.push(PacketView::with_fields(0, "t".to_string(), vec![PacketField::typed(0, String::new(), PacketDataType::U16, None)]));
+        s.conns[0].sock = Some(crate::websocket::tests::connect());
+        let mut sc = Scenario::new(0);
+        sc.update(ScMessage::AddStep);
+        sc.update(ScMessage::ActionEntry(0, StepAction::Expect));
+        sc.update(ScMessage::PacketEntry(0, 0));
+        s.scenarios.push(sc);
+        s.send_bytes(0, vec![1, 2]).unwrap();
+        let _ = s.update(Message::RunScenario(0));
+        let run = s.scenarios[0].run_id;
+        // What the task would do with the connection the step took
+        let read = |s : &mut State| {
+            let sock = s.conns[0].sock.take().unwrap();
+            scenario::expect(0, sock, s.conns[0].framing.clone(), &s.packet_views[0], &s.variables, s.conns[0].timeouts.read())
+        };
+        let e = read(&mut s);
+        let _ = s.update(Message::Expected(0, run, Box::new(e)));
+        assert!(s.conns[0].sock.is_some());
+        assert_eq!(s.packet_views[0].fields[0].data_string, "513");
+        assert_eq!(s.scenarios[0].steps[0].status, StepStatus::Passed);
+        // A read for a stopped run only lands in the session
+        s.send_bytes(0, vec![3, 4]).unwrap();
+        let e = read(&mut s);
+        let _ = s.update(Message::Expected(0, run, Box::new(e)));
+        assert!(s.conns[0].sock.is_some());
+        assert_eq!(s.packet_views[0].fields[0].data_string, "513");
+        assert_eq!(s.session.last().unwrap().data, vec![3, 4]);
+    }
+
+    #[test]
+    fn redial_outcomes(){
+        let mut s = State::default();
+        s.conns[0].timeouts.retries_string = "2".to_string();
+        s.conns[0].reconnect = Some((7, 0));
+        let _ = s.update(Message::Redialed(0, 7, Err("refused".to_string())));
+        assert_eq!(s.conns[0].reconnect, Some((7, 1)));
+        // Stale dials dont count
+        let _ = s.update(Message::Redialed(0, 6, Ok(Handoff::new(crate::websocket::tests::connect()))));
+        assert!(s.conns[0].sock.is_none());
+        let _ = s.update(Message::Redialed(0, 7, Ok(Handoff::new(crate::websocket::tests::connect()))));
+        assert!(s.conns[0].sock.is_some());
+        assert_eq!(s.conns[0].reconnect, None);
+        assert_eq!(s.conns[0].status, "Reconnected on attempt 2");
+    }
+
+    #[test]
+    fn accepted_peer_is_attached_once(){
+        let mut s = State::default();
+        s.conns[0].current_ip = "127.0.0.1".to_string();
+        s.conns[0].current_port = "0".to_string();
+        let _ = s.update(Message::Listen(0));
+        let run = s.conns[0].accept_run;
+        let addr = s.conns[0].listener.as_ref().unwrap().local_addr().unwrap();
+        let client = std::net::TcpStream::connect(addr).unwrap();
+        let peer = loop{
+            if let Some(x) = s.conns[0].accept().unwrap(){
+                break x;
+            }
+        };
+        let shake = s.conns[0].handshaker(peer);
+        // A peer from an earlier listen is dropped
+        let _ = s.update(Message::Accepted(0, run - 1, Ok(Handoff::new(crate::websocket::tests::connect()))));
+        assert!(s.conns[0].sock.is_none());
+        let _ = s.update(Message::Accepted(0, run, Ok(Handoff::new(shake().unwrap()))));
+        assert!(s.conns[0].sock.is_some());
+        assert_eq!(s.conns[0].endpoints().map(|x| x.1), Some(client.local_addr().unwrap()));
+    }
+
+    #[test]
+    fn load_waits_for_replies_across_ticks(){
+        let mut s = State::default();
+        s.packet_views.push(PacketView::with_fields(0, "t".to_string(), vec![PacketField::typed(0, String::new(), PacketDataType::U8, None)]));
+        s.conns[0].sock = Some(crate::websocket::tests::connect());
+        s.packet_views[0].fields[0].data_string = "7".to_string();
+        s.load.packet = Some(0);
+        s.load.count_string = "3".to_string();
+        s.load.wait_reply = true;
+        let _ = s.update(Message::StartLoad);
+        let start = Instant::now();
+        while s.load.running && start.elapsed() < Duration::from_secs(5){
+            let _ = s.load_tick(s.load.run_id);
+        }
+        let stats = s.load.stats.as_ref().unwrap();
+        assert_eq!((stats.sent, stats.replies, stats.timeouts), (3, 3, 0));
+        assert_eq!(stats.error, None);
+    }
+
+    #[test]
+    fn load_backs_off_when_the_peer_stops_reading(){
+        let mut s = State::default();
+        let fill = PacketField::typed(0, "0".to_string(), PacketDataType::Padding, Some(crate::packet::SizingMethod::FixedSize(1 << 20)));
+        s.packet_views.push(PacketView::with_fields(0, "t".to_string(), vec![fill]));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        s.conns[0].sock = Some(crate::transport::Connection::Tcp(std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap()));
+        let _peer = listener.accept().unwrap();
+        s.load.packet = Some(0);
+        s.load.count_string = "0".to_string();
+        let _ = s.update(Message::StartLoad);
+        for _ in 0..20{
+            let tick = Instant::now();
+            let _ = s.load_tick(s.load.run_id);
+            assert!(tick.elapsed() < Duration::from_secs(1));
+        }
+        // Stuck on a full socket, not failed
+        assert!(s.load.running);
+        assert!(!s.load.unsent.is_empty());
+        assert_eq!(s.load.stats.as_ref().unwrap().error, None);
+    }
+}
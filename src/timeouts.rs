@@ -0,0 +1,122 @@
+use std::io::{self, ErrorKind};
+use std::time::Duration;
+
+use iced::{widget::{row, text, text_input, toggler}, Element, Length};
+
+use crate::state::Message;
+
+// How long connecting, reading and writing may take before giving up, and
+// whether a connection that drops is dialed again. Reconnect attempts back off
+// exponentially from the configured delay so a restarting peer isn't hammered.
+
+/// Longest wait between two reconnect attempts
+const MAX_BACKOFF : Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub enum TimeoutMessage{
+    ConnectEntry(String),
+    ReadEntry(String),
+    WriteEntry(String),
+    AutoReconnect(bool),
+    RetriesEntry(String),
+    BackoffEntry(String)
+}
+
+pub struct TimeoutSettings{
+    pub(crate) connect_string : String,
+    pub(crate) read_string : String,
+    pub(crate) write_string : String,
+    pub(crate) auto_reconnect : bool,
+    pub(crate) retries_string : String,
+    pub(crate) backoff_string : String
+}
+
+impl Default for TimeoutSettings{
+    fn default() -> Self {
+        Self {
+            connect_string: "5000".to_string(),
+            // A peer that never answers shouldnt hang a recieve for good
+            read_string: "5000".to_string(),
+            write_string: Default::default(),
+            auto_reconnect: false,
+            retries_string: "5".to_string(),
+            backoff_string: "500".to_string()
+        }
+    }
+}
+
+/// Empty or 0 means wait forever
+fn millis(s : &str) -> Option<Duration>{
+    s.parse::<u64>().ok().filter(|x| *x > 0).map(Duration::from_millis)
+}
+
+/// Whether the error means the connection is gone rather than just slow
+pub fn is_disconnect(e : &io::Error) -> bool{
+    matches!(e.kind(), ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe | ErrorKind::NotConnected | ErrorKind::UnexpectedEof)
+}
+
+/// Status line for a failed operation, e.g. "Recieve timed out"
+pub fn describe(op : &str, e : &io::Error) -> String{
+    match e.kind(){
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => format!("{op} timed out"),
+        ErrorKind::UnexpectedEof => format!("{op} failed: peer closed the connection"),
+        _ => format!("{op} failed: {e}")
+    }
+}
+
+impl TimeoutSettings{
+    pub fn connect(&self) -> Option<Duration>{
+        millis(&self.connect_string)
+    }
+
+    pub fn read(&self) -> Option<Duration>{
+        millis(&self.read_string)
+    }
+
+    pub fn write(&self) -> Option<Duration>{
+        millis(&self.write_string)
+    }
+
+    pub fn retries(&self) -> usize{
+        self.retries_string.parse().unwrap_or(5)
+    }
+
+    /// Wait before reconnect attempt `attempt`, counting from 0
+    pub fn backoff(&self, attempt : usize) -> Duration{
+        let base = self.backoff_string.parse::<u64>().map(Duration::from_millis).unwrap_or(Duration::from_millis(500));
+        base.saturating_mul(1 << attempt.min(16) as u32).min(MAX_BACKOFF)
+    }
+
+    pub fn update(&mut self, msg : TimeoutMessage){
+        let valid = |x : &String| x.is_empty() || x.parse::<u64>().is_ok();
+        match msg{
+            TimeoutMessage::ConnectEntry(x) => if valid(&x) { self.connect_string = x },
+            TimeoutMessage::ReadEntry(x) => if valid(&x) { self.read_string = x },
+            TimeoutMessage::WriteEntry(x) => if valid(&x) { self.write_string = x },
+            TimeoutMessage::AutoReconnect(x) => self.auto_reconnect = x,
+            TimeoutMessage::RetriesEntry(x) => if valid(&x) { self.retries_string = x },
+            TimeoutMessage::BackoffEntry(x) => if valid(&x) { self.backoff_string = x },
+        }
+    }
+
+    /// `shared` for serial ports, where writes go by the read timeout
+    pub fn draw(&self, shared : bool) -> Element<'_, Message>{
+        let mut r = row![
+            text("Timeouts"),
+            text_input("Connect ms", &self.connect_string).on_input(|x| Message::TimeoutMessage(TimeoutMessage::ConnectEntry(x))).width(Length::Fixed(100.0)),
+            text_input("Read ms", &self.read_string).on_input(|x| Message::TimeoutMessage(TimeoutMessage::ReadEntry(x))).width(Length::Fixed(100.0)),
+        ].spacing(5);
+        if shared{
+            r = r.push(text("Writes use the read timeout on serial ports"));
+        }
+        else{
+            r = r.push(text_input("Write ms", &self.write_string).on_input(|x| Message::TimeoutMessage(TimeoutMessage::WriteEntry(x))).width(Length::Fixed(100.0)));
+        }
+        r = r.push(toggler(self.auto_reconnect).label("Auto reconnect").on_toggle(|x| Message::TimeoutMessage(TimeoutMessage::AutoReconnect(x))));
+        if self.auto_reconnect{
+            r = r.push(text_input("Retries", &self.retries_string).on_input(|x| Message::TimeoutMessage(TimeoutMessage::RetriesEntry(x))).width(Length::Fixed(80.0)));
+            r = r.push(text_input("Backoff ms", &self.backoff_string).on_input(|x| Message::TimeoutMessage(TimeoutMessage::BackoffEntry(x))).width(Length::Fixed(100.0)));
+        }
+        r.into()
+    }
+}
@@ -0,0 +1,273 @@
+use std::io;
+use std::net::TcpStream;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use iced::{widget::{button, row, text, text_input, toggler, Row}, Element, Length};
+use rfd::FileDialog;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, ServerConfig, ServerConnection, SignatureScheme, StreamOwned};
+
+use crate::state::Message;
+use crate::transport::Connection;
+
+// TLS on top of the TCP socket, in both directions. As a client the CA bundle
+// replaces the system trust store and cert/key enable mutual TLS. When
+// listening cert/key are the server's own, a CA bundle then requires clients to
+// present a certificate signed by it.
+
+/// Longest a peer may take to finish the handshake before we give up on it
+const HANDSHAKE_TIMEOUT : Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub enum TlsMessage{
+    Enabled(bool),
+    SniEntry(String),
+    PickCa,
+    PickCert,
+    PickKey,
+    AcceptInvalid(bool)
+}
+
+#[derive(Default, Clone)]
+pub struct TlsSettings{
+    pub(crate) enabled : bool,
+    /// Server name sent by the client, the host is used when empty
+    pub(crate) sni : String,
+    pub(crate) ca_path : String,
+    pub(crate) cert_path : String,
+    pub(crate) key_path : String,
+    pub(crate) accept_invalid : bool
+}
+
+/// Takes whatever certificate the server presents, only meant for test rigs
+#[derive(Debug)]
+struct AcceptAnyCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCert{
+    fn verify_server_cert(&self, _ : &CertificateDer<'_>, _ : &[CertificateDer<'_>], _ : &ServerName<'_>, _ : &[u8], _ : UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message : &[u8], cert : &CertificateDer<'_>, dss : &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message : &[u8], cert : &CertificateDer<'_>, dss : &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn provider() -> Arc<CryptoProvider>{
+    Arc::new(ring::default_provider())
+}
+
+fn load_certs(path : &str) -> Result<Vec<CertificateDer<'static>>, String>{
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|x| x.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("{path}: {e}"))?;
+    if certs.is_empty(){
+        return Err(format!("{path}: no certificates found"));
+    }
+    Ok(certs)
+}
+
+fn load_key(path : &str) -> Result<PrivateKeyDer<'static>, String>{
+    PrivateKeyDer::from_pem_file(path).map_err(|e| format!("{path}: {e}"))
+}
+
+fn roots(path : &str) -> Result<RootCertStore, String>{
+    let mut store = RootCertStore::empty();
+    for cert in load_certs(path)?{
+        store.add(cert).map_err(|e| format!("{path}: {e}"))?;
+    }
+    Ok(store)
+}
+
+/// The system trust store, webpki's bundled roots where the system has none
+fn default_roots() -> RootCertStore{
+    static ROOTS : OnceLock<RootCertStore> = OnceLock::new();
+    ROOTS.get_or_init(||{
+        let mut store = RootCertStore::empty();
+        let (added, _) = store.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+        if added == 0{
+            store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        }
+        store
+    }).clone()
+}
+
+/// Drives the handshake to completion so bad certificates show up at connect time
+fn handshake<C, S>(conn : &mut C, sock : &mut TcpStream) -> io::Result<()>
+where C : std::ops::DerefMut<Target = rustls::ConnectionCommon<S>>, S : rustls::SideData{
+    sock.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    while conn.is_handshaking(){
+        conn.complete_io(sock)?;
+    }
+    sock.set_read_timeout(None)
+}
+
+impl TlsSettings{
+    fn client_config(&self) -> Result<ClientConfig, String>{
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?;
+        let builder = if self.accept_invalid{
+            builder.dangerous().with_custom_certificate_verifier(Arc::new(AcceptAnyCert(provider())))
+        }
+        else if self.ca_path.is_empty(){
+            builder.with_root_certificates(default_roots())
+        }
+        else{
+            builder.with_root_certificates(roots(&self.ca_path)?)
+        };
+        match (self.cert_path.is_empty(), self.key_path.is_empty()){
+            (true, true) => Ok(builder.with_no_client_auth()),
+            (false, false) => builder.with_client_auth_cert(load_certs(&self.cert_path)?, load_key(&self.key_path)?).map_err(|e| e.to_string()),
+            _ => Err("Mutual TLS needs both a certificate and a key".to_string())
+        }
+    }
+
+    fn server_config(&self) -> Result<ServerConfig, String>{
+        if self.cert_path.is_empty() || self.key_path.is_empty(){
+            return Err("Listening with TLS needs a certificate and a key".to_string());
+        }
+        let builder = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?;
+        let builder = if self.ca_path.is_empty(){
+            builder.with_no_client_auth()
+        }
+        else{
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots(&self.ca_path)?), provider())
+                .build()
+                .map_err(|e| e.to_string())?;
+            builder.with_client_cert_verifier(verifier)
+        };
+        builder.with_single_cert(load_certs(&self.cert_path)?, load_key(&self.key_path)?).map_err(|e| e.to_string())
+    }
+
+    /// Wraps a freshly connected socket, `host` is what the user connected to
+    pub fn connect(&self, mut sock : TcpStream, host : &str) -> io::Result<Connection>{
+        let config = self.client_config().map_err(io::Error::other)?;
+        let name = if self.sni.is_empty() { host } else { self.sni.as_str() };
+        let name = ServerName::try_from(name.to_string()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut conn = ClientConnection::new(Arc::new(config), name).map_err(io::Error::other)?;
+        handshake(&mut conn, &mut sock)?;
+        Ok(Connection::TlsClient(Box::new(StreamOwned::new(conn, sock))))
+    }
+
+    /// Wraps a socket accepted in listen mode
+    pub fn accept(&self, mut sock : TcpStream) -> io::Result<Connection>{
+        let config = self.server_config().map_err(io::Error::other)?;
+        let mut conn = ServerConnection::new(Arc::new(config)).map_err(io::Error::other)?;
+        handshake(&mut conn, &mut sock)?;
+        Ok(Connection::TlsServer(Box::new(StreamOwned::new(conn, sock))))
+    }
+
+    pub fn update(&mut self, msg : TlsMessage){
+        let pick = |filter : &str| FileDialog::new().add_filter(filter, &["pem", "crt", "key"]).pick_file().map(|x| x.to_string_lossy().to_string());
+        match msg{
+            TlsMessage::Enabled(x) => self.enabled = x,
+            TlsMessage::SniEntry(x) => self.sni = x,
+            TlsMessage::PickCa => self.ca_path = pick("CA bundle").unwrap_or_default(),
+            TlsMessage::PickCert => self.cert_path = pick("Certificate").unwrap_or_default(),
+            TlsMessage::PickKey => self.key_path = pick("Private key").unwrap_or_default(),
+            TlsMessage::AcceptInvalid(x) => self.accept_invalid = x,
+        }
+    }
+
+    pub fn draw(&self) -> Element<'_, Message>{
+        let mut r : Row<'_, Message> = row![
+            toggler(self.enabled).label("TLS").on_toggle(|x| Message::TlsMessage(TlsMessage::Enabled(x)))
+        ].spacing(5);
+        if self.enabled{
+            r = r.push(self.draw_certs());
+        }
+        r.into()
+    }
+
+    /// Everything but the on/off switch, wss:// always needs these
+    pub fn draw_certs(&self) -> Element<'_, Message>{
+        // Cancelling the dialog clears the path again
+        let file = |path : &str, what : &str| if path.is_empty() { what.to_string() } else { format!("{what}: {path}") };
+        row![
+            text_input("SNI, defaults to the host", &self.sni).on_input(|x| Message::TlsMessage(TlsMessage::SniEntry(x))).width(Length::Fixed(200.0)),
+            button(text(file(&self.ca_path, "CA bundle, system roots if unset"))).on_press(Message::TlsMessage(TlsMessage::PickCa)),
+            button(text(file(&self.cert_path, "Certificate"))).on_press(Message::TlsMessage(TlsMessage::PickCert)),
+            button(text(file(&self.key_path, "Key"))).on_press(Message::TlsMessage(TlsMessage::PickKey)),
+            toggler(self.accept_invalid).label("Accept invalid certs").on_toggle(|x| Message::TlsMessage(TlsMessage::AcceptInvalid(x)))
+        ].spacing(5).into()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    /// Self signed cert and key for localhost, written out as (cert, key) paths
+    fn self_signed(name : &str) -> (String, String){
+        let ck = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = std::env::temp_dir();
+        let cert = dir.join(format!("packetmancer-{name}-{}.crt", std::process::id()));
+        let key = dir.join(format!("packetmancer-{name}-{}.key", std::process::id()));
+        std::fs::write(&cert, ck.cert.pem()).unwrap();
+        std::fs::write(&key, ck.signing_key.serialize_pem()).unwrap();
+        (cert.to_string_lossy().to_string(), key.to_string_lossy().to_string())
+    }
+
+    /// Accepts one TLS client and echoes a single read back
+    fn echo_server(cert : &str, key : &str) -> (u16, std::thread::JoinHandle<io::Result<()>>){
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let tls = TlsSettings { enabled: true, cert_path: cert.to_string(), key_path: key.to_string(), ..Default::default() };
+        (port, std::thread::spawn(move ||{
+            let (sock, _) = listener.accept()?;
+            let mut conn = tls.accept(sock)?;
+            let mut buf = [0; 64];
+            let n = conn.read(&mut buf)?;
+            conn.write_all(&buf[..n])
+        }))
+    }
+
+    #[test]
+    fn round_trip_with_the_ca_bundle(){
+        let (cert, key) = self_signed("trusted");
+        let (port, server) = echo_server(&cert, &key);
+        let tls = TlsSettings { enabled: true, ca_path: cert, ..Default::default() };
+        let mut conn = tls.connect(TcpStream::connect(("127.0.0.1", port)).unwrap(), "localhost").unwrap();
+        conn.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        conn.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn system_roots_reject_a_self_signed_server(){
+        let (cert, key) = self_signed("untrusted");
+        let (port, server) = echo_server(&cert, &key);
+        let tls = TlsSettings { enabled: true, ..Default::default() };
+        let err = tls.connect(TcpStream::connect(("127.0.0.1", port)).unwrap(), "localhost").err().unwrap();
+        assert!(err.to_string().contains("certificate"), "{err}");
+        assert!(server.join().unwrap().is_err());
+
+        let (port, server) = echo_server(&cert, &key);
+        let tls = TlsSettings { enabled: true, accept_invalid: true, ..Default::default() };
+        let mut conn = tls.connect(TcpStream::connect(("127.0.0.1", port)).unwrap(), "localhost").unwrap();
+        conn.write_all(b"hi").unwrap();
+        let mut buf = [0; 2];
+        conn.read_exact(&mut buf).unwrap();
+        server.join().unwrap().unwrap();
+    }
+}
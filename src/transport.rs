@@ -0,0 +1,340 @@
+use std::fmt::Display;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rustls::{ClientConnection, ServerConnection, StreamOwned};
+use serialport::{SerialPort, TTYPort};
+
+use crate::framing::Source;
+use crate::websocket::WsStream;
+
+// The byte stream packets are sent over. Everything above this only needs
+// Read + Write and a read timeout, so new transports slot in as variants here.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transport{
+    #[default]
+    Tcp,
+    UnixStream,
+    UnixDatagram,
+    Serial,
+    WebSocket
+}
+
+impl Transport{
+    pub const ALL : [Transport; 5] = [Transport::Tcp, Transport::UnixStream, Transport::UnixDatagram, Transport::Serial, Transport::WebSocket];
+}
+
+impl Display for Transport{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self{
+            Transport::Tcp => write!(f, "TCP"),
+            Transport::UnixStream => write!(f, "Unix stream"),
+            Transport::UnixDatagram => write!(f, "Unix datagram"),
+            Transport::Serial => write!(f, "Serial"),
+            Transport::WebSocket => write!(f, "WebSocket"),
+        }
+    }
+}
+
+/// Tries every address the name resolves to, each for at most `timeout`
+pub fn tcp_connect<A : ToSocketAddrs>(addr : A, timeout : Option<Duration>) -> io::Result<TcpStream>{
+    let Some(timeout) = timeout else { return TcpStream::connect(addr) };
+    let mut last = None;
+    for a in addr.to_socket_addrs()?{
+        match TcpStream::connect_timeout(&a, timeout){
+            Ok(s) => return Ok(s),
+            Err(e) => last = Some(e)
+        }
+    }
+    Err(last.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Address didnt resolve to anything")))
+}
+
+/// Connected Unix datagram socket. Framing takes each datagram as one message,
+/// plain reads are served from the last one so a packet can be decoded field
+/// by field, bytes it leaves over go to the next read
+pub struct Datagram{
+    sock : UnixDatagram,
+    /// Our own bound path, the peer needs it to reply
+    local : PathBuf,
+    buf : Vec<u8>,
+    pos : usize
+}
+
+impl Datagram{
+    pub fn connect(path : &str) -> io::Result<Self>{
+        static SOCKETS : AtomicUsize = AtomicUsize::new(0);
+        let local = std::env::temp_dir().join(format!("packetmancer-{}-{}.sock", std::process::id(), SOCKETS.fetch_add(1, Ordering::Relaxed)));
+        let _ = std::fs::remove_file(&local);
+        let sock = UnixDatagram::bind(&local)?;
+        if let Err(e) = sock.connect(path){
+            let _ = std::fs::remove_file(&local);
+            return Err(e);
+        }
+        Ok(Self { sock, local, buf: Vec::new(), pos: 0 })
+    }
+}
+
+impl Drop for Datagram{
+    fn drop(&mut self){
+        let _ = std::fs::remove_file(&self.local);
+    }
+}
+
+impl Datagram{
+    /// The next datagram whole, empty ones included. What plain reads left
+    /// of the last one comes first
+    pub fn recv(&mut self) -> io::Result<Vec<u8>>{
+        if self.pos < self.buf.len(){
+            let dat = self.buf.split_off(self.pos);
+            self.buf.clear();
+            self.pos = 0;
+            return Ok(dat);
+        }
+        let mut dat = vec![0; 65536];
+        let n = self.sock.recv(&mut dat)?;
+        dat.truncate(n);
+        Ok(dat)
+    }
+}
+
+impl Read for Datagram{
+    fn read(&mut self, buf : &mut [u8]) -> io::Result<usize>{
+        // An empty datagram has nothing to read, Ok(0) would say the peer closed
+        while self.pos == self.buf.len(){
+            self.buf = self.recv()?;
+            self.pos = 0;
+        }
+        let n = buf.len().min(self.buf.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl Write for Datagram{
+    /// Every write is one datagram
+    fn write(&mut self, buf : &[u8]) -> io::Result<usize>{
+        self.sock.send(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()>{
+        Ok(())
+    }
+}
+
+pub enum Connection{
+    Tcp(TcpStream),
+    TlsClient(Box<StreamOwned<ClientConnection, TcpStream>>),
+    TlsServer(Box<StreamOwned<ServerConnection, TcpStream>>),
+    Unix(UnixStream),
+    UnixDatagram(Datagram),
+    Serial(TTYPort),
+    WebSocket(Box<WsStream>)
+}
+
+impl Connection{
+    fn tcp(&self) -> Option<&TcpStream>{
+        match self{
+            Connection::Tcp(s) => Some(s),
+            Connection::TlsClient(s) => Some(&s.sock),
+            Connection::TlsServer(s) => Some(&s.sock),
+            Connection::WebSocket(s) => s.inner_ref().tcp(),
+            Connection::Unix(_) | Connection::UnixDatagram(_) | Connection::Serial(_) => None,
+        }
+    }
+
+    pub fn set_read_timeout(&mut self, dur : Option<Duration>) -> io::Result<()>{
+        match self{
+            Connection::Unix(s) => s.set_read_timeout(dur),
+            Connection::UnixDatagram(s) => s.sock.set_read_timeout(dur),
+            // A serial port always has a timeout, a very long one stands in for none
+            Connection::Serial(s) => s.set_timeout(dur.unwrap_or(Duration::MAX)).map_err(io::Error::from),
+            Connection::WebSocket(s) => s.inner().set_read_timeout(dur),
+            _ => self.tcp().unwrap().set_read_timeout(dur)
+        }
+    }
+
+    pub fn set_write_timeout(&mut self, dur : Option<Duration>) -> io::Result<()>{
+        match self{
+            Connection::Unix(s) => s.set_write_timeout(dur),
+            Connection::UnixDatagram(s) => s.sock.set_write_timeout(dur),
+            // Reads and writes share the one timeout a serial port has
+            Connection::Serial(_) => Ok(()),
+            Connection::WebSocket(s) => s.inner().set_write_timeout(dur),
+            _ => self.tcp().unwrap().set_write_timeout(dur)
+        }
+    }
+
+    /// (local, peer) addresses, used when exporting the session. Only IP
+    /// transports have them
+    pub fn endpoints(&self) -> Option<(SocketAddr, SocketAddr)>{
+        let s = self.tcp()?;
+        Some((s.local_addr().ok()?, s.peer_addr().ok()?))
+    }
+
+    pub fn shutdown(&mut self) -> io::Result<()>{
+        // Let the peer know the TLS session ended on purpose
+        match self{
+            Connection::TlsClient(s) => { s.conn.send_close_notify(); let _ = s.flush(); },
+            Connection::TlsServer(s) => { s.conn.send_close_notify(); let _ = s.flush(); },
+            Connection::Unix(s) => return s.shutdown(Shutdown::Both),
+            Connection::WebSocket(s) => {
+                let _ = s.close();
+                return s.inner().shutdown();
+            },
+            Connection::UnixDatagram(_) | Connection::Serial(_) => return Ok(()),
+            Connection::Tcp(_) => ()
+        }
+        self.tcp().unwrap().shutdown(Shutdown::Both)
+    }
+}
+
+/// A connection carried by a message, e.g. back from a dial on a worker thread.
+/// Messages get cloned, whoever takes it first gets it
+#[derive(Clone, Default)]
+pub struct Handoff(Arc<Mutex<Option<Connection>>>);
+
+impl Handoff{
+    pub fn new(s : Connection) -> Self{
+        Self(Arc::new(Mutex::new(Some(s))))
+    }
+
+    pub fn take(&self) -> Option<Connection>{
+        self.0.lock().ok()?.take()
+    }
+}
+
+impl std::fmt::Debug for Handoff{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Handoff")
+    }
+}
+
+impl Source for Connection{
+    fn keeps_boundaries(&self) -> bool{
+        matches!(self, Connection::UnixDatagram(_) | Connection::WebSocket(_))
+    }
+
+    fn message(&mut self) -> io::Result<Option<Vec<u8>>>{
+        match self{
+            Connection::UnixDatagram(s) => s.recv().map(Some),
+            Connection::WebSocket(s) => s.recv(),
+            s => {
+                let mut dat = vec![0; 65536];
+                let n = s.read(&mut dat)?;
+                dat.truncate(n);
+                Ok((n > 0).then_some(dat))
+            }
+        }
+    }
+}
+
+impl Read for Connection{
+    fn read(&mut self, buf : &mut [u8]) -> io::Result<usize>{
+        match self{
+            Connection::Tcp(s) => s.read(buf),
+            Connection::TlsClient(s) => s.read(buf),
+            Connection::TlsServer(s) => s.read(buf),
+            Connection::Unix(s) => s.read(buf),
+            Connection::UnixDatagram(s) => s.read(buf),
+            Connection::Serial(s) => s.read(buf),
+            Connection::WebSocket(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Connection{
+    fn write(&mut self, buf : &[u8]) -> io::Result<usize>{
+        match self{
+            Connection::Tcp(s) => s.write(buf),
+            Connection::TlsClient(s) => s.write(buf),
+            Connection::TlsServer(s) => s.write(buf),
+            Connection::Unix(s) => s.write(buf),
+            Connection::UnixDatagram(s) => s.write(buf),
+            Connection::Serial(s) => s.write(buf),
+            Connection::WebSocket(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()>{
+        match self{
+            Connection::Tcp(s) => s.flush(),
+            Connection::TlsClient(s) => s.flush(),
+            Connection::TlsServer(s) => s.flush(),
+            Connection::Unix(s) => s.flush(),
+            Connection::UnixDatagram(s) => s.flush(),
+            Connection::Serial(s) => s.flush(),
+            Connection::WebSocket(s) => s.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::framing::{FrameRule, Framing};
+    use crate::packet::PacketView;
+    use crate::variables::Variables;
+
+    fn pair(name : &str) -> (UnixDatagram, Datagram, PathBuf){
+        let path = std::env::temp_dir().join(format!("packetmancer-{name}-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let peer = UnixDatagram::bind(&path).unwrap();
+        let dgram = Datagram::connect(path.to_str().unwrap()).unwrap();
+        peer.connect(&dgram.local).unwrap();
+        (peer, dgram, path)
+    }
+
+    #[test]
+    fn datagrams_are_frames(){
+        let (peer, dgram, path) = pair("frames");
+        let mut conn = Connection::UnixDatagram(dgram);
+        peer.send(b"one\r\ntwo").unwrap();
+        peer.send(b"").unwrap();
+        peer.send(b"three").unwrap();
+        // The delimiter would cut the first datagram in two
+        let mut f = Framing::default();
+        f.rule = FrameRule::Delimiter;
+        let (view, vars) = (PacketView::new(0), Variables::new());
+        assert_eq!(f.next_frame(&mut conn, &view, &vars).unwrap(), b"one\r\ntwo");
+        assert_eq!(f.next_frame(&mut conn, &view, &vars).unwrap(), b"");
+        assert_eq!(f.next_frame(&mut conn, &view, &vars).unwrap(), b"three");
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn empty_datagram_is_not_a_close(){
+        let (peer, mut dgram, path) = pair("empty");
+        peer.send(b"").unwrap();
+        peer.send(b"ab").unwrap();
+        let mut buf = [0; 1];
+        assert_eq!(dgram.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf, *b"a");
+        // What the read left over is still its own datagram
+        assert_eq!(dgram.recv().unwrap(), b"b");
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn serial_pair(){
+        let (ours, mut theirs) = TTYPort::pair().unwrap();
+        let mut conn = Connection::Serial(ours);
+        conn.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        theirs.set_timeout(Duration::from_secs(5)).unwrap();
+        conn.write_all(b"ping\n").unwrap();
+        let mut got = [0; 5];
+        theirs.read_exact(&mut got).unwrap();
+        assert_eq!(&got, b"ping\n");
+        theirs.write_all(b"pong\nmore").unwrap();
+        let mut f = Framing::default();
+        f.rule = FrameRule::Delimiter;
+        f.delimiter_string = "\\n".to_string();
+        assert_eq!(f.next_frame(&mut conn, &PacketView::new(0), &Variables::new()).unwrap(), b"pong\n");
+    }
+}
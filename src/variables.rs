@@ -0,0 +1,62 @@
+use std::collections::BTreeMap;
+
+// Workspace variables. Received fields can be captured into a variable and any
+// field's data string can pull one back in with `${name}`, which is expanded
+// right before the field is encoded.
+
+pub type Variables = BTreeMap<String, String>;
+
+pub fn is_valid_name(name : &str) -> bool{
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Replaces every `${name}` with its value. An unknown name is an error, sending
+/// the reference itself would only fail further down or go out as garbage
+pub fn substitute(s : &str, vars : &Variables) -> Result<String, String>{
+    let mut ret = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find("${"){
+        ret.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        // Without a closing brace it is just text
+        let Some(end) = after.find('}') else {
+            ret.push_str("${");
+            rest = after;
+            continue;
+        };
+        let name = &after[..end];
+        let val = vars.get(name).ok_or_else(|| format!("Variable ${{{name}}} is not defined"))?;
+        ret.push_str(val);
+        rest = &after[end + 1..];
+    }
+    ret.push_str(rest);
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn vars() -> Variables{
+        Variables::from([("id".to_string(), "7".to_string()), ("host".to_string(), "box".to_string())])
+    }
+
+    #[test]
+    fn substitutes_known_names(){
+        assert_eq!(substitute("${id}", &vars()).unwrap(), "7");
+        assert_eq!(substitute("a${host}b${id}", &vars()).unwrap(), "aboxb7");
+        assert_eq!(substitute("plain", &vars()).unwrap(), "plain");
+    }
+
+    #[test]
+    fn unknown_name_is_an_error(){
+        let e = substitute("${idd}", &vars()).unwrap_err();
+        assert!(e.contains("${idd}"), "{e}");
+    }
+
+    #[test]
+    fn unclosed_reference_is_text(){
+        assert_eq!(substitute("${id", &vars()).unwrap(), "${id");
+        assert_eq!(substitute("$id", &vars()).unwrap(), "$id");
+    }
+}
@@ -0,0 +1,113 @@
+use std::io::{self, Read};
+
+// LEB128 encodings. Unsigned LEB128 is the same wire format as a protobuf varint,
+// zigzag is the protobuf sint mapping layered on top of it.
+
+pub fn encode_uleb128(mut val : u64) -> Vec<u8>{
+    let mut ret = Vec::new();
+    loop{
+        let byte = (val & 0x7f) as u8;
+        val >>= 7;
+        if val == 0{
+            ret.push(byte);
+            return ret;
+        }
+        ret.push(byte | 0x80);
+    }
+}
+
+pub fn encode_sleb128(mut val : i64) -> Vec<u8>{
+    let mut ret = Vec::new();
+    loop{
+        let byte = (val & 0x7f) as u8;
+        val >>= 7;
+        let done = (val == 0 && byte & 0x40 == 0) || (val == -1 && byte & 0x40 != 0);
+        if done{
+            ret.push(byte);
+            return ret;
+        }
+        ret.push(byte | 0x80);
+    }
+}
+
+pub const fn zigzag_encode(val : i64) -> u64{
+    ((val << 1) ^ (val >> 63)) as u64
+}
+
+pub const fn zigzag_decode(val : u64) -> i64{
+    ((val >> 1) as i64) ^ -((val & 1) as i64)
+}
+
+/// Reads one varint a byte at a time so nothing past its last byte is consumed.
+/// Returns the raw bytes, the caller decides how to interpret them.
+pub fn read_varint<R : Read>(src : &mut R) -> io::Result<Vec<u8>>{
+    let mut ret = Vec::new();
+    let mut c : [u8;1] = [0];
+    loop{
+        src.read_exact(&mut c)?;
+        ret.push(c[0]);
+        if c[0] & 0x80 == 0{
+            return Ok(ret);
+        }
+        if ret.len() >= 10{
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Varint longer than 10 bytes"));
+        }
+    }
+}
+
+pub fn decode_uleb128(dat : &[u8]) -> u64{
+    dat.iter().take(10).enumerate().fold(0, |acc, (i, b)| acc | (((b & 0x7f) as u64) << (7 * i)))
+}
+
+pub fn decode_sleb128(dat : &[u8]) -> i64{
+    let mut ret : i64 = 0;
+    let mut shift = 0;
+    for b in dat.iter().take(10){
+        ret |= ((b & 0x7f) as i64) << shift;
+        shift += 7;
+    }
+    if shift < 64 && dat.last().is_some_and(|b| b & 0x40 != 0){
+        ret |= -1 << shift;
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn round_trips(){
+        for val in [0, 1, 127, 128, 300, 16383, 16384, u32::MAX as u64, u64::MAX]{
+            let enc = encode_uleb128(val);
+            assert_eq!(read_varint(&mut Cursor::new(&enc)).unwrap(), enc);
+            assert_eq!(decode_uleb128(&enc), val);
+        }
+        for val in [0, 1, -1, 63, 64, -64, -65, 8191, -8193, i64::MAX, i64::MIN]{
+            let enc = encode_sleb128(val);
+            assert_eq!(read_varint(&mut Cursor::new(&enc)).unwrap(), enc);
+            assert_eq!(decode_sleb128(&enc), val);
+            assert_eq!(zigzag_decode(zigzag_encode(val)), val);
+        }
+        assert_eq!(encode_uleb128(300), [0xac, 0x02]);
+        assert_eq!(encode_sleb128(-65), [0xbf, 0x7f]);
+        assert_eq!(encode_uleb128(u64::MAX).len(), 10);
+        assert_eq!([zigzag_encode(0), zigzag_encode(-1), zigzag_encode(1), zigzag_encode(-2)], [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn reads_stop_at_the_last_byte(){
+        let mut src = Cursor::new([0x96, 0x01, 0x55]);
+        assert_eq!(read_varint(&mut src).unwrap(), [0x96, 0x01]);
+        assert_eq!(src.position(), 2);
+    }
+
+    #[test]
+    fn overlong_and_truncated_input(){
+        let err = read_varint(&mut Cursor::new([0x80; 11])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = read_varint(&mut Cursor::new([0x80, 0x80])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,215 @@
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use iced::{widget::{button, row, text, text_input, Column}, Element, Length};
+use tungstenite::client::ClientRequestBuilder;
+use tungstenite::http::Uri;
+use tungstenite::protocol::WebSocket;
+
+use crate::state::Message;
+use crate::tls::TlsSettings;
+use crate::transport::{self, Connection};
+
+// WebSocket client on top of TCP or TLS. Every write goes out as one binary
+// frame and framing takes every frame recieved as one message. Plain reads
+// hand out the payload of the last frame so a packet can be decoded field by
+// field like on a byte stream. Pings are answered by tungstenite while reading.
+
+#[derive(Debug, Clone)]
+pub enum WsMessage{
+    UrlEntry(String),
+    AddHeader,
+    HeaderNameEntry(usize, String),
+    HeaderValueEntry(usize, String),
+    RemoveHeader(usize)
+}
+
+#[derive(Default, Clone)]
+pub struct WsSettings{
+    pub(crate) url : String,
+    /// Extra handshake headers, e.g. Authorization or Origin
+    pub(crate) headers : Vec<(String, String)>
+}
+
+pub struct WsStream{
+    ws : WebSocket<Connection>,
+    buf : Vec<u8>,
+    pos : usize
+}
+
+fn to_io(e : tungstenite::Error) -> io::Error{
+    match e{
+        tungstenite::Error::Io(e) => e,
+        e => io::Error::other(e.to_string())
+    }
+}
+
+impl WsStream{
+    pub fn inner(&mut self) -> &mut Connection{
+        self.ws.get_mut()
+    }
+
+    pub fn inner_ref(&self) -> &Connection{
+        self.ws.get_ref()
+    }
+
+    pub fn close(&mut self) -> io::Result<()>{
+        self.ws.close(None).map_err(to_io)?;
+        // Push the close frame out, the peer's answer does not matter any more
+        match self.ws.flush(){
+            Ok(_) | Err(tungstenite::Error::ConnectionClosed) => Ok(()),
+            Err(e) => Err(to_io(e))
+        }
+    }
+
+    /// Payload of the next data frame whole, None once the peer closed. What
+    /// plain reads left of the last one comes first
+    pub fn recv(&mut self) -> io::Result<Option<Vec<u8>>>{
+        if self.pos < self.buf.len(){
+            let dat = self.buf.split_off(self.pos);
+            self.buf.clear();
+            self.pos = 0;
+            return Ok(Some(dat));
+        }
+        loop{
+            match self.ws.read(){
+                Ok(tungstenite::Message::Binary(x)) => return Ok(Some(x.to_vec())),
+                Ok(tungstenite::Message::Text(x)) => return Ok(Some(x.as_str().as_bytes().to_vec())),
+                Ok(tungstenite::Message::Close(_)) | Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => return Ok(None),
+                // Pings and pongs
+                Ok(_) => (),
+                Err(e) => return Err(to_io(e))
+            }
+        }
+    }
+}
+
+impl Read for WsStream{
+    fn read(&mut self, buf : &mut [u8]) -> io::Result<usize>{
+        // Empty frames have nothing to read, Ok(0) would say the peer closed
+        while self.pos == self.buf.len(){
+            let Some(payload) = self.recv()? else { return Ok(0) };
+            self.buf = payload;
+            self.pos = 0;
+        }
+        let n = buf.len().min(self.buf.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl Write for WsStream{
+    /// Every write is one binary frame
+    fn write(&mut self, buf : &[u8]) -> io::Result<usize>{
+        self.ws.send(tungstenite::Message::Binary(buf.to_vec().into())).map_err(to_io)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()>{
+        self.ws.flush().map_err(to_io)
+    }
+}
+
+impl WsSettings{
+    /// wss:// uses the TLS settings whether or not TLS is switched on for plain TCP.
+    /// `timeout` covers connecting and each read of the handshake
+    pub fn connect(&self, tls : &TlsSettings, timeout : Option<Duration>) -> io::Result<Connection>{
+        let invalid = |e : String| io::Error::new(io::ErrorKind::InvalidInput, e);
+        let uri : Uri = self.url.parse().map_err(|e : tungstenite::http::uri::InvalidUri| invalid(e.to_string()))?;
+        let secure = match uri.scheme_str(){
+            Some("ws") => false,
+            Some("wss") => true,
+            _ => return Err(invalid(format!("{} is not a ws:// or wss:// URL", self.url)))
+        };
+        let host = uri.host().ok_or_else(|| invalid(format!("{} has no host", self.url)))?.trim_start_matches('[').trim_end_matches(']').to_string();
+        let port = uri.port_u16().unwrap_or(if secure { 443 } else { 80 });
+        let sock = transport::tcp_connect((host.as_str(), port), timeout)?;
+        let mut sock = if secure { tls.connect(sock, &host)? } else { Connection::Tcp(sock) };
+        sock.set_read_timeout(timeout)?;
+        let mut request = ClientRequestBuilder::new(uri);
+        for (k, v) in self.headers.iter().filter(|(k, _)| !k.is_empty()){
+            request = request.with_header(k.clone(), v.clone());
+        }
+        let (ws, _) = tungstenite::client(request, sock).map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(Connection::WebSocket(Box::new(WsStream { ws, buf: Vec::new(), pos: 0 })))
+    }
+
+    pub fn update(&mut self, msg : WsMessage){
+        match msg{
+            WsMessage::UrlEntry(x) => self.url = x,
+            WsMessage::AddHeader => self.headers.push(Default::default()),
+            WsMessage::HeaderNameEntry(i, x) => self.headers[i].0 = x,
+            WsMessage::HeaderValueEntry(i, x) => self.headers[i].1 = x,
+            WsMessage::RemoveHeader(i) => { self.headers.remove(i); },
+        }
+    }
+
+    pub fn draw(&self) -> Element<'_, Message>{
+        let mut col = Column::new();
+        col = col.push(
+            row![
+                text_input("ws://host:port/path", &self.url).on_input(|x| Message::WsMessage(WsMessage::UrlEntry(x))),
+                button("Add header").on_press(Message::WsMessage(WsMessage::AddHeader))
+            ].spacing(5)
+        );
+        for (i, (k, v)) in self.headers.iter().enumerate(){
+            col = col.push(
+                row![
+                    text("Header"),
+                    text_input("Name", k).on_input(move |x| Message::WsMessage(WsMessage::HeaderNameEntry(i, x))).width(Length::Fixed(200.0)),
+                    text_input("Value", v).on_input(move |x| Message::WsMessage(WsMessage::HeaderValueEntry(i, x))),
+                    button("Remove").on_press(Message::WsMessage(WsMessage::RemoveHeader(i)))
+                ].spacing(5)
+            );
+        }
+        col.spacing(5).into()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests{
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::framing::{FrameRule, Framing};
+    use crate::packet::PacketView;
+    use crate::variables::Variables;
+
+    /// Sends every frame back on a thread of its own, for one client
+    pub(crate) fn echo_server() -> String{
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            let mut ws = tungstenite::accept(sock).unwrap();
+            while let Ok(m) = ws.read(){
+                if m.is_binary() || m.is_text(){
+                    ws.send(m).unwrap();
+                }
+            }
+        });
+        url
+    }
+
+    pub(crate) fn connect() -> Connection{
+        let ws = WsSettings { url: echo_server(), headers: Vec::new() };
+        ws.connect(&TlsSettings::default(), Some(Duration::from_secs(5))).unwrap()
+    }
+
+    #[test]
+    fn frames_are_messages(){
+        let mut conn = connect();
+        conn.write_all(b"a\r\nb").unwrap();
+        // write_all skips empty buffers
+        assert_eq!(conn.write(b"").unwrap(), 0);
+        conn.write_all(b"cd").unwrap();
+        // The delimiter would cut the first frame in two
+        let mut f = Framing::default();
+        f.rule = FrameRule::Delimiter;
+        let (view, vars) = (PacketView::new(0), Variables::new());
+        assert_eq!(f.next_frame(&mut conn, &view, &vars).unwrap(), b"a\r\nb");
+        assert_eq!(f.next_frame(&mut conn, &view, &vars).unwrap(), b"");
+        assert_eq!(f.next_frame(&mut conn, &view, &vars).unwrap(), b"cd");
+    }
+}